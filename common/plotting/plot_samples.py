@@ -0,0 +1,34 @@
+import csv
+import sys
+
+import matplotlib.pyplot as plt
+
+# Throughput over time from the CSV files the benches write with
+# --sample-us=N --sample-format=csv --sample-file=PATH:
+#
+#   python plot_samples.py writer-samples.csv reader-samples.csv
+#
+# One line per file; samples without progress (stalls) are marked.
+
+if len(sys.argv) < 2:
+    sys.exit("usage: plot_samples.py SAMPLES.csv [SAMPLES.csv ...]")
+
+plt.figure()
+for path in sys.argv[1:]:
+    with open(path, newline="") as f:
+        rows = list(csv.DictReader(f))
+    if not rows:
+        continue
+    t = [int(row["ns"]) / 1e6 for row in rows]
+    rate = [float(row["gb_per_s"]) for row in rows]
+    line, = plt.plot(t, rate, label=f"{rows[0]['role']} ({path})")
+    stalls = [x for x, r in zip(t, rate) if r == 0.0]
+    plt.plot(stalls, [0.0] * len(stalls), "x", color=line.get_color())
+
+plt.xlabel("Time since start (ms)")
+plt.ylabel("GB / s over the quantum")
+plt.title("Throughput per Time Quantum")
+plt.legend()
+plt.grid()
+
+plt.show()
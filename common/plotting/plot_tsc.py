@@ -1,5 +1,18 @@
+import csv
+import sys
+
 import matplotlib.pyplot as plt
 
+# TSC ticks per second, from the "Clock: tsc at X GHz" line the benches
+# print (e.g. 2.4e9). With it the deltas are plotted in milliseconds; left
+# at None they stay in cycles.
+TSC_HZ = None
+
+# The skew bound in cycles from the launcher's "TSC: ... skew <= N cycles"
+# line. Writer and reader ran on different CPUs, so their readings differ
+# by up to this much on top of any real difference; None if not measured.
+TSC_SKEW_CYCLES = None
+
 writer_runs = [
     [
         2388750332103860, 2388750600415580, 2388750899833260, 2388751169017140,
@@ -56,13 +69,33 @@ reader_runs = [
     ],
 ]
 
+# Runs can also come from the CSV files the benches write with
+# --checkpoint-format=csv --checkpoint-file=PATH, one file per side per run:
+#
+#   python plot_tsc.py run1-writer.csv run1-reader.csv run2-writer.csv ...
+#
+# Each file's rows go to the runs of the role in their first column, in
+# place of the arrays above.
+def load_runs(paths):
+    runs = {"Writer": [], "Reader": []}
+    for path in paths:
+        with open(path, newline="") as f:
+            rows = list(csv.DictReader(f))
+        if rows:
+            runs[rows[0]["role"]].append([int(row["ticks"]) for row in rows])
+    return runs["Writer"], runs["Reader"]
+
+if len(sys.argv) > 1:
+    writer_runs, reader_runs = load_runs(sys.argv[1:])
+
 def deltas(tsc):
     return [tsc[i+1] - tsc[i] for i in range(len(tsc)-1)]
 
 def average_runs(runs):
     all_d = [deltas(r) for r in runs]
     n = len(all_d)
-    m = len(all_d[0])
+    # A run whose chunks skipped a checkpoint has fewer.
+    m = min(len(d) for d in all_d)
     return [sum(run[i] for run in all_d) / n for i in range(m)]
 
 def total_diff(tsc):
@@ -74,6 +107,13 @@ def total_diff(tsc):
 writer_avg = average_runs(writer_runs)
 reader_avg = average_runs(reader_runs)
 
+if TSC_HZ:
+    writer_avg = [v * 1e3 / TSC_HZ for v in writer_avg]
+    reader_avg = [v * 1e3 / TSC_HZ for v in reader_avg]
+    unit, per = "ms", "Time"
+else:
+    unit, per = "Cycles (TSC delta)", "Cycles"
+
 # compute total diff per run, then average
 writer_total_diffs = [total_diff(r) for r in writer_runs]
 reader_total_diffs = [total_diff(r) for r in reader_runs]
@@ -99,8 +139,13 @@ plt.plot(x, writer_avg, marker='o', label='Writer')
 plt.plot(x, reader_avg, marker='x', label='Reader')
 
 plt.xlabel("Checkpoint Interval")
-plt.ylabel("Cycles (TSC delta)")
-plt.title("4 GB Transfer: Avg Cycles per Interval")
+plt.ylabel(unit)
+plt.title(f"4 GB Transfer: Avg {per} per Interval")
+if TSC_SKEW_CYCLES is not None:
+    skew = TSC_SKEW_CYCLES * 1e3 / TSC_HZ if TSC_HZ else TSC_SKEW_CYCLES
+    plt.figtext(0.01, 0.01, f"cross-core TSC skew <= {skew:g} {'ms' if TSC_HZ else 'cycles'}")
+else:
+    plt.figtext(0.01, 0.01, "cross-core TSC skew not measured")
 plt.legend()
 plt.grid()
 
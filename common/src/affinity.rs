@@ -0,0 +1,219 @@
+// affinity.rs
+//
+// CPU sets in the kernel's cpulist syntax ("3", "0-3,8") and pinning to
+// them with sched_setaffinity, plus `Placement`, the `--cpu`/`--numa`
+// choice every benchmark role takes on its command line.
+
+use std::fmt;
+use std::io;
+use std::mem::size_of;
+use std::str::FromStr;
+
+use crate::cli::Args;
+use crate::error::RingError;
+use crate::numa;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuList(Vec<usize>);
+
+impl CpuList {
+    pub fn one(cpu: usize) -> Self {
+        CpuList(vec![cpu])
+    }
+
+    pub fn cpus(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// The list as a `cpu_set_t`, e.g. to apply between fork and exec where
+    /// nothing should allocate.
+    pub fn to_cpu_set(&self) -> libc::cpu_set_t {
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        for &cpu in &self.0 {
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        set
+    }
+}
+
+impl FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let max = 8 * size_of::<libc::cpu_set_t>();
+        let cpu = |s: &str| -> Result<usize, String> {
+            let cpu: usize = s
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad CPU number {:?}", s))?;
+            if cpu >= max {
+                return Err(format!("CPU {} is out of range (max {})", cpu, max - 1));
+            }
+            Ok(cpu)
+        };
+
+        let mut cpus = Vec::new();
+        for part in s.split(',') {
+            match part.split_once('-') {
+                Some((lo, hi)) => {
+                    let (lo, hi) = (cpu(lo)?, cpu(hi)?);
+                    if lo > hi {
+                        return Err(format!("empty CPU range {:?}", part));
+                    }
+                    cpus.extend(lo..=hi);
+                }
+                None => cpus.push(cpu(part)?),
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuList(cpus))
+    }
+}
+
+impl fmt::Display for CpuList {
+    // Back in cpulist form, with consecutive CPUs folded into ranges.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut i = 0;
+        while i < self.0.len() {
+            let lo = self.0[i];
+            while i + 1 < self.0.len() && self.0[i + 1] == self.0[i] + 1 {
+                i += 1;
+            }
+            if self.0[i] != lo {
+                write!(f, "{}-{}", lo, self.0[i])?;
+            } else {
+                write!(f, "{}", lo)?;
+            }
+            i += 1;
+            if i < self.0.len() {
+                f.write_str(",")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Restricts the calling thread to `cpus`.
+pub fn pin_current(cpus: &CpuList) -> io::Result<()> {
+    set_affinity(&cpus.to_cpu_set())
+}
+
+/// The CPUs the calling thread may run on.
+pub fn allowed_cpus() -> io::Result<CpuList> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let cpus = (0..8 * size_of::<libc::cpu_set_t>())
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect();
+    Ok(CpuList(cpus))
+}
+
+/// `sched_setaffinity` on the calling thread. Async-signal-safe, so it can
+/// run in a `pre_exec` hook.
+pub fn set_affinity(set: &libc::cpu_set_t) -> io::Result<()> {
+    if unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Where one benchmark role runs: the CPUs it is pinned to and the NUMA node
+/// its memory comes from. `None` leaves the choice to the kernel.
+#[derive(Debug, Clone, Default)]
+pub struct Placement {
+    pub cpus: Option<CpuList>,
+    pub node: Option<usize>,
+}
+
+impl Placement {
+    /// Reads `--<cpu_option>=LIST` and `--numa=NODE`, exiting with a usage
+    /// error if either is malformed.
+    pub fn from_args(cli: &Args, cpu_option: &str) -> Self {
+        Placement {
+            cpus: cli.parsed(cpu_option),
+            node: cli.parsed("numa"),
+        }
+    }
+
+    /// Pins the calling thread and binds every page the process faults in
+    /// from now on to the node. Call before allocating the buffers.
+    /// `InvalidConfig` if the CPUs or the node cannot be used.
+    pub fn apply(&self) -> Result<(), RingError> {
+        if let Some(cpus) = &self.cpus {
+            pin_current(cpus).map_err(|e| {
+                RingError::InvalidConfig(format!("cannot run on cpus {}: {}", cpus, e))
+            })?;
+        }
+        if let Some(node) = self.node {
+            numa::bind_process(node).map_err(|e| {
+                RingError::InvalidConfig(format!("cannot bind memory to node {}: {}", node, e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Placement {
+    // What was asked for and where the calling thread actually runs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.cpus {
+            Some(cpus) => write!(f, "cpus {}", cpus)?,
+            None => f.write_str("cpus any")?,
+        }
+        match self.node {
+            Some(node) => write!(f, ", memory node {}", node)?,
+            None => f.write_str(", memory any node")?,
+        }
+        let cpu = numa::current_cpu();
+        write!(f, ", running on cpu {}", cpu)?;
+        match numa::node_of_cpu(cpu) {
+            Some(node) => write!(f, " (node {})", node),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpulist() {
+        let list: CpuList = "3,0-2,8,2".parse().unwrap();
+        assert_eq!(list.cpus(), &[0, 1, 2, 3, 8]);
+        assert_eq!(list.to_string(), "0-3,8");
+    }
+
+    #[test]
+    fn rejects_bad_cpulist() {
+        assert!("".parse::<CpuList>().is_err());
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("x".parse::<CpuList>().is_err());
+        assert!("100000".parse::<CpuList>().is_err());
+    }
+
+    #[test]
+    fn placement_from_args() {
+        let cli = Args::parse(["bench", "--ping-cpu=0-1", "--numa=0"].map(String::from));
+        let placement = Placement::from_args(&cli, "ping-cpu");
+        assert_eq!(placement.cpus.unwrap().cpus(), &[0, 1]);
+        assert_eq!(placement.node, Some(0));
+
+        let placement = Placement::from_args(&cli, "cpu");
+        assert!(placement.cpus.is_none());
+    }
+
+    #[test]
+    fn pins_current_thread() {
+        let list: CpuList = "0".parse().unwrap();
+        std::thread::spawn(move || {
+            pin_current(&list).unwrap();
+            assert_eq!(unsafe { libc::sched_getcpu() }, 0);
+        })
+        .join()
+        .unwrap();
+    }
+}
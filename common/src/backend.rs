@@ -0,0 +1,749 @@
+// backend.rs
+//
+// Where a ring segment lives, picked by the prefix of its name:
+//
+//   ring, posix:/ring  a POSIX shm object (`shm_open`)
+//   file:/tmp/ring     a regular file at that path
+//   memfd:ring         an anonymous `memfd_create` region called `ring`
+//   sysv:1234          a System V segment (`shmget`) with that key (0x..
+//                      for hex)
+//   unix:/tmp/ring.sk  a memfd the producer hands to the consumer over a
+//                      Unix socket at that path (see `handoff.rs`)
+//
+// With explicit huge pages a posix name becomes a file of the same name on
+// the hugetlbfs mount (see `pages.rs`), memfd and sysv ask the kernel for
+// huge pages themselves (MFD_HUGETLB, SHM_HUGETLB), and a file has to be on
+// hugetlbfs already.
+//
+// The ring needs the same few things from every object: create it sized,
+// open it, read its size and identity, map it and remove the name. posix,
+// file and memfd objects are file descriptors mapped with `mmap` at any
+// offset. A sysv segment is an id that `shmat` attaches as a whole, so it
+// cannot be mirrored and MAP_POPULATE does not apply to it.
+//
+// A memfd has no name anyone else can open. Its creator writes its pid to
+// /dev/shm/memfd:ring.pid, and the peer opens the `/memfd:ring (deleted)`
+// link among that process's /proc/PID/fd, which takes the same user; no
+// other process is looked at. The memfd goes away with the last process
+// holding it. Unlinking removes the pid file; one a killed creator left
+// names a process without the memfd, so it opens nothing and the next
+// creator replaces it. A unix segment is a memfd too, but only the one
+// consumer it is sent to gets it; the socket is the only name, and is gone
+// once it was used. Both kinds are sealed against resizing once sized.
+//
+// Both ends of a ring remove the name when they are dropped, so it goes
+// with whichever exits first, except a unix segment's socket, which only
+// the producer serving it removes.
+
+use std::borrow::Cow;
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::RingError;
+use crate::handoff::{self, Server};
+use crate::pages;
+use crate::ring::{page_size, shm_name, RingOptions};
+
+/// The kind of object behind a segment name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Posix,
+    File,
+    Memfd,
+    SysV,
+    Unix,
+}
+
+impl Backend {
+    pub const ALL: [Backend; 5] = [
+        Backend::Posix,
+        Backend::File,
+        Backend::Memfd,
+        Backend::SysV,
+        Backend::Unix,
+    ];
+
+    /// The name prefix.
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Posix => "posix",
+            Backend::File => "file",
+            Backend::Memfd => "memfd",
+            Backend::SysV => "sysv",
+            Backend::Unix => "unix",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|b| b.name() == s)
+            .ok_or_else(|| format!("unknown segment backend `{}` (posix|file|memfd|sysv|unix)", s))
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// An open segment object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    /// posix, file, memfd and unix objects.
+    Fd(libc::c_int),
+    /// A System V shm id.
+    SysV(libc::c_int),
+}
+
+/// A segment's name in the namespace of its backend.
+#[derive(Debug, Clone)]
+pub struct SegmentName {
+    backend: Backend,
+    // The shm name, path, memfd name, key or socket path as given.
+    path: CString,
+    key: libc::key_t,
+    // Huge page size memfd and sysv objects are created with.
+    huge: Option<usize>,
+}
+
+impl SegmentName {
+    pub fn new(spec: &str, options: &RingOptions) -> Result<Self, RingError> {
+        let invalid =
+            |msg: String| RingError::InvalidConfig(format!("segment `{}`: {}", spec, msg));
+        let (backend, name) = match spec.split_once(':') {
+            Some((prefix, name)) => (prefix.parse::<Backend>().map_err(invalid)?, name),
+            None => (Backend::Posix, spec),
+        };
+        if name.is_empty() {
+            return Err(invalid("empty name".to_string()));
+        }
+        if backend == Backend::SysV && options.mirrored {
+            return Err(invalid("a sysv segment cannot be mirrored".to_string()));
+        }
+        let cstring = |s: &[u8]| CString::new(s).map_err(|e| invalid(e.to_string()));
+        let huge = options.pages.huge_size();
+        let mut key = 0;
+        let (backend, path) = match (backend, huge) {
+            (Backend::Posix, None) => (Backend::Posix, shm_name(name)?),
+            (Backend::Posix, Some(size)) => {
+                let dir = match &options.hugetlbfs {
+                    Some(dir) => dir.clone(),
+                    None => pages::hugetlbfs_mount(size)
+                        .map_err(|e| RingError::InvalidConfig(e.to_string()))?,
+                };
+                let path = dir.join(name.trim_start_matches('/'));
+                (Backend::File, cstring(path.as_os_str().as_bytes())?)
+            }
+            (Backend::File, _) => (Backend::File, cstring(name.as_bytes())?),
+            (Backend::Memfd, _) => {
+                let name = name.trim_start_matches('/');
+                // The limit memfd_create puts on names.
+                if name.is_empty() || name.contains('/') || name.len() > 249 {
+                    return Err(invalid(
+                        "a memfd name is 1-249 bytes without `/`".to_string(),
+                    ));
+                }
+                (Backend::Memfd, cstring(name.as_bytes())?)
+            }
+            (Backend::SysV, _) => {
+                key = parse_key(name).map_err(invalid)?;
+                (Backend::SysV, cstring(name.as_bytes())?)
+            }
+            (Backend::Unix, _) => {
+                // What fits `sun_path` with its nul.
+                if name.len() > 107 {
+                    return Err(invalid("a socket path is at most 107 bytes".to_string()));
+                }
+                (Backend::Unix, cstring(name.as_bytes())?)
+            }
+        };
+        Ok(SegmentName {
+            backend,
+            path,
+            key,
+            huge,
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Creates the object, `AlreadyExists` if the name is taken (never for
+    /// a memfd, whose pid file is replaced, or a unix segment). A sysv
+    /// segment is `len` bytes from the start; the others are empty until
+    /// `resize`.
+    pub fn create(&self, len: usize) -> io::Result<Handle> {
+        let rc = unsafe {
+            match self.backend {
+                Backend::Posix => libc::shm_open(
+                    self.path.as_ptr(),
+                    libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                    0o666,
+                ),
+                Backend::File => libc::open(
+                    self.path.as_ptr(),
+                    libc::O_CREAT | libc::O_EXCL | libc::O_RDWR | libc::O_CLOEXEC,
+                    0o666 as libc::c_uint,
+                ),
+                Backend::Memfd | Backend::Unix => libc::memfd_create(
+                    self.path.as_ptr(),
+                    libc::MFD_CLOEXEC
+                        | libc::MFD_ALLOW_SEALING
+                        | self.huge_flags(libc::MFD_HUGETLB as libc::c_int) as libc::c_uint,
+                ),
+                Backend::SysV => libc::shmget(
+                    self.key,
+                    len,
+                    libc::IPC_CREAT | libc::IPC_EXCL | 0o666 | self.huge_flags(libc::SHM_HUGETLB),
+                ),
+            }
+        };
+        if self.backend == Backend::Memfd && rc >= 0 {
+            if let Err(err) = publish_memfd(&self.path) {
+                unsafe { libc::close(rc) };
+                return Err(err);
+            }
+        }
+        self.handle(rc)
+    }
+
+    /// Sizes a new object, for good with a memfd; a sysv segment already
+    /// is sized.
+    pub fn resize(&self, handle: Handle, len: usize) -> io::Result<()> {
+        match handle {
+            Handle::Fd(fd) => {
+                if unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if matches!(self.backend, Backend::Memfd | Backend::Unix)
+                    && unsafe { libc::fcntl(fd, libc::F_ADD_SEALS, handoff::SIZE_SEALS) } != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            }
+            Handle::SysV(_) => Ok(()),
+        }
+    }
+
+    /// Opens an existing object, `NotFound` if there is none. A unix
+    /// segment can be opened once, and only while its producer runs.
+    pub fn open(&self, writable: bool) -> io::Result<Handle> {
+        let flags = if writable {
+            libc::O_RDWR
+        } else {
+            libc::O_RDONLY
+        };
+        let rc = unsafe {
+            match self.backend {
+                Backend::Posix => libc::shm_open(self.path.as_ptr(), flags, 0o666),
+                Backend::File => libc::open(self.path.as_ptr(), flags | libc::O_CLOEXEC),
+                Backend::Memfd => {
+                    let link = find_memfd(&self.path)?;
+                    let link = CString::new(link.as_os_str().as_bytes())?;
+                    libc::open(link.as_ptr(), flags | libc::O_CLOEXEC)
+                }
+                Backend::SysV => libc::shmget(self.key, 0, 0),
+                Backend::Unix => handoff::fetch(&self.path)?,
+            }
+        };
+        self.handle(rc)
+    }
+
+    fn handle(&self, rc: libc::c_int) -> io::Result<Handle> {
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(match self.backend {
+            Backend::SysV => Handle::SysV(rc),
+            _ => Handle::Fd(rc),
+        })
+    }
+
+    fn huge_flags(&self, hugetlb: libc::c_int) -> libc::c_int {
+        match self.huge {
+            Some(size) => {
+                hugetlb | ((size.trailing_zeros() as libc::c_int) << libc::MAP_HUGE_SHIFT)
+            }
+            None => 0,
+        }
+    }
+
+    pub fn close(&self, handle: Handle) {
+        if let Handle::Fd(fd) = handle {
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    /// Removes the name, `NotFound` if nothing has it. A memfd's is its pid
+    /// file, a unix segment's its socket.
+    pub fn unlink(&self) -> io::Result<()> {
+        let rc = unsafe {
+            match self.backend {
+                Backend::Posix => libc::shm_unlink(self.path.as_ptr()),
+                Backend::File | Backend::Unix => libc::unlink(self.path.as_ptr()),
+                Backend::Memfd => return fs::remove_file(memfd_pid_file(&self.path)),
+                Backend::SysV => match libc::shmget(self.key, 0, 0) {
+                    -1 => -1,
+                    id => libc::shmctl(id, libc::IPC_RMID, ptr::null_mut()),
+                },
+            }
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Size of the object in bytes.
+    pub fn len(&self, handle: Handle) -> io::Result<usize> {
+        match handle {
+            Handle::Fd(fd) => fstat(fd).map(|st| st.st_size as usize),
+            Handle::SysV(id) => shm_stat(id).map(|ds| ds.shm_segsz),
+        }
+    }
+
+    /// When the object was last resized or had its attributes changed (a
+    /// sysv segment: created or `shmctl`ed), in whole seconds for sysv.
+    pub fn changed(&self, handle: Handle) -> io::Result<SystemTime> {
+        let (secs, nanos) = match handle {
+            Handle::Fd(fd) => fstat(fd).map(|st| (st.st_ctime, st.st_ctime_nsec))?,
+            Handle::SysV(id) => shm_stat(id).map(|ds| (ds.shm_ctime, 0))?,
+        };
+        Ok(UNIX_EPOCH + Duration::new(secs as u64, nanos as u32))
+    }
+
+    /// Tells objects apart even when they had the same name.
+    pub fn id(&self, handle: Handle) -> io::Result<(u64, u64)> {
+        match handle {
+            Handle::Fd(fd) => fstat(fd).map(|st| (st.st_dev, st.st_ino)),
+            Handle::SysV(id) => Ok((self.key as u64, id as u64)),
+        }
+    }
+
+    /// `mmap` of `len` bytes at `offset`, or `shmat` of the whole segment
+    /// (which only has offset 0). `addr` is a hint unless `flags` has
+    /// MAP_FIXED.
+    ///
+    /// # Safety
+    /// With MAP_FIXED, whatever was mapped at `addr..addr + len` is replaced.
+    pub unsafe fn map(
+        &self,
+        handle: Handle,
+        addr: *mut libc::c_void,
+        len: usize,
+        offset: usize,
+        writable: bool,
+        flags: libc::c_int,
+    ) -> io::Result<*mut libc::c_void> {
+        let ptr = match handle {
+            Handle::Fd(fd) => {
+                let prot = if writable {
+                    libc::PROT_READ | libc::PROT_WRITE
+                } else {
+                    libc::PROT_READ
+                };
+                libc::mmap(
+                    addr,
+                    len,
+                    prot,
+                    libc::MAP_SHARED | flags,
+                    fd,
+                    offset as libc::off_t,
+                )
+            }
+            Handle::SysV(id) => {
+                if offset != 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "a sysv segment is only attached as a whole",
+                    ));
+                }
+                let mut shmflg = if writable { 0 } else { libc::SHM_RDONLY };
+                if flags & libc::MAP_FIXED != 0 {
+                    shmflg |= libc::SHM_REMAP;
+                }
+                libc::shmat(id, addr, shmflg)
+            }
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr)
+    }
+
+    /// Undoes `map`.
+    ///
+    /// # Safety
+    /// `ptr..ptr + len` must be a mapping `map` returned for this object.
+    pub unsafe fn unmap(&self, ptr: *mut libc::c_void, len: usize) {
+        match self.backend {
+            Backend::SysV => libc::shmdt(ptr),
+            _ => libc::munmap(ptr, len),
+        };
+    }
+
+    /// Starts handing a new unix segment to its consumer; `None` for the
+    /// other backends, whose consumers open the name themselves.
+    pub fn serve(&self, handle: Handle) -> io::Result<Option<Server>> {
+        match (self.backend, handle) {
+            (Backend::Unix, Handle::Fd(fd)) => Server::start(&self.path, fd).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether there is a sized object to open, without opening a unix
+    /// segment (which would use it up).
+    pub fn exists(&self) -> bool {
+        if self.backend == Backend::Unix {
+            return self.listener().is_some();
+        }
+        match self.open(false) {
+            Ok(handle) => {
+                let sized = self.len(handle).is_ok_and(|len| len > 0);
+                self.close(handle);
+                sized
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The pid of the producer listening on a unix segment's socket; `None`
+    /// for the other backends.
+    pub fn listener(&self) -> Option<u32> {
+        match self.backend {
+            Backend::Unix => handoff::probe(&self.path),
+            _ => None,
+        }
+    }
+
+    /// `InvalidConfig` if creating a unix segment would have to replace
+    /// something at its path that is not a socket.
+    pub fn check_creatable(&self) -> Result<(), RingError> {
+        if self.backend != Backend::Unix {
+            return Ok(());
+        }
+        match handoff::check_path(&self.path) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::InvalidInput => {
+                Err(RingError::InvalidConfig(err.to_string()))
+            }
+            Err(err) => Err(self.open_error(err)),
+        }
+    }
+
+    /// The `id` of the object under the name if process `pid` has it
+    /// mapped, read from /proc/PID/maps; for a unix segment, `(0, 0)` if
+    /// `pid` listens on its socket. Works for any object, ring or not.
+    pub fn mapped_by(&self, pid: u32) -> io::Result<Option<(u64, u64)>> {
+        if self.backend == Backend::Unix {
+            return Ok((self.listener() == Some(pid)).then_some((0, 0)));
+        }
+        let handle = match self.open(false) {
+            Ok(handle) => handle,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let id = self.id(handle);
+        self.close(handle);
+        let (dev, ino) = id?;
+        // A process that is gone maps nothing.
+        let Ok(maps) = fs::read_to_string(format!("/proc/{}/maps", pid)) else {
+            return Ok(None);
+        };
+        // address perms offset dev inode path
+        let mapped = maps.lines().any(|line| {
+            let mut fields = line.split_whitespace().skip(3);
+            let (Some(major_minor), Some(inode)) = (fields.next(), fields.next()) else {
+                return false;
+            };
+            if inode.parse() != Ok(ino) {
+                return false;
+            }
+            match self.backend {
+                // The inode of a sysv mapping is its shm id.
+                Backend::SysV => line.contains("/SYSV"),
+                _ => parse_dev(major_minor) == Some(dev),
+            }
+        });
+        Ok(mapped.then_some((dev, ino)))
+    }
+
+    /// The least `map` can map of the object.
+    pub fn page(&self) -> usize {
+        self.huge.unwrap_or_else(page_size)
+    }
+
+    /// Whether `map` honours MAP_POPULATE.
+    pub fn populates(&self) -> bool {
+        self.backend != Backend::SysV
+    }
+
+    pub fn display(&self) -> Cow<'_, str> {
+        let path = OsStr::from_bytes(self.path.as_bytes()).to_string_lossy();
+        match self.backend {
+            Backend::Posix | Backend::File => path,
+            Backend::Memfd | Backend::SysV | Backend::Unix => {
+                format!("{}:{}", self.backend, path).into()
+            }
+        }
+    }
+
+    pub(crate) fn open_error(&self, source: io::Error) -> RingError {
+        RingError::Open {
+            name: self.display().into_owned(),
+            source,
+        }
+    }
+
+    pub(crate) fn map_error(&self, source: io::Error) -> RingError {
+        RingError::Map {
+            name: self.display().into_owned(),
+            source,
+        }
+    }
+}
+
+fn parse_key(s: &str) -> Result<libc::key_t, String> {
+    let key = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse::<u32>(),
+    }
+    .map_err(|e| format!("bad sysv key `{}`: {}", s, e))?;
+    // IPC_PRIVATE would make a new unnamed segment every time.
+    if key == 0 {
+        return Err("the sysv key must be non-zero".to_string());
+    }
+    Ok(key as libc::key_t)
+}
+
+// Where the creator of the memfd called `name` publishes its pid.
+fn memfd_pid_file(name: &CString) -> PathBuf {
+    let mut path = b"/dev/shm/memfd:".to_vec();
+    path.extend_from_slice(name.as_bytes());
+    path.extend_from_slice(b".pid");
+    PathBuf::from(OsStr::from_bytes(&path))
+}
+
+// Makes this process the one peers look in for the memfd called `name`.
+fn publish_memfd(name: &CString) -> io::Result<()> {
+    let path = memfd_pid_file(name);
+    let pid = std::process::id();
+    // A peer never reads a half-written pid.
+    let tmp = path.with_extension(format!("pid.{}", pid));
+    fs::write(&tmp, pid.to_string())?;
+    fs::rename(&tmp, &path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+// The /proc/PID/fd link of the memfd called `name` in the process its pid
+// file names, `NotFound` if there is no pid file or that process has no
+// such memfd (any more).
+fn find_memfd(name: &CString) -> io::Result<PathBuf> {
+    let pid = fs::read_to_string(memfd_pid_file(name))?;
+    let pid: u32 = pid.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("bad pid `{}` in {}", pid.trim(), memfd_pid_file(name).display()),
+        )
+    })?;
+    let mut target = b"/memfd:".to_vec();
+    target.extend_from_slice(name.as_bytes());
+    target.extend_from_slice(b" (deleted)");
+    // The process may exit while we look; then it has nothing to open.
+    let fds = match fs::read_dir(format!("/proc/{}/fd", pid)) {
+        Ok(fds) => fds,
+        Err(_) => return Err(io::Error::from(io::ErrorKind::NotFound)),
+    };
+    fds.flatten()
+        .map(|fd| fd.path())
+        .find(|fd| fs::read_link(fd).is_ok_and(|link| link.as_os_str().as_bytes() == target))
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+}
+
+// A `major:minor` device number in hex, as /proc/PID/maps shows it.
+fn parse_dev(s: &str) -> Option<u64> {
+    let (major, minor) = s.split_once(':')?;
+    let major = u32::from_str_radix(major, 16).ok()?;
+    let minor = u32::from_str_radix(minor, 16).ok()?;
+    Some(libc::makedev(major, minor) as u64)
+}
+
+fn fstat(fd: libc::c_int) -> io::Result<libc::stat> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(st)
+}
+
+fn shm_stat(id: libc::c_int) -> io::Result<libc::shmid_ds> {
+    let mut ds: libc::shmid_ds = unsafe { std::mem::zeroed() };
+    if unsafe { libc::shmctl(id, libc::IPC_STAT, &mut ds) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(spec: &str) -> Result<SegmentName, RingError> {
+        SegmentName::new(spec, &RingOptions::default())
+    }
+
+    #[test]
+    fn parses_specs() {
+        let bare = name("ring").unwrap();
+        assert_eq!(
+            (bare.backend(), bare.display().as_ref()),
+            (Backend::Posix, "/ring")
+        );
+        assert_eq!(name("posix:/ring").unwrap().display(), "/ring");
+        let file = name("file:/tmp/ring").unwrap();
+        assert_eq!(
+            (file.backend(), file.display().as_ref()),
+            (Backend::File, "/tmp/ring")
+        );
+        assert_eq!(name("memfd:/ring").unwrap().display(), "memfd:ring");
+        assert_eq!(name("sysv:0x10").unwrap().key, 16);
+        assert_eq!(name("sysv:1234").unwrap().key, 1234);
+
+        for bad in ["shm:ring", "sysv:0", "sysv:ring", "memfd:a/b", "file:"] {
+            assert!(
+                matches!(name(bad), Err(RingError::InvalidConfig(_))),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            name("unix:/tmp/ring.sock").unwrap().display(),
+            "unix:/tmp/ring.sock"
+        );
+        let long = format!("unix:/tmp/{}", "r".repeat(110));
+        assert!(matches!(name(&long), Err(RingError::InvalidConfig(_))));
+        let mirrored = RingOptions {
+            mirrored: true,
+            ..RingOptions::default()
+        };
+        assert!(SegmentName::new("sysv:1234", &mirrored).is_err());
+    }
+
+    #[test]
+    fn memfd_is_found_through_its_pid_file() {
+        let object = name(&format!("memfd:backend-test-{}", std::process::id())).unwrap();
+        assert_eq!(
+            object.open(false).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        let created = object.create(0).unwrap();
+        object.resize(created, 4096).unwrap();
+        let opened = object.open(true).unwrap();
+        assert_eq!(object.id(opened).unwrap(), object.id(created).unwrap());
+        assert_eq!(object.len(opened).unwrap(), 4096);
+        object.close(opened);
+        object.unlink().unwrap();
+        assert_eq!(
+            object.open(false).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(object.unlink().unwrap_err().kind(), io::ErrorKind::NotFound);
+        object.close(created);
+    }
+
+    #[test]
+    fn memfd_pid_file_of_another_process_finds_nothing() {
+        let object = name(&format!("memfd:backend-stale-{}", std::process::id())).unwrap();
+        let created = object.create(0).unwrap();
+        // pid 1 has no memfd of that name, and ours is not looked for.
+        fs::write(memfd_pid_file(&object.path), "1").unwrap();
+        assert_eq!(
+            object.open(false).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::write(memfd_pid_file(&object.path), "pid").unwrap();
+        assert_eq!(
+            object.open(false).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        object.unlink().unwrap();
+        object.close(created);
+    }
+
+    #[test]
+    fn mapped_by_finds_our_mapping() {
+        let object = name(&format!("posix:backend-mapped-{}", std::process::id())).unwrap();
+        let _ = object.unlink();
+        let pid = std::process::id();
+        assert!(object.mapped_by(pid).unwrap().is_none());
+        let created = object.create(0).unwrap();
+        object.resize(created, 4096).unwrap();
+        assert!(object.mapped_by(pid).unwrap().is_none());
+        unsafe {
+            let ptr = object
+                .map(created, ptr::null_mut(), 4096, 0, false, 0)
+                .unwrap();
+            assert_eq!(
+                object.mapped_by(pid).unwrap(),
+                Some(object.id(created).unwrap())
+            );
+            // pid 1 does not map it.
+            assert!(object.mapped_by(1).unwrap().is_none());
+            object.unmap(ptr, 4096);
+        }
+        object.unlink().unwrap();
+        object.close(created);
+    }
+
+    #[test]
+    fn sysv_segment_round_trip() {
+        let key = 0x5200_0000 | (std::process::id() & 0xff_ffff);
+        let object = name(&format!("sysv:{}", key)).unwrap();
+        let _ = object.unlink();
+        let created = object.create(8192).unwrap();
+        assert_eq!(
+            object.create(8192).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        let opened = object.open(true).unwrap();
+        assert_eq!(object.len(opened).unwrap(), 8192);
+        unsafe {
+            let a = object
+                .map(created, ptr::null_mut(), 8192, 0, true, 0)
+                .unwrap();
+            let b = object
+                .map(opened, ptr::null_mut(), 8192, 0, false, 0)
+                .unwrap();
+            *(a as *mut u8).add(4096) = 7;
+            assert_eq!(*(b as *const u8).add(4096), 7);
+            assert_eq!(
+                object.mapped_by(std::process::id()).unwrap(),
+                Some(object.id(opened).unwrap())
+            );
+            object.unmap(a, 8192);
+            object.unmap(b, 8192);
+        }
+        object.unlink().unwrap();
+        assert_eq!(
+            object.open(false).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// copy_sweep.rs
+//
+// Runs a transfer through one ring for every copy kernel and chunk size and
+// prints the throughput as a table, so the cost of the memcpy can be told
+// apart from the cost of the ring:
+//
+//   copy_sweep [--kernels=LIST] [--chunks=LIST] [--side=both|writer|reader]
+//              [--size-mb=N] [--capacity=BYTES] [--writer-cpu=LIST]
+//              [--reader-cpu=LIST] [--numa=NODE]
+//              [--backends=posix,file,memfd,sysv,unix] [--file-dir=DIR]
+//
+// Both ends are threads of this process on a fresh segment per run. `--side`
+// says which end uses the kernel under test; the other copies with `std`.
+// Each backend (see `backend.rs`) gets a table of its own; files and
+// sockets go to `--file-dir`, the temp dir by default.
+// Kernels the CPU does not have are left out of the default list and are an
+// error if asked for.
+
+use std::env;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Instant;
+
+use common::affinity::Placement;
+use common::backend::Backend;
+use common::cli::{self, Args};
+use common::copy::CopyKernel;
+use common::error::OrExit;
+use common::{RingConsumer, RingOptions, RingProducer};
+
+const MB: u64 = 1024 * 1024;
+
+const DEFAULT_CHUNKS: [usize; 8] = [64, 256, 1024, 4096, 16384, 65536, 262144, 1048576];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Side {
+    Both,
+    Writer,
+    Reader,
+}
+
+impl FromStr for Side {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "both" => Ok(Side::Both),
+            "writer" => Ok(Side::Writer),
+            "reader" => Ok(Side::Reader),
+            _ => Err(format!("unknown side `{}` (both|writer|reader)", s)),
+        }
+    }
+}
+
+// `--name=a,b,c`, exiting on a bad element like `Args::parsed` does.
+fn list<T: FromStr>(cli: &Args, name: &str) -> Option<Vec<T>>
+where
+    T::Err: Display,
+{
+    cli.value(name).map(|v| {
+        v.split(',')
+            .map(|item| {
+                item.parse().unwrap_or_else(|e| {
+                    eprintln!("invalid value for --{}={}: {}", name, v, e);
+                    std::process::exit(cli::USAGE);
+                })
+            })
+            .collect()
+    })
+}
+
+struct Run<'a> {
+    name: String,
+    capacity: u64,
+    transfer: u64,
+    chunk: usize,
+    writer: RingOptions,
+    reader: RingOptions,
+    writer_at: &'a Placement,
+    reader_at: &'a Placement,
+}
+
+// GB/s from the consumer's start signal to its last byte.
+fn run(r: &Run) -> f64 {
+    let mut producer = RingProducer::create_with(&r.name, r.capacity, &r.writer)
+        .or_exit("Failed to create shared memory");
+    thread::scope(|s| {
+        let reader = s.spawn(|| {
+            r.reader_at
+                .apply()
+                .or_exit("Failed to apply placement");
+            let mut consumer = RingConsumer::open_with(&r.name, r.capacity, &r.reader)
+                .or_exit("Failed to map shared memory");
+            let mut dst = vec![1u8; r.chunk];
+            let mut total_read = 0u64;
+            consumer.signal_start();
+            let start = Instant::now();
+            while total_read < r.transfer {
+                let len = (r.chunk as u64).min(r.transfer - total_read) as usize;
+                let read = consumer.read(&mut dst[..len]);
+                if read > 0 {
+                    total_read += read as u64;
+                } else {
+                    std::hint::spin_loop();
+                }
+            }
+            let elapsed = start.elapsed();
+            consumer.signal_done();
+            total_read as f64 / (1024.0 * 1024.0 * 1024.0 * elapsed.as_secs_f64())
+        });
+
+        r.writer_at
+            .apply()
+            .or_exit("Failed to apply placement");
+        let src: Vec<u8> = (0..r.chunk).map(|i| ((i % 255) + 1) as u8).collect();
+        let mut total_written = 0u64;
+        producer.wait_for_consumer();
+        while total_written < r.transfer {
+            let len = (r.chunk as u64).min(r.transfer - total_written) as usize;
+            let written = producer.write(&src[..len]);
+            if written > 0 {
+                total_written += written as u64;
+            } else {
+                std::hint::spin_loop();
+            }
+        }
+        producer.wait_for_consumer_done();
+        reader.join().unwrap()
+    })
+}
+
+// A segment name of this run's own for `backend`.
+fn segment(backend: Backend, dir: &std::path::Path) -> String {
+    let name = format!("copy-sweep-{}", std::process::id());
+    match backend {
+        Backend::Posix | Backend::Memfd => format!("{}:{}", backend, name),
+        Backend::File => format!("file:{}", dir.join(name).display()),
+        Backend::Unix => format!("unix:{}.sock", dir.join(name).display()),
+        Backend::SysV => format!("sysv:{:#x}", 0x4353_0000 | (std::process::id() & 0xffff)),
+    }
+}
+
+fn main() {
+    let cli = Args::from_env();
+    let backends = list(&cli, "backends").unwrap_or_else(|| vec![Backend::Posix]);
+    let file_dir = cli.value("file-dir").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+    let kernels = list(&cli, "kernels").unwrap_or_else(CopyKernel::supported);
+    let chunks = list(&cli, "chunks").unwrap_or_else(|| DEFAULT_CHUNKS.to_vec());
+    let side: Side = cli.parsed("side").unwrap_or(Side::Both);
+    let transfer = cli.parsed::<u64>("size-mb").unwrap_or(256) * MB;
+    let capacity: u64 = cli.parsed("capacity").unwrap_or(4 * MB);
+    let writer_at = Placement::from_args(&cli, "writer-cpu");
+    let reader_at = Placement::from_args(&cli, "reader-cpu");
+    for kernel in &kernels {
+        kernel.check().or_exit("Failed to select copy kernel");
+    }
+
+    println!(
+        "Copy sweep: {} MiB per run through a {} byte ring, kernel on {} side(s)",
+        transfer / MB,
+        capacity,
+        match side {
+            Side::Both => "both",
+            Side::Writer => "the writer",
+            Side::Reader => "the reader",
+        }
+    );
+    for &backend in &backends {
+        let name = segment(backend, &file_dir);
+        println!();
+        println!("Backend: {} ({})", backend, name);
+        print!("{:>10}", "chunk");
+        for kernel in &kernels {
+            print!(" {:>10}", kernel.to_string());
+        }
+        println!("   (GB/s)");
+
+        for &chunk in &chunks {
+            print!("{:>10}", chunk);
+            for &kernel in &kernels {
+                let options = |on: bool| RingOptions {
+                    numa_node: writer_at.node,
+                    copy: if on { kernel } else { CopyKernel::Std },
+                    ..RingOptions::default()
+                };
+                let gbps = run(&Run {
+                    name: name.clone(),
+                    capacity,
+                    transfer,
+                    chunk,
+                    writer: options(side != Side::Reader),
+                    reader: options(side != Side::Writer),
+                    writer_at: &writer_at,
+                    reader_at: &reader_at,
+                });
+                print!(" {:>10.3}", gbps);
+            }
+            println!();
+        }
+    }
+}
@@ -0,0 +1,382 @@
+// launch.rs
+//
+// Runs both peers of a throughput benchmark from one command:
+//
+//   launch [--writer-cpu=LIST] [--reader-cpu=LIST] [--shm=NAME]
+//          [--segment-timeout=SECS] [--max-tsc-skew=NS] [--strict-tsc]
+//          <writer> [args...] -- <reader> [args...]
+//
+// e.g. launch --writer-cpu=2 --reader-cpu=4 writer ring 4194304 1024 4096 -- reader ring 4194304 1024 4096
+//
+// Launcher options come before the writer command; everything after it is
+// passed through untouched. A program without a `/` is looked up next to
+// this binary first, then in PATH.
+//
+// The writer creates the segment, since only it knows the layout (a ring,
+// majd-experiment's `Shared`, ...); the launcher watches its name (`--shm`,
+// by default the writer's first argument, which is where every throughput
+// bench takes it). Leftovers are the writer's to deal with: it replaces a
+// dead one and fails with `InUse` if another producer has the name. The
+// reader is only started once the object under the name is mapped by the
+// writer (/proc/PID/maps), so readers that do not wait for the name work
+// too, and none is ever pointed at someone else's segment; a ring reader
+// then waits for `ready` itself. A writer that has not got that far after
+// `--segment-timeout` (30 s by default) is killed and the launch fails
+// with `Timeout`. At the end the name is unlinked if it still holds the
+// object the writer mapped, which a killed writer leaves behind; anything
+// else under it is left alone. The writer's `--pages`/`--hugetlbfs` say
+// where the object lives.
+//
+// With a `unix:/path` segment nothing is left in /dev/shm: the writer's
+// memfd reaches the reader over the socket, and the launcher only asks who
+// is listening there (SO_PEERCRED), which leaves the fd for the reader. A
+// killed writer's socket is replaced by the next producer that listens.
+//
+// Before anything starts, the TSC is checked (see `tsc.rs`) on the CPUs
+// either side may run on (every CPU the launcher may use if a side is not
+// pinned), since the sides' checkpoints are only comparable if it is
+// invariant and agrees across them. A skew over `--max-tsc-skew` (1 µs by
+// default) or a TSC that is not invariant is reported, and with
+// `--strict-tsc` ends the launch with the invalid configuration status.
+//
+// Each side runs pinned to its CPU list (`sched_setaffinity` between fork
+// and exec) with stdout and stderr collected and prefixed with its role. If
+// either side fails, the other is killed, since it would wait for its peer
+// forever. Exits 0 if both sides succeed; otherwise with the exit status of
+// a side that failed by itself rather than being killed (else 1), so the
+// cause a bench reported (see `error.rs`) survives the launcher.
+
+use std::env;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use common::affinity::{allowed_cpus, set_affinity, CpuList};
+use common::cli::{self, Args};
+use common::error::OrExit;
+use common::backend::Backend;
+use common::ring::SegmentName;
+use common::{tsc, RingError, RingOptions};
+
+const USAGE: &str = "Usage: launch [--writer-cpu=LIST] [--reader-cpu=LIST] [--shm=NAME] [--segment-timeout=SECS] [--max-tsc-skew=NS] [--strict-tsc] <writer> [args...] -- <reader> [args...]";
+
+/// Default for `--max-tsc-skew`.
+const MAX_TSC_SKEW_NS: u64 = 1000;
+
+/// How often the launcher checks for the segment while the writer creates it.
+const SEGMENT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Default for `--segment-timeout`.
+const SEGMENT_TIMEOUT_SECS: f64 = 30.0;
+
+struct Role {
+    name: &'static str,
+    child: Child,
+    output: Vec<JoinHandle<()>>,
+    status: Option<ExitStatus>,
+}
+
+fn main() {
+    let (options, writer_cmd, reader_cmd) = split_args(env::args().skip(1).collect());
+    let cli = Args::parse(options);
+    let writer_cpu: Option<CpuList> = cli.parsed("writer-cpu");
+    let reader_cpu: Option<CpuList> = cli.parsed("reader-cpu");
+    let max_skew_ns: u64 = cli.parsed("max-tsc-skew").unwrap_or(MAX_TSC_SKEW_NS);
+    let segment_timeout = Duration::from_secs_f64(
+        cli.parsed("segment-timeout")
+            .unwrap_or(SEGMENT_TIMEOUT_SECS)
+            .max(0.0),
+    );
+    let shm = cli
+        .value("shm")
+        .or(writer_cmd.get(1).map(String::as_str))
+        .unwrap_or_else(|| usage());
+    // Only the options that decide where the segment lives matter here.
+    let writer_cli = Args::parse(writer_cmd.iter().cloned());
+    let options = RingOptions {
+        pages: writer_cli.parsed("pages").unwrap_or_default(),
+        hugetlbfs: writer_cli.value("hugetlbfs").map(PathBuf::from),
+        ..RingOptions::default()
+    };
+    let shm = SegmentName::new(shm, &options).or_exit("launch: invalid segment name");
+
+    println!("========================================");
+    println!("LAUNCH");
+    println!("========================================");
+    println!("Segment: {}", shm.display());
+    println!(
+        "Writer: {} (cpus {})",
+        writer_cmd.join(" "),
+        describe(&writer_cpu)
+    );
+    println!(
+        "Reader: {} (cpus {})",
+        reader_cmd.join(" "),
+        describe(&reader_cpu)
+    );
+    check_tsc(&writer_cpu, &reader_cpu, max_skew_ns, cli.flag("strict-tsc")).or_exit("launch");
+    println!("========================================");
+
+    let mut roles = vec![spawn("writer", &writer_cmd, writer_cpu.as_ref()).or_exit("launch")];
+    let segment = wait_for_segment(&shm, &mut roles[0], segment_timeout).or_exit("launch");
+    match segment {
+        Some(object) => match spawn("reader", &reader_cmd, reader_cpu.as_ref()) {
+            Ok(reader) => roles.push(reader),
+            Err(e) => {
+                kill(&mut roles[0]);
+                remove(&shm, object);
+                Err::<(), _>(e).or_exit("launch");
+            }
+        },
+        None => println!("launch: writer exited before creating the segment"),
+    }
+    wait_all(&mut roles).or_exit("launch");
+    if let Some(object) = segment {
+        remove(&shm, object);
+    }
+
+    let mut ok = roles.len() == 2;
+    let mut code = None;
+    for role in &mut roles {
+        for output in role.output.drain(..) {
+            let _ = output.join();
+        }
+        let status = role.status.expect("child not reaped");
+        println!("launch: {} {}", role.name, status);
+        if !status.success() {
+            ok = false;
+            code = code.or(status.code());
+        }
+    }
+    std::process::exit(if ok { 0 } else { code.unwrap_or(1) });
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(cli::USAGE);
+}
+
+// Splits the command line into launcher options, the writer command and the
+// reader command.
+fn split_args(args: Vec<String>) -> (Vec<String>, Vec<String>, Vec<String>) {
+    let first = args
+        .iter()
+        .position(|a| !a.starts_with("--"))
+        .unwrap_or_else(|| usage());
+    let sep = match args[first..].iter().position(|a| a == "--") {
+        Some(i) => first + i,
+        None => usage(),
+    };
+    let mut options = vec!["launch".to_string()];
+    options.extend_from_slice(&args[..first]);
+    let writer = args[first..sep].to_vec();
+    let reader = args[sep + 1..].to_vec();
+    if writer.is_empty() || reader.is_empty() {
+        usage();
+    }
+    (options, writer, reader)
+}
+
+// Reports whether the two sides' TSC readings can be compared; an error if
+// not and `strict`.
+fn check_tsc(
+    writer_cpu: &Option<CpuList>,
+    reader_cpu: &Option<CpuList>,
+    max_skew_ns: u64,
+    strict: bool,
+) -> Result<(), RingError> {
+    let anywhere = || allowed_cpus().map(|cpus| cpus.cpus().to_vec());
+    let cpus = match (writer_cpu, reader_cpu) {
+        (Some(w), Some(r)) => Ok([w.cpus(), r.cpus()].concat()),
+        _ => anywhere(),
+    };
+    let problem = match cpus.and_then(|cpus| tsc::check(&cpus)) {
+        Ok(report) => {
+            println!("TSC: {}", report);
+            if report.comparable(max_skew_ns) {
+                return Ok(());
+            }
+            if report.features.invariant() {
+                format!("skew of {} ns is over {} ns", report.skew_ns(), max_skew_ns)
+            } else {
+                "the TSC is not invariant".to_string()
+            }
+        }
+        Err(e) => format!("cannot check the TSC: {}", e),
+    };
+    let problem = format!("writer and reader TSC checkpoints are not comparable: {}", problem);
+    if strict {
+        return Err(RingError::InvalidConfig(problem));
+    }
+    println!("launch: {}", problem);
+    Ok(())
+}
+
+fn describe(cpus: &Option<CpuList>) -> String {
+    match cpus {
+        Some(cpus) => cpus.to_string(),
+        None => "any".to_string(),
+    }
+}
+
+// A bare program name resolves to a sibling of this binary if there is one,
+// so `launch writer ... -- reader ...` runs the benches built alongside it.
+fn resolve(program: &str) -> PathBuf {
+    if !program.contains('/') {
+        if let Some(dir) = env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(PathBuf::from))
+        {
+            let sibling = dir.join(program);
+            if sibling.is_file() {
+                return sibling;
+            }
+        }
+    }
+    PathBuf::from(program)
+}
+
+fn spawn(name: &'static str, cmd: &[String], cpus: Option<&CpuList>) -> Result<Role, RingError> {
+    let mut command = Command::new(resolve(&cmd[0]));
+    command
+        .args(&cmd[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    if let Some(cpus) = cpus {
+        let set = cpus.to_cpu_set();
+        // Only the async-signal-safe syscall runs in the forked child.
+        unsafe { command.pre_exec(move || set_affinity(&set)) };
+    }
+    let mut child = command.spawn().map_err(|e| {
+        RingError::InvalidConfig(format!("cannot start {} {:?}: {}", name, cmd[0], e))
+    })?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let output = vec![
+        forward(name, stdout, || Box::new(io::stdout())),
+        forward(name, stderr, || Box::new(io::stderr())),
+    ];
+    Ok(Role {
+        name,
+        child,
+        output,
+        status: None,
+    })
+}
+
+// Copies `from` line by line to `to`, each line prefixed with the role.
+fn forward(
+    name: &'static str,
+    from: impl Read + Send + 'static,
+    to: fn() -> Box<dyn Write>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut from = BufReader::new(from);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match from.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            if !line.ends_with(b"\n") {
+                line.push(b'\n');
+            }
+            let mut to = to();
+            let _ = write!(to, "[{}] ", name).and_then(|_| to.write_all(&line));
+        }
+    })
+}
+
+// Unlinks `shm` if it still names `object` (an `id`), which the writer
+// mapped; a unix segment's socket is gone once used or replaced by the
+// next producer.
+fn remove(shm: &SegmentName, object: (u64, u64)) {
+    if shm.backend() == Backend::Unix {
+        return;
+    }
+    let Ok(handle) = shm.open(false) else {
+        return;
+    };
+    let same = shm.id(handle).is_ok_and(|id| id == object);
+    shm.close(handle);
+    if same {
+        let _ = shm.unlink();
+    }
+}
+
+fn kill(role: &mut Role) {
+    unsafe { libc::kill(role.child.id() as libc::pid_t, libc::SIGKILL) };
+    role.status = role.child.wait().ok();
+}
+
+// Waits until the writer has mapped the object under `shm` and returns its
+// `id`. `None` if the writer exited first, e.g. because another producer
+// has the name; a writer still not there after `timeout` is killed.
+fn wait_for_segment(
+    shm: &SegmentName,
+    writer: &mut Role,
+    timeout: Duration,
+) -> Result<Option<(u64, u64)>, RingError> {
+    let pid = writer.child.id();
+    let start = Instant::now();
+    loop {
+        if let Ok(Some(object)) = shm.mapped_by(pid) {
+            return Ok(Some(object));
+        }
+        match writer.child.try_wait() {
+            Ok(Some(status)) => {
+                writer.status = Some(status);
+                return Ok(None);
+            }
+            Ok(None) => {}
+            Err(e) => return Err(RingError::InvalidConfig(format!("waitpid: {}", e))),
+        }
+        let waited = start.elapsed();
+        if waited >= timeout {
+            kill(writer);
+            return Err(RingError::Timeout {
+                name: shm.display().into_owned(),
+                waited,
+            });
+        }
+        thread::sleep(SEGMENT_POLL_INTERVAL);
+    }
+}
+
+// Reaps every role. The first one to fail takes the others down with it.
+fn wait_all(roles: &mut [Role]) -> Result<(), RingError> {
+    while roles.iter().any(|r| r.status.is_none()) {
+        let mut raw = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut raw, 0) };
+        if pid < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(RingError::InvalidConfig(format!("waitpid: {}", e)));
+        }
+        let status = ExitStatus::from_raw(raw);
+        let Some(role) = roles.iter_mut().find(|r| r.child.id() == pid as u32) else {
+            continue;
+        };
+        role.status = Some(status);
+        if status.success() {
+            continue;
+        }
+        let name = role.name;
+        for other in roles.iter().filter(|r| r.status.is_none()) {
+            println!(
+                "launch: {} failed ({}), killing {}",
+                name, status, other.name
+            );
+            unsafe { libc::kill(other.child.id() as libc::pid_t, libc::SIGKILL) };
+        }
+    }
+    Ok(())
+}
@@ -1,178 +1,165 @@
 // reader.rs
-use std::env;
-use std::ffi::CString;
-use std::sync::atomic::{fence, Ordering};
-use std::ptr;
-use std::mem::size_of;
-use throughput::{read_tsc, ShmHeader};
+use std::path::PathBuf;
+use std::time::Duration;
+use common::affinity::Placement;
+use common::cli::{Args, USAGE};
+use common::checkpoint::{CheckpointOptions, Recorder};
+use common::clock::{overhead, Clock, ClockSource};
+use common::error::{OrExit, RingError};
+use common::numa;
+use common::prefault::{Faults, Prefault};
+use common::sampler::SampleOptions;
+use common::{RingConsumer, RingOptions};
 
 const MB: u64 = 1024 * 1024;
 
+// Empty polls between checks that the writer is still running.
+const PEER_CHECK_POLLS: u64 = 1 << 16;
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
 
     if args.len() < 5 {
         eprintln!(
-            "Usage: {} <shared_mem_name> <share_mem_size_bytes> <transfer_size_mb> <read_chunk_size_bytes>",
+            "Usage: {} <shared_mem_name|posix:|file:|memfd:|sysv:|unix:NAME> <share_mem_size_bytes> <transfer_size_mb> <read_chunk_size_bytes> [--mirrored] [--layout=legacy|padded] [--blocking] [--cpu=LIST] [--numa=NODE] [--pages=4k|thp|2m|1g] [--hugetlbfs=DIR] [--populate] [--mlock] [--warm] [--copy=std|movsb|avx2|avx512|nt] [--clock=tsc|tsc-lfence|tsc-raw|rdtscp|monotonic] [--checkpoints=N] [--checkpoint-format=text|csv|json] [--checkpoint-file=PATH] [--sample-us=N] [--sample-format=text|csv|json] [--sample-file=PATH] [--prefetch=LINES] [--timeout=SECS]",
             args[0]
         );
-        std::process::exit(1);
+        std::process::exit(USAGE);
     }
 
     let shm_name = &args[1];
-    let shm_size: u64 = args[2].parse()
-        .expect("share_mem_size must be a valid number (bytes)");
-    let transfer_size_mb: u64 = args[3].parse()
-        .expect("transfer_size_mb must be a valid number (MB)");
+    let shm_size: u64 = cli.arg(2, "share_mem_size_bytes");
+    let transfer_size_mb: u64 = cli.arg(3, "transfer_size_mb");
     let transfer_size: u64 = transfer_size_mb.saturating_mul(MB);
-    let chunk_size: u32 = args[4].parse()
-        .expect("chunk_size must be a valid number (bytes)");
-
-    // Add '/' prefix if needed
-    let shm_name = if shm_name.starts_with('/') {
-        shm_name.to_string()
-    } else {
-        format!("/{}", shm_name)
+    let chunk_size: u32 = cli.arg(4, "read_chunk_size_bytes");
+    let placement = Placement::from_args(&cli, "cpu");
+    let clock: ClockSource = cli.parsed("clock").unwrap_or_default();
+    placement
+        .apply()
+        .or_exit("Reader: Failed to apply placement");
+    let options = RingOptions {
+        mirrored: cli.flag("mirrored"),
+        layout: cli.parsed("layout").unwrap_or_default(),
+        blocking: cli.flag("blocking"),
+        numa_node: placement.node,
+        pages: cli.parsed("pages").unwrap_or_default(),
+        hugetlbfs: cli.value("hugetlbfs").map(PathBuf::from),
+        prefault: Prefault::from_args(&cli),
+        copy: cli.parsed("copy").unwrap_or_default(),
+        prefetch: cli.parsed("prefetch").unwrap_or_default(),
+        open_timeout: cli.parsed("timeout").map(Duration::from_secs_f64),
     };
 
-    // Convert to C string
-    let c_name = CString::new(shm_name.as_bytes()).unwrap();
-
     println!("Reader: Waiting for writer to create shared memory...");
 
-    // Open existing shared memory (no O_CREAT flag)
-    let fd = loop {
-        let fd = unsafe { libc::shm_open(c_name.as_ptr(), libc::O_RDWR, 0o666) };
-        if fd >= 0 {
-            break fd;
-        }
-        std::thread::sleep(std::time::Duration::from_millis(100));
-    };
+    let mut consumer = RingConsumer::open_with(shm_name, shm_size, &options)
+        .or_exit("Reader: Failed to open shared memory");
 
     println!("Reader: Shared memory found!");
-
-    let total_size = size_of::<ShmHeader>() as u64 + shm_size;
-    println!("Writer: ShmHeader size: {}", size_of::<ShmHeader>());
-
-    let ptr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),
-            total_size as usize,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED,
-            fd,
-            0,
-        )
-    };
-
-    if ptr == libc::MAP_FAILED {
-        panic!("Failed to map shared memory");
-    }
-
-    // Get pointers to shared variables
-    let header = unsafe { &*(ptr as *mut ShmHeader) };
-    let data_start = unsafe { (ptr as *mut u8).add(size_of::<ShmHeader>()) };
+    // Calibrates the TSC now rather than at the first checkpoint.
+    println!(
+        "Reader: Clock: {} at {:.3} GHz, {}",
+        clock,
+        clock.hz() / 1e9,
+        overhead(&clock)
+    );
+    println!("Writer: ShmHeader size: {}", options.layout.header_size());
 
     // Prepare buffer for reading
     let mut dst = vec![0u8; transfer_size as usize];
 
     // Touch pages so allocation/fault cost doesn't hit the timed path (reader side)
-    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize };
-    for i in (0..dst.len()).step_by(page_size) {
-        dst[i] = 1;
-    }
+    Prefault { warm: true, ..options.prefault }
+        .buffer(&mut dst)
+        .map_err(|source| RingError::Map {
+            name: "the sink".to_string(),
+            source,
+        })
+        .or_exit("Reader: Failed to prefault the sink");
 
     let mut total_read = 0u64;
+    let mut empty_polls = 0u64;
 
-    #[cfg(debug_assertions)]
-    let mut xor_checksum: u8 = 0;
-
-    // tsc
-    let ckpt_total_interval = 10;
-    let ckpt_interval_sz = (transfer_size + ckpt_total_interval - 1) / ckpt_total_interval;
-    let mut ckpt_next = ckpt_interval_sz;
+    let mut checkpoints = Recorder::new(
+        "Reader",
+        clock,
+        transfer_size,
+        &CheckpointOptions::from_args(&cli),
+    );
+    let mut sampler = SampleOptions::from_args(&cli).sampler("Reader", clock);
 
     // Change transfer_started to 1 (signal writer to start)
-    header.transfer_started.store(1, Ordering::Release);
+    let faults = Faults::now();
+    consumer.signal_start();
     println!("Reader: Signaled writer to start, waiting for data...");
 
-    eprintln!("--- Reader checkpoint 0/{} tsc: {}", ckpt_total_interval, read_tsc());
+    checkpoints.start();
+    if let Some(sampler) = &mut sampler {
+        sampler.start();
+    }
 
     while total_read < transfer_size {
-        let end_idx = header.end_index.load(Ordering::Acquire);
-        let start_idx = header.start_index.load(Ordering::Acquire);
-
-        let avail_len = end_idx - start_idx;
-
-        if avail_len > 0 {
-            let len = (chunk_size as u64)
-                .min(transfer_size - total_read)
-                .min(avail_len);
-
-            // Calculate read position with wrap-around
-            let read_start = (start_idx % shm_size) as usize;
-            let l = std::cmp::min(len, shm_size - read_start as u64) as usize;
-
-            unsafe {
-                // First part (until wrap or end of chunk)
-                ptr::copy_nonoverlapping(
-                    data_start.add(read_start),
-                    dst.as_mut_ptr().add(total_read as usize),
-                    l,
-                );
-
-                // Second part (wrapped around to beginning)
-                if l < len as usize {
-                    ptr::copy_nonoverlapping(
-                        data_start,
-                        dst.as_mut_ptr().add(total_read as usize + l),
-                        len as usize - l,
-                    );
-                }
-            }
+        let len = (chunk_size as u64).min(transfer_size - total_read) as usize;
+        let read = consumer.read(&mut dst[total_read as usize..][..len]);
 
-            // Barrier: smp_wmb()
-            fence(Ordering::Release);
+        if read > 0 {
+            total_read += read as u64;
 
-            header.start_index.store(start_idx + len, Ordering::Relaxed);
-            total_read += len;
-
-            if total_read > ckpt_next {
-                eprintln!(
-                    "--- Reader checkpoint {}/{} tsc: {}",
-                    ckpt_next / ckpt_interval_sz,
-                    ckpt_total_interval,
-                    read_tsc()
-                );
-                ckpt_next += ckpt_interval_sz;
+            checkpoints.progress(total_read);
+            if let Some(sampler) = &mut sampler {
+                sampler.progress(total_read);
             }
         } else {
-            std::hint::spin_loop();
+            empty_polls += 1;
+            if empty_polls.is_multiple_of(PEER_CHECK_POLLS) {
+                consumer.check_producer().or_exit("Reader: Writer went away");
+            }
+            consumer.wait_for_data().or_exit("Reader: Writer went away");
         }
     }
 
-    eprintln!(
-        "--- Reader checkpoint {}/{} tsc: {}",
-        ckpt_next / ckpt_interval_sz,
-        ckpt_total_interval,
-        read_tsc()
-    );
+    checkpoints.finish(total_read);
+    if let Some(sampler) = &mut sampler {
+        sampler.finish(total_read);
+    }
+    let faults = Faults::since(faults);
     println!("Reader: Finished reading {} bytes", total_read);
-
-    header.transfer_started.store(0, Ordering::Relaxed);
+    println!("Reader: Page faults: {} (prefault: {})", faults, options.prefault);
+
+    consumer.signal_done();
+    checkpoints
+        .report()
+        .map_err(|e| RingError::InvalidConfig(e.to_string()))
+        .or_exit("Reader: Failed to write checkpoints");
+    if let Some(sampler) = &sampler {
+        sampler
+            .report()
+            .map_err(|e| RingError::InvalidConfig(e.to_string()))
+            .or_exit("Reader: Failed to write samples");
+    }
+    println!(
+        "Reader: Topology: {}, ring data on node {}",
+        placement,
+        numa::show(consumer.data_node())
+    );
+    println!("Reader: Ring pages: {}", consumer.pages());
+    println!("Reader: Segment: {}", consumer.segment().display());
+    println!("Reader: Copy kernel: {}", consumer.copy_kernel());
+    println!("Reader: Migrations: {} between checkpoints", checkpoints.migrations());
+    if let Some(sampler) = &sampler {
+        println!(
+            "Reader: Samples: {} of {} ns, {} without progress",
+            sampler.samples().len(),
+            clock.to_ns(sampler.quantum()),
+            sampler.stalls()
+        );
+    }
+    println!("Reader: Prefetch: {} cache lines ahead", options.prefetch);
 
     #[cfg(debug_assertions)]
     {
-        for i in 0..total_read as usize {
-            xor_checksum ^= dst[i];
-        }
+        let xor_checksum = dst[..total_read as usize].iter().fold(0u8, |acc, &b| acc ^ b);
         println!("Reader XOR checksum: 0x{:02X}", xor_checksum);
     }
-
-    // Cleanup
-    unsafe {
-        libc::munmap(ptr, total_size as usize);
-        libc::close(fd);
-        libc::shm_unlink(c_name.as_ptr());
-    }
-}
\ No newline at end of file
+}
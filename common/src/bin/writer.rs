@@ -1,165 +1,119 @@
 // writer.rs
-use std::env;
-use std::ffi::CString;
-use std::sync::atomic::{fence, Ordering};
+use std::path::PathBuf;
 use std::time::Instant;
-use std::ptr;
-use std::mem::size_of;
-use throughput::{read_tsc, ShmHeader};
+use common::affinity::Placement;
+use common::cli::{Args, USAGE};
+use common::checkpoint::{CheckpointOptions, Recorder};
+use common::clock::{overhead, Clock, ClockSource};
+use common::error::{OrExit, RingError};
+use common::numa;
+use common::prefault::{Faults, Prefault};
+use common::sampler::SampleOptions;
+use common::{RingOptions, RingProducer};
 
 const MB: u64 = 1024 * 1024;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
 
     if args.len() < 5 {
         eprintln!(
-            "Usage: {} <shared_mem_name> <share_mem_size_bytes> <transfer_size_mb> <write_chunk_size_bytes>",
+            "Usage: {} <shared_mem_name|posix:|file:|memfd:|sysv:|unix:NAME> <share_mem_size_bytes> <transfer_size_mb> <write_chunk_size_bytes> [--mirrored] [--layout=legacy|padded] [--blocking] [--cpu=LIST] [--numa=NODE] [--pages=4k|thp|2m|1g] [--hugetlbfs=DIR] [--populate] [--mlock] [--warm] [--copy=std|movsb|avx2|avx512|nt] [--clock=tsc|tsc-lfence|tsc-raw|rdtscp|monotonic] [--checkpoints=N] [--checkpoint-format=text|csv|json] [--checkpoint-file=PATH] [--sample-us=N] [--sample-format=text|csv|json] [--sample-file=PATH]",
             args[0]
         );
-        std::process::exit(1);
+        std::process::exit(USAGE);
     }
 
     let shm_name = &args[1];
-    let shm_size: u64 = args[2].parse()
-        .expect("share_mem_size must be a valid number (bytes)");
-    let transfer_size_mb: u64 = args[3].parse()
-        .expect("transfer_size_mb must be a valid number (MB)");
+    let shm_size: u64 = cli.arg(2, "share_mem_size_bytes");
+    let transfer_size_mb: u64 = cli.arg(3, "transfer_size_mb");
     let transfer_size: u64 = transfer_size_mb.saturating_mul(MB);
-    let chunk_size: u32 = args[4].parse()
-        .expect("chunk_size must be a valid number (bytes)");
-
-    // Add '/' prefix if needed
-    let shm_name = if shm_name.starts_with('/') {
-        shm_name.to_string()
-    } else {
-        format!("/{}", shm_name)
+    let chunk_size: u32 = cli.arg(4, "write_chunk_size_bytes");
+    let placement = Placement::from_args(&cli, "cpu");
+    let clock: ClockSource = cli.parsed("clock").unwrap_or_default();
+    placement
+        .apply()
+        .or_exit("Writer: Failed to apply placement");
+    let options = RingOptions {
+        mirrored: cli.flag("mirrored"),
+        layout: cli.parsed("layout").unwrap_or_default(),
+        blocking: cli.flag("blocking"),
+        numa_node: placement.node,
+        pages: cli.parsed("pages").unwrap_or_default(),
+        hugetlbfs: cli.value("hugetlbfs").map(PathBuf::from),
+        prefault: Prefault::from_args(&cli),
+        copy: cli.parsed("copy").unwrap_or_default(),
+        ..RingOptions::default()
     };
 
-    // Convert to C string
-    let c_name = CString::new(shm_name.as_bytes()).unwrap();
-
-    // Open/create shared memory
-    let fd = unsafe { libc::shm_open(c_name.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o666) };
-
-    if fd < 0 {
-        panic!("Failed to create shared memory");
-    }
-
-    let total_size = size_of::<ShmHeader>() as u64 + shm_size;
-    println!("Writer: ShmHeader size: {}", size_of::<ShmHeader>());
-
-    unsafe {
-        libc::ftruncate(fd, total_size as i64);
-    }
-
-    let ptr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),
-            total_size as usize,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED,
-            fd,
-            0,
-        )
-    };
-
-    if ptr == libc::MAP_FAILED {
-        panic!("Failed to map shared memory");
-    }
-
-    // Get pointers to shared variables
-    let header = unsafe { &*(ptr as *mut ShmHeader) };
-    let data_start = unsafe { (ptr as *mut u8).add(size_of::<ShmHeader>()) };
+    let mut producer = RingProducer::create_with(shm_name, shm_size, &options)
+        .or_exit("Writer: Failed to create shared memory");
+    println!("Writer: ShmHeader size: {}", options.layout.header_size());
+    // Calibrates the TSC now rather than at the first checkpoint.
+    println!(
+        "Writer: Clock: {} at {:.3} GHz, {}",
+        clock,
+        clock.hz() / 1e9,
+        overhead(&clock)
+    );
 
     // Fill with pattern: 1, 2, 3, ..., 255, 1, 2, 3, ...
-    let mut src = vec![0u8; chunk_size as usize];
-    for i in 0..chunk_size as usize {
-        src[i] = ((i % 255) + 1) as u8;
-    }
+    let src: Vec<u8> = (0..chunk_size as usize).map(|i| ((i % 255) + 1) as u8).collect();
 
     let mut total_written = 0u64;
 
     #[cfg(debug_assertions)]
     let mut xor_checksum: u8 = 0;
 
-    // tsc
-    let ckpt_total_interval = 10;
-    let ckpt_interval_sz = (transfer_size + ckpt_total_interval - 1) / ckpt_total_interval;
-    let mut ckpt_next = ckpt_interval_sz;
+    let mut checkpoints = Recorder::new(
+        "Writer",
+        clock,
+        transfer_size,
+        &CheckpointOptions::from_args(&cli),
+    );
+    let mut sampler = SampleOptions::from_args(&cli).sampler("Writer", clock);
 
     println!("Writer: Waiting for reader to start (transfer_started=1)...");
 
     // Wait till reader changes transfer_started to 1
-    while header.transfer_started.load(Ordering::Acquire) == 0 {
-        std::hint::spin_loop();
-    }
+    producer.wait_for_consumer();
 
     println!("Writer: Reader ready, starting write...");
     let start_time = Instant::now();
-    eprintln!("--- Writer checkpoint 0/{} tsc: {}", ckpt_total_interval, read_tsc());
+    let faults = Faults::now();
+    checkpoints.start();
+    if let Some(sampler) = &mut sampler {
+        sampler.start();
+    }
 
     while total_written < transfer_size {
-        let end_idx = header.end_index.load(Ordering::Acquire);
-        let start_idx = header.start_index.load(Ordering::Acquire);
-
-        let unused_len = shm_size - (end_idx - start_idx);
-
-        if unused_len > 0 {
-            let len = (chunk_size as u64)
-                .min(transfer_size - total_written)
-                .min(unused_len);
-
-            // Calculate write position with wrap-around
-            let write_start = (end_idx % shm_size) as usize;
-            let l = std::cmp::min(len, shm_size - write_start as u64) as usize;
-
-            unsafe {
-                // First part (until wrap or end of chunk)
-                ptr::copy_nonoverlapping(src.as_ptr(), data_start.add(write_start), l);
-
-                // Second part (wrapped around to beginning)
-                if l < len as usize {
-                    ptr::copy_nonoverlapping(
-                        src.as_ptr().add(l),
-                        data_start,
-                        len as usize - l,
-                    );
-                }
-            }
-
-            fence(Ordering::Release);
+        let len = (chunk_size as u64).min(transfer_size - total_written) as usize;
+        let written = producer.write(&src[..len]);
 
-            header.end_index.store(end_idx + len, Ordering::Release);
-            total_written += len;
+        if written > 0 {
+            total_written += written as u64;
 
             #[cfg(debug_assertions)]
             {
-                for i in 0..len as usize {
-                    xor_checksum ^= src[i];
+                for &b in &src[..written] {
+                    xor_checksum ^= b;
                 }
             }
 
-            if total_written > ckpt_next {
-                eprintln!(
-                    "--- Writer checkpoint {}/{} tsc: {}",
-                    ckpt_next / ckpt_interval_sz,
-                    ckpt_total_interval,
-                    read_tsc()
-                );
-                ckpt_next += ckpt_interval_sz;
+            checkpoints.progress(total_written);
+            if let Some(sampler) = &mut sampler {
+                sampler.progress(total_written);
             }
         } else {
-            std::hint::spin_loop();
+            producer.wait_for_space();
         }
     }
 
-    eprintln!(
-        "--- Writer checkpoint {}/{} tsc: {}",
-        ckpt_next / ckpt_interval_sz,
-        ckpt_total_interval,
-        read_tsc()
-    );
+    checkpoints.finish(total_written);
+    if let Some(sampler) = &mut sampler {
+        sampler.finish(total_written);
+    }
     println!("Writer: Finished writing {} bytes", total_written);
 
     #[cfg(debug_assertions)]
@@ -168,26 +122,53 @@ fn main() {
     println!("Writer: Waiting for reader to finish ...");
 
     // Wait till reader changes transfer_started to 0
-    while header.transfer_started.load(Ordering::Relaxed) != 0 {
-        std::hint::spin_loop();
-    }
+    producer.wait_for_consumer_done();
 
     let elapsed = start_time.elapsed();
+    let faults = Faults::since(faults);
+    checkpoints
+        .report()
+        .map_err(|e| RingError::InvalidConfig(e.to_string()))
+        .or_exit("Writer: Failed to write checkpoints");
+    if let Some(sampler) = &sampler {
+        sampler
+            .report()
+            .map_err(|e| RingError::InvalidConfig(e.to_string()))
+            .or_exit("Writer: Failed to write samples");
+    }
 
     println!("========================================");
     println!("WRITER STATS");
     println!("========================================");
     println!("Total time: {} µs, {} s", elapsed.as_micros(), elapsed.as_secs_f64());
     println!("Data written: {} bytes", total_written);
+    println!("Page faults: {} (prefault: {})", faults, options.prefault);
+    println!("Copy kernel: {}", producer.copy_kernel());
+    println!("Migrations: {} between checkpoints", checkpoints.migrations());
+    if let Some(sampler) = &sampler {
+        println!(
+            "Samples: {} of {} ns, {} without progress",
+            sampler.samples().len(),
+            clock.to_ns(sampler.quantum()),
+            sampler.stalls()
+        );
+    }
+    println!(
+        "Ring: {}, {:?} header, {}, {}",
+        if producer.is_mirrored() { "mirrored" } else { "split copy" },
+        producer.layout(),
+        if producer.is_blocking() { "blocking" } else { "spinning" },
+        producer.pages()
+    );
+    println!("Segment: {}", producer.segment().display());
+    println!(
+        "Topology: {}, ring data on node {}",
+        placement,
+        numa::show(producer.data_node())
+    );
     println!(
         "Throughput: {:.4} GB / s",
         total_written as f64 / (1024.0 * 1024.0 * 1024.0 * elapsed.as_secs_f64())
     );
     println!("========================================");
-
-    // Cleanup
-    unsafe {
-        libc::munmap(ptr, total_size as usize);
-        libc::close(fd);
-    }
-}
\ No newline at end of file
+}
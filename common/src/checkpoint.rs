@@ -0,0 +1,331 @@
+// checkpoint.rs
+//
+// Progress timestamps of a throughput run, kept in memory and written out
+// once the timed part is over, so taking one costs a clock reading and a
+// store into a preallocated vector rather than a write to stderr:
+//
+//   let mut checkpoints = Recorder::new("Writer", clock, transfer, &options);
+//   checkpoints.start();
+//   ... checkpoints.progress(bytes) after every chunk ...
+//   checkpoints.finish(bytes);
+//   checkpoints.report()?;
+//
+// `progress` is one comparison until the next of `count` evenly spaced byte
+// marks is passed; then it stamps (see `clock.rs`) once, however many marks
+// the chunk passed. Every checkpoint keeps the bytes done, the clock reading,
+// its nanoseconds and the CPU, and whether the thread moved since the last.
+//
+// Output goes to stderr, or `--checkpoint-file`, in one of
+//
+//   text  --- Writer checkpoint 3/10 bytes: 3145728 tsc: ... cpu: 2
+//   csv   role,index,bytes,clock,ticks,ns,cpu,migrated_from, one row each
+//   json  {"role": ..., "clock": ..., "hz": ..., "checkpoints": [{...}]}
+//
+// `plotting/plot_tsc.py` reads the CSV files.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::cli::Args;
+use crate::clock::{Clock, ClockSource, Migrations, Stamp};
+
+/// Default `--checkpoints`.
+pub const DEFAULT_COUNT: usize = 10;
+
+/// How `report` writes the checkpoints.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Text, Format::Csv, Format::Json];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Csv => "csv",
+            Format::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| format!("unknown checkpoint format `{}` (text|csv|json)", s))
+    }
+}
+
+/// How many checkpoints to take and where they go.
+#[derive(Debug, Clone)]
+pub struct CheckpointOptions {
+    /// Intervals the transfer is split into.
+    pub count: usize,
+    pub format: Format,
+    /// stderr if `None`.
+    pub file: Option<PathBuf>,
+}
+
+impl Default for CheckpointOptions {
+    fn default() -> Self {
+        CheckpointOptions {
+            count: DEFAULT_COUNT,
+            format: Format::default(),
+            file: None,
+        }
+    }
+}
+
+impl CheckpointOptions {
+    /// `--checkpoints=N`, `--checkpoint-format=text|csv|json` and
+    /// `--checkpoint-file=PATH`.
+    pub fn from_args(cli: &Args) -> Self {
+        CheckpointOptions {
+            count: cli.parsed("checkpoints").unwrap_or(DEFAULT_COUNT),
+            format: cli.parsed("checkpoint-format").unwrap_or_default(),
+            file: cli.value("checkpoint-file").map(PathBuf::from),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    /// Bytes transferred when the stamp was taken.
+    pub bytes: u64,
+    pub stamp: Stamp,
+}
+
+/// Checkpoints of one side of a transfer.
+pub struct Recorder {
+    role: &'static str,
+    clock: ClockSource,
+    options: CheckpointOptions,
+    interval: u64,
+    next: u64,
+    migrations: Migrations,
+    points: Vec<Checkpoint>,
+}
+
+impl Recorder {
+    /// Room for every checkpoint of a `transfer`-byte run is allocated here.
+    pub fn new(
+        role: &'static str,
+        clock: ClockSource,
+        transfer: u64,
+        options: &CheckpointOptions,
+    ) -> Self {
+        let interval = transfer.div_ceil(options.count.max(1) as u64).max(1);
+        Recorder {
+            role,
+            clock,
+            options: options.clone(),
+            interval,
+            next: interval,
+            migrations: Migrations::default(),
+            // Start, one per mark, finish.
+            points: Vec::with_capacity(options.count + 2),
+        }
+    }
+
+    /// Checkpoint 0, at the start of the timed part.
+    pub fn start(&mut self) {
+        self.record(0);
+    }
+
+    /// Records a checkpoint if `bytes` passed the next mark.
+    #[inline]
+    pub fn progress(&mut self, bytes: u64) {
+        if bytes >= self.next {
+            self.record(bytes);
+            self.next = (bytes / self.interval + 1) * self.interval;
+        }
+    }
+
+    /// The last checkpoint, unless `progress` just took it.
+    pub fn finish(&mut self, bytes: u64) {
+        if self.points.last().is_none_or(|p| p.bytes != bytes) {
+            self.record(bytes);
+        }
+    }
+
+    #[cold]
+    fn record(&mut self, bytes: u64) {
+        let stamp = self.migrations.track(self.clock.stamp());
+        self.points.push(Checkpoint { bytes, stamp });
+    }
+
+    pub fn checkpoints(&self) -> &[Checkpoint] {
+        &self.points
+    }
+
+    /// CPU changes between checkpoints.
+    pub fn migrations(&self) -> u64 {
+        self.migrations.count
+    }
+
+    /// Writes the checkpoints where the options say.
+    pub fn report(&self) -> io::Result<()> {
+        match &self.options.file {
+            Some(path) => {
+                let mut out = BufWriter::new(File::create(path)?);
+                self.write(self.options.format, &mut out)?;
+                out.flush()
+            }
+            None => self.write(self.options.format, &mut io::stderr().lock()),
+        }
+    }
+
+    pub fn write(&self, format: Format, out: &mut dyn Write) -> io::Result<()> {
+        match format {
+            Format::Text => {
+                for (i, p) in self.points.iter().enumerate() {
+                    writeln!(
+                        out,
+                        "--- {} checkpoint {}/{} bytes: {} {}",
+                        self.role, i, self.options.count, p.bytes, p.stamp
+                    )?;
+                }
+            }
+            Format::Csv => {
+                writeln!(out, "role,index,bytes,clock,ticks,ns,cpu,migrated_from")?;
+                for (i, p) in self.points.iter().enumerate() {
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{},{},{}",
+                        self.role,
+                        i,
+                        p.bytes,
+                        self.clock,
+                        p.stamp.ticks,
+                        p.stamp.ns(),
+                        p.stamp.cpu,
+                        p.stamp
+                            .migrated_from
+                            .map_or(String::new(), |cpu| cpu.to_string())
+                    )?;
+                }
+            }
+            Format::Json => {
+                write!(
+                    out,
+                    "{{\"role\": \"{}\", \"clock\": \"{}\", \"hz\": {}, \"checkpoints\": [",
+                    self.role,
+                    self.clock,
+                    self.clock.hz()
+                )?;
+                for (i, p) in self.points.iter().enumerate() {
+                    write!(
+                        out,
+                        "{}\n  {{\"index\": {}, \"bytes\": {}, \"ticks\": {}, \"ns\": {}, \"cpu\": {}, \"migrated_from\": {}}}",
+                        if i == 0 { "" } else { "," },
+                        i,
+                        p.bytes,
+                        p.stamp.ticks,
+                        p.stamp.ns(),
+                        p.stamp.cpu,
+                        p.stamp
+                            .migrated_from
+                            .map_or("null".to_string(), |cpu| cpu.to_string())
+                    )?;
+                }
+                writeln!(out, "\n]}}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder(count: usize) -> Recorder {
+        let options = CheckpointOptions {
+            count,
+            ..CheckpointOptions::default()
+        };
+        Recorder::new("Writer", ClockSource::Monotonic, 1000, &options)
+    }
+
+    fn bytes(r: &Recorder) -> Vec<u64> {
+        r.checkpoints().iter().map(|p| p.bytes).collect()
+    }
+
+    #[test]
+    fn records_once_per_mark() {
+        let mut r = recorder(4);
+        r.start();
+        for done in (0..=1000).step_by(50).skip(1) {
+            r.progress(done);
+        }
+        r.finish(1000);
+        assert_eq!(bytes(&r), [0, 250, 500, 750, 1000]);
+        assert!(r
+            .checkpoints()
+            .windows(2)
+            .all(|w| w[0].stamp.ticks <= w[1].stamp.ticks));
+    }
+
+    #[test]
+    fn big_chunks_skip_marks() {
+        let mut r = recorder(10);
+        r.start();
+        r.progress(450);
+        r.progress(460);
+        r.progress(999);
+        r.finish(1000);
+        assert_eq!(bytes(&r), [0, 450, 999, 1000]);
+    }
+
+    #[test]
+    fn writes_every_format() {
+        let mut r = recorder(2);
+        r.start();
+        r.progress(500);
+        r.finish(1000);
+
+        let mut csv = Vec::new();
+        r.write(Format::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "role,index,bytes,clock,ticks,ns,cpu,migrated_from"
+        );
+        assert!(
+            lines[2].starts_with("Writer,1,500,monotonic,"),
+            "{}",
+            lines[2]
+        );
+
+        let mut json = Vec::new();
+        r.write(Format::Json, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"role\": \"Writer\", \"clock\": \"monotonic\""));
+        assert_eq!(json.matches("\"index\"").count(), 3);
+        assert!(json.trim_end().ends_with("]}"));
+
+        let mut text = Vec::new();
+        r.write(Format::Text, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("--- Writer checkpoint 0/2 bytes: 0 monotonic: "));
+    }
+}
@@ -0,0 +1,88 @@
+// cli.rs
+//
+// Tiny argument splitter shared by the benchmark binaries: positional
+// arguments keep their meaning and order, options are `--name` switches or
+// `--name=value` pairs and may appear anywhere. A malformed or missing
+// argument is a usage error: it is reported and the process exits with 2,
+// the status `RingError::InvalidConfig` uses too.
+
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Exit status for a bad command line.
+pub const USAGE: i32 = 2;
+
+pub struct Args {
+    positional: Vec<String>,
+    options: Vec<(String, Option<String>)>,
+}
+
+impl Args {
+    pub fn from_env() -> Self {
+        Self::parse(env::args())
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut positional = Vec::new();
+        let mut options = Vec::new();
+        for arg in args {
+            match arg.strip_prefix("--") {
+                Some(opt) => match opt.split_once('=') {
+                    Some((name, value)) => options.push((name.to_string(), Some(value.to_string()))),
+                    None => options.push((opt.to_string(), None)),
+                },
+                None => positional.push(arg),
+            }
+        }
+        Args { positional, options }
+    }
+
+    /// Positional arguments, including the program name at index 0.
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// `true` if `--name` (with or without a value) was given.
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(n, _)| n == name)
+    }
+
+    /// The value of the last `--name=value`.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .and_then(|(_, v)| v.as_deref())
+    }
+
+    /// Parses `--name=value`, exiting with a usage error if it is malformed.
+    pub fn parsed<T: FromStr>(&self, name: &str) -> Option<T>
+    where
+        T::Err: Display,
+    {
+        self.value(name).map(|v| {
+            v.parse().unwrap_or_else(|e| {
+                eprintln!("invalid value for --{}={}: {}", name, v, e);
+                std::process::exit(USAGE);
+            })
+        })
+    }
+
+    /// Parses positional argument `index`, exiting with a usage error if it
+    /// is missing or malformed.
+    pub fn arg<T: FromStr>(&self, index: usize, name: &str) -> T
+    where
+        T::Err: Display,
+    {
+        let Some(v) = self.positional.get(index) else {
+            eprintln!("missing argument <{}>", name);
+            std::process::exit(USAGE);
+        };
+        v.parse().unwrap_or_else(|e| {
+            eprintln!("invalid <{}> `{}`: {}", name, v, e);
+            std::process::exit(USAGE);
+        })
+    }
+}
@@ -0,0 +1,486 @@
+// clock.rs
+//
+// The clocks a bench can timestamp with. Every clock implements `Clock`,
+// counts in ticks of its own and converts them to nanoseconds:
+//
+//   tsc         MFENCE + LFENCE + RDTSC + LFENCE (`read_tsc`), TSC cycles;
+//               earlier loads and stores are done, later work not started
+//   tsc-lfence  LFENCE + RDTSC + LFENCE; earlier stores may still drain
+//   tsc-raw     bare RDTSC, which the CPU may run early or late
+//   rdtscp      RDTSCP + LFENCE; waits for earlier instructions by itself
+//               and also reports the CPU it ran on
+//   monotonic   clock_gettime(CLOCK_MONOTONIC_RAW), nanoseconds
+//
+// The TSC rate is measured once per process, against CLOCK_MONOTONIC_RAW
+// (not NTP-slewed, like the TSC): a TSC read is bracketed by two clock reads,
+// the tightest of a few tries kept, at both ends of a short sleep. The error
+// is the bracket width over the sleep, a few ppm. The first `hz()` or
+// `to_ns()` pays the sleep, so call it before the timed part.
+//
+// Every reading costs time that ends up in the interval it starts or ends,
+// tens of cycles for the fenced ones. `overhead` measures that cost for a
+// clock on the calling thread's CPU from back-to-back readings, so it can
+// be reported and taken off short intervals.
+//
+// A thread moved to another CPU between two readings makes their difference
+// wrong by the skew between the two counters (see `tsc.rs`), plus whatever
+// the move cost. Every `Stamp` records the CPU it was taken on, from
+// RDTSCP's TSC_AUX for `rdtscp` and `getcpu` otherwise, and `Migrations`
+// marks the stamps that start an interval spanning a move.
+
+use std::arch::x86_64::{__rdtscp, _mm_lfence, _rdtsc};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use crate::numa;
+use crate::read_tsc;
+
+/// How long the TSC rate is measured over.
+const CALIBRATION: Duration = Duration::from_millis(20);
+
+// Bracketing attempts per end of the calibration.
+const PAIRS: usize = 16;
+
+/// Back-to-back reading pairs `overhead` times.
+const OVERHEAD_SAMPLES: usize = 10_000;
+
+pub trait Clock {
+    /// The current reading, in ticks.
+    fn now(&self) -> u64;
+
+    /// Ticks per second.
+    fn hz(&self) -> f64;
+
+    /// `ticks` in nanoseconds.
+    fn to_ns(&self, ticks: u64) -> u64 {
+        (ticks as f64 * 1e9 / self.hz()) as u64
+    }
+}
+
+/// RDTSC between fences, as `read_tsc`.
+pub struct Tsc;
+
+impl Clock for Tsc {
+    #[inline]
+    fn now(&self) -> u64 {
+        read_tsc()
+    }
+
+    fn hz(&self) -> f64 {
+        tsc_hz()
+    }
+}
+
+/// RDTSC between two LFENCEs, without the MFENCE.
+pub struct LfenceTsc;
+
+impl Clock for LfenceTsc {
+    #[inline]
+    fn now(&self) -> u64 {
+        unsafe {
+            _mm_lfence();
+            let tsc = _rdtsc();
+            _mm_lfence();
+            tsc
+        }
+    }
+
+    fn hz(&self) -> f64 {
+        tsc_hz()
+    }
+}
+
+/// RDTSC with no fences.
+pub struct RawTsc;
+
+impl Clock for RawTsc {
+    #[inline]
+    fn now(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    fn hz(&self) -> f64 {
+        tsc_hz()
+    }
+}
+
+/// RDTSCP followed by LFENCE.
+pub struct Rdtscp;
+
+impl Clock for Rdtscp {
+    #[inline]
+    fn now(&self) -> u64 {
+        read_tscp().0
+    }
+
+    fn hz(&self) -> f64 {
+        tsc_hz()
+    }
+}
+
+/// CLOCK_MONOTONIC_RAW; ticks are nanoseconds.
+pub struct MonotonicRaw;
+
+impl Clock for MonotonicRaw {
+    #[inline]
+    fn now(&self) -> u64 {
+        monotonic_raw_ns()
+    }
+
+    fn hz(&self) -> f64 {
+        1e9
+    }
+
+    fn to_ns(&self, ticks: u64) -> u64 {
+        ticks
+    }
+}
+
+/// The TSC and the `IA32_TSC_AUX` value the kernel keeps there, which holds
+/// the CPU (low 12 bits) and node (above) the read ran on.
+#[inline]
+pub fn read_tscp() -> (u64, u32) {
+    let mut aux = 0;
+    unsafe {
+        let tsc = __rdtscp(&mut aux);
+        _mm_lfence();
+        (tsc, aux)
+    }
+}
+
+/// The CPU number in a TSC_AUX value from `read_tscp`.
+#[inline]
+pub fn aux_cpu(aux: u32) -> usize {
+    (aux & 0xfff) as usize
+}
+
+#[inline]
+pub fn monotonic_raw_ns() -> u64 {
+    unsafe {
+        let mut ts: libc::timespec = std::mem::zeroed();
+        libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts);
+        (ts.tv_sec as u64) * 1_000_000_000 + ts.tv_nsec as u64
+    }
+}
+
+/// TSC ticks per second, measured on first use.
+pub fn tsc_hz() -> f64 {
+    static HZ: OnceLock<f64> = OnceLock::new();
+    *HZ.get_or_init(|| {
+        let (ns0, tsc0) = paired_reading();
+        thread::sleep(CALIBRATION);
+        let (ns1, tsc1) = paired_reading();
+        (tsc1 - tsc0) as f64 * 1e9 / (ns1 - ns0) as f64
+    })
+}
+
+// A TSC reading and the CLOCK_MONOTONIC_RAW time it was taken at, from the
+// narrowest of `PAIRS` brackets (an interrupt widens one).
+fn paired_reading() -> (u64, u64) {
+    let mut best = (u64::MAX, 0, 0);
+    for _ in 0..PAIRS {
+        let before = monotonic_raw_ns();
+        let tsc = read_tsc();
+        let after = monotonic_raw_ns();
+        if after - before < best.0 {
+            best = (after - before, before + (after - before) / 2, tsc);
+        }
+    }
+    (best.1, best.2)
+}
+
+/// What one reading of a clock costs, in its ticks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overhead {
+    /// The cheapest back-to-back pair.
+    pub min: u64,
+    /// The typical pair; what to take off an interval.
+    pub median: u64,
+    /// `median` in nanoseconds.
+    pub ns: f64,
+}
+
+/// Measures `clock`'s reading cost on the current CPU.
+pub fn overhead<C: Clock + ?Sized>(clock: &C) -> Overhead {
+    let mut deltas = Vec::with_capacity(OVERHEAD_SAMPLES);
+    // The first round warms the code and the clock's data.
+    for _ in 0..2 {
+        deltas.clear();
+        for _ in 0..OVERHEAD_SAMPLES {
+            let a = clock.now();
+            let b = clock.now();
+            deltas.push(b.wrapping_sub(a));
+        }
+    }
+    deltas.sort_unstable();
+    let median = deltas[deltas.len() / 2];
+    Overhead {
+        min: deltas[0],
+        median,
+        ns: median as f64 * 1e9 / clock.hz(),
+    }
+}
+
+impl fmt::Display for Overhead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ticks ({:.1} ns) per reading, {} at best",
+            self.median, self.ns, self.min
+        )
+    }
+}
+
+/// Command line name of a `Clock`, for `--clock=...`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ClockSource {
+    #[default]
+    Tsc,
+    TscLfence,
+    TscRaw,
+    Rdtscp,
+    Monotonic,
+}
+
+impl ClockSource {
+    pub const ALL: [ClockSource; 5] = [
+        ClockSource::Tsc,
+        ClockSource::TscLfence,
+        ClockSource::TscRaw,
+        ClockSource::Rdtscp,
+        ClockSource::Monotonic,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockSource::Tsc => "tsc",
+            ClockSource::TscLfence => "tsc-lfence",
+            ClockSource::TscRaw => "tsc-raw",
+            ClockSource::Rdtscp => "rdtscp",
+            ClockSource::Monotonic => "monotonic",
+        }
+    }
+
+    /// The current reading and where it was taken, to print as
+    /// `name: ticks ns: N cpu: C`.
+    pub fn stamp(self) -> Stamp {
+        let (ticks, cpu) = match self {
+            ClockSource::Rdtscp => {
+                let (tsc, aux) = read_tscp();
+                (tsc, aux_cpu(aux))
+            }
+            _ => (self.now(), numa::current_cpu()),
+        };
+        Stamp {
+            clock: self,
+            ticks,
+            cpu,
+            migrated_from: None,
+        }
+    }
+}
+
+impl Clock for ClockSource {
+    #[inline]
+    fn now(&self) -> u64 {
+        match self {
+            ClockSource::Tsc => Tsc.now(),
+            ClockSource::TscLfence => LfenceTsc.now(),
+            ClockSource::TscRaw => RawTsc.now(),
+            ClockSource::Rdtscp => Rdtscp.now(),
+            ClockSource::Monotonic => MonotonicRaw.now(),
+        }
+    }
+
+    fn hz(&self) -> f64 {
+        match self {
+            ClockSource::Tsc => Tsc.hz(),
+            ClockSource::TscLfence => LfenceTsc.hz(),
+            ClockSource::TscRaw => RawTsc.hz(),
+            ClockSource::Rdtscp => Rdtscp.hz(),
+            ClockSource::Monotonic => MonotonicRaw.hz(),
+        }
+    }
+
+    fn to_ns(&self, ticks: u64) -> u64 {
+        match self {
+            ClockSource::Tsc => Tsc.to_ns(ticks),
+            ClockSource::TscLfence => LfenceTsc.to_ns(ticks),
+            ClockSource::TscRaw => RawTsc.to_ns(ticks),
+            ClockSource::Rdtscp => Rdtscp.to_ns(ticks),
+            ClockSource::Monotonic => MonotonicRaw.to_ns(ticks),
+        }
+    }
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for ClockSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ClockSource::ALL
+            .into_iter()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = ClockSource::ALL.iter().map(|c| c.name()).collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+/// One reading of a clock, shown with its conversion.
+#[derive(Debug, Clone, Copy)]
+pub struct Stamp {
+    pub clock: ClockSource,
+    pub ticks: u64,
+    pub cpu: usize,
+    /// The CPU of the previous stamp if it was another one; set by
+    /// `Migrations::track`.
+    pub migrated_from: Option<usize>,
+}
+
+impl Stamp {
+    pub fn ns(&self) -> u64 {
+        self.clock.to_ns(self.ticks)
+    }
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ns: {} cpu: {}",
+            self.clock,
+            self.ticks,
+            self.ns(),
+            self.cpu
+        )?;
+        match self.migrated_from {
+            Some(cpu) => write!(f, " (migrated from cpu {})", cpu),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counts the CPU changes between successive readings of one thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Migrations {
+    last: Option<usize>,
+    pub count: u64,
+}
+
+impl Migrations {
+    /// Notes that the thread ran on `cpu`; returns the CPU of the previous
+    /// note if it was another one.
+    pub fn on_cpu(&mut self, cpu: usize) -> Option<usize> {
+        let previous = self.last.replace(cpu)?;
+        if previous == cpu {
+            return None;
+        }
+        self.count += 1;
+        Some(previous)
+    }
+
+    /// `stamp` with `migrated_from` set if the interval since the previous
+    /// stamp spans a migration.
+    pub fn track(&mut self, mut stamp: Stamp) -> Stamp {
+        stamp.migrated_from = self.on_cpu(stamp.cpu);
+        stamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names() {
+        for clock in ClockSource::ALL {
+            assert_eq!(clock.name().parse::<ClockSource>(), Ok(clock));
+        }
+        assert!("hpet".parse::<ClockSource>().is_err());
+    }
+
+    #[test]
+    fn clocks_agree_on_elapsed_time() {
+        let clocks = ClockSource::ALL;
+        // Calibrates before the first reading.
+        assert!(tsc_hz() > 1e8 && tsc_hz() < 1e10, "{} Hz", tsc_hz());
+        let start: Vec<u64> = clocks.iter().map(|c| c.now()).collect();
+        thread::sleep(Duration::from_millis(50));
+        let end: Vec<u64> = clocks.iter().map(|c| c.now()).collect();
+        let elapsed: Vec<f64> = clocks
+            .iter()
+            .zip(start.iter().zip(&end))
+            .map(|(c, (&s, &e))| c.to_ns(e - s) as f64)
+            .collect();
+        for (clock, ns) in clocks.iter().zip(&elapsed) {
+            assert!(*ns >= 50e6, "{}: {} ns", clock, ns);
+            // Readings are a few µs apart; allow for a preemption or two.
+            let monotonic = elapsed[elapsed.len() - 1];
+            assert!(
+                (ns - monotonic).abs() < 5e6,
+                "{}: {} vs {} ns",
+                clock,
+                ns,
+                monotonic
+            );
+        }
+    }
+
+    #[test]
+    fn measures_reading_cost() {
+        for clock in ClockSource::ALL {
+            let cost = overhead(&clock);
+            assert!(cost.min <= cost.median, "{}: {}", clock, cost);
+            // Even a syscall-free reading takes well under 10 µs.
+            assert!(cost.ns < 10_000.0, "{}: {}", clock, cost);
+        }
+    }
+
+    #[test]
+    fn stamp_shows_ticks_and_ns() {
+        let stamp = Stamp {
+            clock: ClockSource::Monotonic,
+            ticks: 1234,
+            cpu: 3,
+            migrated_from: None,
+        };
+        assert_eq!(stamp.to_string(), "monotonic: 1234 ns: 1234 cpu: 3");
+        let moved = Stamp {
+            migrated_from: Some(1),
+            ..stamp
+        };
+        assert_eq!(
+            moved.to_string(),
+            "monotonic: 1234 ns: 1234 cpu: 3 (migrated from cpu 1)"
+        );
+        assert!(ClockSource::Tsc.stamp().to_string().starts_with("tsc: "));
+    }
+
+    #[test]
+    fn stamps_know_their_cpu() {
+        let cpu = numa::current_cpu();
+        crate::affinity::pin_current(&crate::affinity::CpuList::one(cpu)).unwrap();
+        for clock in ClockSource::ALL {
+            assert_eq!(clock.stamp().cpu, cpu, "{}", clock);
+        }
+    }
+
+    #[test]
+    fn counts_migrations() {
+        let mut migrations = Migrations::default();
+        let cpus = [2, 2, 5, 5, 2];
+        let moved: Vec<_> = cpus.iter().map(|&cpu| migrations.on_cpu(cpu)).collect();
+        assert_eq!(moved, [None, None, Some(2), None, Some(5)]);
+        assert_eq!(migrations.count, 2);
+    }
+}
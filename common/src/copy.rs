@@ -0,0 +1,255 @@
+// copy.rs
+//
+// The loops `RingProducer::write` and `RingConsumer::read` move bytes with:
+//
+//   std     `copy_from_slice`, i.e. whatever memcpy libc picked
+//   movsb   `rep movsb`, fast on CPUs with ERMSB/FSRM
+//   avx2    32-byte unaligned loads and stores, four per iteration
+//   avx512  the same with 64-byte registers
+//   nt      16-byte non-temporal (streaming) stores that bypass the cache,
+//           then `sfence`
+//
+// Streaming stores are weakly ordered: the Release store that publishes an
+// index does not order them, so `nt` ends every copy with `sfence` before
+// the caller gets to commit/release. Heads and tails that do not fill a
+// whole aligned store are copied with `std`.
+//
+// The vector kernels are detected at run time; `check` is the error a ring
+// returns when it is asked for one the CPU does not have.
+
+use std::arch::asm;
+use std::arch::x86_64::*;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::RingError;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CopyKernel {
+    #[default]
+    Std,
+    RepMovsb,
+    Avx2,
+    Avx512,
+    NonTemporal,
+}
+
+impl CopyKernel {
+    pub const ALL: [CopyKernel; 5] = [
+        CopyKernel::Std,
+        CopyKernel::RepMovsb,
+        CopyKernel::Avx2,
+        CopyKernel::Avx512,
+        CopyKernel::NonTemporal,
+    ];
+
+    /// Whether this CPU can run the kernel.
+    pub fn is_supported(self) -> bool {
+        match self {
+            // SSE2 (for `nt`) is part of x86_64.
+            CopyKernel::Std | CopyKernel::RepMovsb | CopyKernel::NonTemporal => true,
+            CopyKernel::Avx2 => is_x86_feature_detected!("avx2"),
+            CopyKernel::Avx512 => is_x86_feature_detected!("avx512f"),
+        }
+    }
+
+    /// `InvalidConfig` if this CPU cannot run the kernel.
+    pub fn check(self) -> Result<(), RingError> {
+        if self.is_supported() {
+            Ok(())
+        } else {
+            Err(RingError::InvalidConfig(format!(
+                "copy kernel `{}` is not supported on this CPU",
+                self.name()
+            )))
+        }
+    }
+
+    /// The kernels this CPU can run.
+    pub fn supported() -> Vec<CopyKernel> {
+        Self::ALL.into_iter().filter(|k| k.is_supported()).collect()
+    }
+
+    /// The command line name.
+    pub fn name(self) -> &'static str {
+        match self {
+            CopyKernel::Std => "std",
+            CopyKernel::RepMovsb => "movsb",
+            CopyKernel::Avx2 => "avx2",
+            CopyKernel::Avx512 => "avx512",
+            CopyKernel::NonTemporal => "nt",
+        }
+    }
+
+    /// Copies `src` into `dst`, which must be as long. Panics if the kernel
+    /// is not supported.
+    #[inline]
+    pub fn copy(self, dst: &mut [u8], src: &[u8]) {
+        assert_eq!(
+            dst.len(),
+            src.len(),
+            "copy between slices of different lengths"
+        );
+        match self {
+            CopyKernel::Std => dst.copy_from_slice(src),
+            CopyKernel::RepMovsb => unsafe { rep_movsb(dst, src) },
+            CopyKernel::Avx2 if is_x86_feature_detected!("avx2") => unsafe { avx2(dst, src) },
+            CopyKernel::Avx512 if is_x86_feature_detected!("avx512f") => unsafe {
+                avx512(dst, src)
+            },
+            CopyKernel::NonTemporal => unsafe { non_temporal(dst, src) },
+            _ => panic!("{}", self.check().unwrap_err()),
+        }
+    }
+}
+
+impl FromStr for CopyKernel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|k| k.name() == s)
+            .ok_or_else(|| format!("unknown copy kernel `{}` (std|movsb|avx2|avx512|nt)", s))
+    }
+}
+
+impl fmt::Display for CopyKernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// The kernels below are only called with slices of equal length.
+
+unsafe fn rep_movsb(dst: &mut [u8], src: &[u8]) {
+    // The ABI guarantees DF is clear, so this copies forwards.
+    asm!(
+        "rep movsb",
+        inout("rcx") src.len() => _,
+        inout("rdi") dst.as_mut_ptr() => _,
+        inout("rsi") src.as_ptr() => _,
+        options(nostack, preserves_flags)
+    );
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn avx2(dst: &mut [u8], src: &[u8]) {
+    let len = src.len();
+    let (s, d) = (src.as_ptr(), dst.as_mut_ptr());
+    let mut i = 0;
+    while i + 128 <= len {
+        let a = _mm256_loadu_si256(s.add(i) as *const __m256i);
+        let b = _mm256_loadu_si256(s.add(i + 32) as *const __m256i);
+        let c = _mm256_loadu_si256(s.add(i + 64) as *const __m256i);
+        let e = _mm256_loadu_si256(s.add(i + 96) as *const __m256i);
+        _mm256_storeu_si256(d.add(i) as *mut __m256i, a);
+        _mm256_storeu_si256(d.add(i + 32) as *mut __m256i, b);
+        _mm256_storeu_si256(d.add(i + 64) as *mut __m256i, c);
+        _mm256_storeu_si256(d.add(i + 96) as *mut __m256i, e);
+        i += 128;
+    }
+    while i + 32 <= len {
+        let a = _mm256_loadu_si256(s.add(i) as *const __m256i);
+        _mm256_storeu_si256(d.add(i) as *mut __m256i, a);
+        i += 32;
+    }
+    dst[i..].copy_from_slice(&src[i..]);
+}
+
+#[target_feature(enable = "avx512f")]
+unsafe fn avx512(dst: &mut [u8], src: &[u8]) {
+    let len = src.len();
+    let (s, d) = (src.as_ptr(), dst.as_mut_ptr());
+    let mut i = 0;
+    while i + 256 <= len {
+        let a = _mm512_loadu_si512(s.add(i) as *const __m512i);
+        let b = _mm512_loadu_si512(s.add(i + 64) as *const __m512i);
+        let c = _mm512_loadu_si512(s.add(i + 128) as *const __m512i);
+        let e = _mm512_loadu_si512(s.add(i + 192) as *const __m512i);
+        _mm512_storeu_si512(d.add(i) as *mut __m512i, a);
+        _mm512_storeu_si512(d.add(i + 64) as *mut __m512i, b);
+        _mm512_storeu_si512(d.add(i + 128) as *mut __m512i, c);
+        _mm512_storeu_si512(d.add(i + 192) as *mut __m512i, e);
+        i += 256;
+    }
+    while i + 64 <= len {
+        let a = _mm512_loadu_si512(s.add(i) as *const __m512i);
+        _mm512_storeu_si512(d.add(i) as *mut __m512i, a);
+        i += 64;
+    }
+    dst[i..].copy_from_slice(&src[i..]);
+}
+
+unsafe fn non_temporal(dst: &mut [u8], src: &[u8]) {
+    let len = src.len();
+    // Streaming stores must be aligned; copy up to the first 16-byte
+    // boundary of `dst` normally.
+    let head = dst.as_ptr().align_offset(16).min(len);
+    dst[..head].copy_from_slice(&src[..head]);
+    let (s, d) = (src.as_ptr(), dst.as_mut_ptr());
+    let mut i = head;
+    while i + 64 <= len {
+        let a = _mm_loadu_si128(s.add(i) as *const __m128i);
+        let b = _mm_loadu_si128(s.add(i + 16) as *const __m128i);
+        let c = _mm_loadu_si128(s.add(i + 32) as *const __m128i);
+        let e = _mm_loadu_si128(s.add(i + 48) as *const __m128i);
+        _mm_stream_si128(d.add(i) as *mut __m128i, a);
+        _mm_stream_si128(d.add(i + 16) as *mut __m128i, b);
+        _mm_stream_si128(d.add(i + 32) as *mut __m128i, c);
+        _mm_stream_si128(d.add(i + 48) as *mut __m128i, e);
+        i += 64;
+    }
+    while i + 16 <= len {
+        let a = _mm_loadu_si128(s.add(i) as *const __m128i);
+        _mm_stream_si128(d.add(i) as *mut __m128i, a);
+        i += 16;
+    }
+    dst[i..].copy_from_slice(&src[i..]);
+    _mm_sfence();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supported_kernels_copy_exactly() {
+        let src: Vec<u8> = (0..4096 + 64).map(|i| (i % 251) as u8).collect();
+        for kernel in CopyKernel::supported() {
+            // Odd lengths and offsets hit every head, body and tail path.
+            for len in [0, 1, 15, 16, 63, 64, 127, 255, 256, 1000, 4096] {
+                for offset in [0, 1, 7, 33] {
+                    let mut dst = vec![0u8; len + 64];
+                    kernel.copy(&mut dst[offset..offset + len], &src[3..3 + len]);
+                    assert_eq!(
+                        &dst[offset..offset + len],
+                        &src[3..3 + len],
+                        "{} {}",
+                        kernel,
+                        len
+                    );
+                    assert!(
+                        dst[..offset].iter().all(|&b| b == 0),
+                        "{} wrote before",
+                        kernel
+                    );
+                    assert!(
+                        dst[offset + len..].iter().all(|&b| b == 0),
+                        "{} wrote past",
+                        kernel
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for kernel in CopyKernel::ALL {
+            assert_eq!(kernel.name().parse::<CopyKernel>(), Ok(kernel));
+        }
+        assert!("sse".parse::<CopyKernel>().is_err());
+        assert!(CopyKernel::Std.check().is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+// error.rs
+//
+// What can go wrong setting up or using a shared segment, ring or plain
+// `ShmSegment`. Every variant names the object it is about; the ones caused
+// by a system call keep its `io::Error` (and so errno) as the source.
+//
+// Binaries end with `.or_exit("...")`, which prints the error once and exits
+// with a status that tells the causes apart:
+//
+//   2  invalid configuration (and bad command lines, see `cli.rs`)
+//   3  open/create     4  truncate     5  map
+//   6  layout mismatch 7  peer gone    8  timeout
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum RingError {
+    /// `shm_open`/`open` of the object failed.
+    Open { name: String, source: io::Error },
+    /// Another running process owns the name.
+    InUse { name: String },
+    /// `ftruncate` to the segment size failed.
+    Truncate { name: String, source: io::Error },
+    /// `mmap`, or placing or prefaulting the mapping, failed.
+    Map { name: String, source: io::Error },
+    /// The object is not the segment this side expects: foreign, another
+    /// version, other options, or too small.
+    LayoutMismatch { name: String, reason: String },
+    /// The process on the other end of the segment is no longer running.
+    PeerGone { name: String, pid: u32 },
+    /// The object did not appear (or was not initialised) in time.
+    Timeout { name: String, waited: Duration },
+    /// Options that cannot work, on this machine or at all.
+    InvalidConfig(String),
+}
+
+impl RingError {
+    /// The process exit status for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            RingError::InvalidConfig(_) => 2,
+            RingError::Open { .. } | RingError::InUse { .. } => 3,
+            RingError::Truncate { .. } => 4,
+            RingError::Map { .. } => 5,
+            RingError::LayoutMismatch { .. } => 6,
+            RingError::PeerGone { .. } => 7,
+            RingError::Timeout { .. } => 8,
+        }
+    }
+}
+
+impl fmt::Display for RingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RingError::Open { name, source } => write!(f, "cannot open {}: {}", name, source),
+            RingError::InUse { name } => write!(f, "{} is in use by a running producer", name),
+            RingError::Truncate { name, source } => {
+                write!(f, "cannot size {}: {}", name, source)
+            }
+            RingError::Map { name, source } => write!(f, "cannot map {}: {}", name, source),
+            RingError::LayoutMismatch { name, reason } => write!(f, "{}: {}", name, reason),
+            RingError::PeerGone { name, pid } => {
+                write!(f, "{}: peer process {} is gone", name, pid)
+            }
+            RingError::Timeout { name, waited } => {
+                write!(f, "gave up waiting for {} after {:?}", name, waited)
+            }
+            RingError::InvalidConfig(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error for RingError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RingError::Open { source, .. }
+            | RingError::Truncate { source, .. }
+            | RingError::Map { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Ends a binary on error: prints `context: error` and exits with
+/// `RingError::exit_code`.
+pub trait OrExit<T> {
+    fn or_exit(self, context: &str) -> T;
+}
+
+impl<T> OrExit<T> for Result<T, RingError> {
+    fn or_exit(self, context: &str) -> T {
+        self.unwrap_or_else(|e| {
+            eprintln!("{}: {}", context, e);
+            std::process::exit(e.exit_code());
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_are_distinct_per_cause() {
+        let errors = [
+            RingError::InvalidConfig(String::new()),
+            RingError::Open {
+                name: "/x".into(),
+                source: io::Error::from(io::ErrorKind::NotFound),
+            },
+            RingError::Truncate {
+                name: "/x".into(),
+                source: io::Error::from(io::ErrorKind::Other),
+            },
+            RingError::Map {
+                name: "/x".into(),
+                source: io::Error::from(io::ErrorKind::Other),
+            },
+            RingError::LayoutMismatch {
+                name: "/x".into(),
+                reason: String::new(),
+            },
+            RingError::PeerGone {
+                name: "/x".into(),
+                pid: 1,
+            },
+            RingError::Timeout {
+                name: "/x".into(),
+                waited: Duration::ZERO,
+            },
+        ];
+        let mut codes: Vec<i32> = errors.iter().map(RingError::exit_code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0) && !codes.contains(&1));
+    }
+
+    #[test]
+    fn keeps_errno() {
+        let err = RingError::Open {
+            name: "/ring".into(),
+            source: io::Error::from_raw_os_error(libc::EACCES),
+        };
+        let source = err.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(source.raw_os_error(), Some(libc::EACCES));
+        assert!(err.to_string().starts_with("cannot open /ring: "));
+    }
+}
@@ -0,0 +1,66 @@
+// futex.rs
+//
+// Thin wrappers around the futex(2) syscall, shared by the latency benches
+// and the blocking ring. The operations are the non-PRIVATE ones, so the
+// word may live in memory shared between processes.
+
+use std::sync::atomic::AtomicU32;
+use std::time::Duration;
+
+// Futex operations
+const FUTEX_WAIT: i32 = 0;
+const FUTEX_WAKE: i32 = 1;
+
+/// Sleeps while `*addr == expected`. Returns immediately (-1, `EAGAIN`) if
+/// the word already differs; may also return spuriously, so callers re-check
+/// their condition in a loop.
+///
+/// # Safety
+/// `addr` must point to a live, 4-byte aligned word.
+pub unsafe fn futex_wait(addr: *const AtomicU32, expected: u32) -> i32 {
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        FUTEX_WAIT,
+        expected,
+        std::ptr::null::<libc::timespec>(),  // no timeout
+        std::ptr::null::<u32>(),
+        0
+    ) as i32
+}
+
+/// `futex_wait` that gives up after `timeout` (-1, `ETIMEDOUT`).
+///
+/// # Safety
+/// `addr` must point to a live, 4-byte aligned word.
+pub unsafe fn futex_wait_for(addr: *const AtomicU32, expected: u32, timeout: Duration) -> i32 {
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    };
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        FUTEX_WAIT,
+        expected,
+        &timeout as *const libc::timespec,  // relative
+        std::ptr::null::<u32>(),
+        0
+    ) as i32
+}
+
+/// Wakes up to `num_to_wake` sleepers on `addr`; returns how many woke.
+///
+/// # Safety
+/// `addr` must point to a live, 4-byte aligned word.
+pub unsafe fn futex_wake(addr: *const AtomicU32, num_to_wake: i32) -> i32 {
+    libc::syscall(
+        libc::SYS_futex,
+        addr,
+        FUTEX_WAKE,
+        num_to_wake,
+        std::ptr::null::<libc::timespec>(),
+        std::ptr::null::<u32>(),
+        0
+    ) as i32
+}
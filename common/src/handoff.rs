@@ -0,0 +1,458 @@
+// handoff.rs
+//
+// Giving a ring's memfd to its consumer over a Unix domain socket, for
+// `unix:/path/to/socket` segments, instead of through a name in /dev/shm:
+//
+//   producer                                consumer
+//   memfd_create, ftruncate
+//   F_ADD_SEALS(SHRINK | GROW)
+//   fill SegmentInfo, ready = 1
+//   bind + listen on the path (mode 0600)   connect until it exists
+//   accept, check the peer's uid   <------  'F'
+//   sendmsg(SCM_RIGHTS, memfd)     ------>  recvmsg, check the seals
+//   unlink the path, stop listening
+//
+// The memory has no name, so only a process the fd was sent to can map it,
+// and the producer sends it once: to the first process of its own user that
+// asks. The seals are checked on the consumer side; with them the producer
+// can neither shrink the object under the consumer's mapping (SIGBUS) nor
+// make it something else than the size that was validated.
+//
+// A 'P' request only asks whether a producer is listening and is answered
+// with 'L'. The launcher and `create`'s in-use check use it; it does not use
+// up the handoff.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem::size_of;
+use std::ptr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const FETCH: u8 = b'F';
+const PROBE: u8 = b'P';
+const LISTENING: u8 = b'L';
+
+// How long the producer waits for a connected peer to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Seals a size-fixed memfd carries.
+pub const SIZE_SEALS: libc::c_int = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+
+/// The producer's end: hands `fd` to the first consumer that asks. The
+/// socket is removed once it was used, or when the server is dropped.
+pub struct Server {
+    listener: libc::c_int,
+    path: CString,
+    // Returns true once the fd was sent.
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl Server {
+    /// Listens on `path`, replacing a stale socket there; anything else
+    /// there is an `InvalidInput` error (see `check_path`). `fd` must stay
+    /// open until the server is dropped.
+    pub fn start(path: &CStr, fd: libc::c_int) -> io::Result<Server> {
+        let addr = sockaddr(path)?;
+        // Before the server exists, whose drop would remove the path.
+        let stale = check_path(path)?;
+        let listener = socket()?;
+        let mut server = Server {
+            listener,
+            path: path.to_owned(),
+            thread: None,
+        };
+        unsafe {
+            if stale {
+                libc::unlink(path.as_ptr());
+            }
+            // bind creates the socket file with the socket's own mode, so it
+            // is 0600 from the start rather than chmod-ed after bind, which
+            // would leave a moment in which anyone may connect.
+            if libc::fchmod(listener, 0o600) != 0
+                || libc::bind(
+                    listener,
+                    &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                    size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                ) != 0
+                || libc::listen(listener, 4) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let path = server.path.clone();
+        server.thread = Some(thread::spawn(move || serve(listener, fd, &path)));
+        Ok(server)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        // Wakes a blocked `accept` with EINVAL.
+        unsafe { libc::shutdown(self.listener, libc::SHUT_RDWR) };
+        let served = self
+            .thread
+            .take()
+            .is_some_and(|t| t.join().unwrap_or(false));
+        unsafe {
+            libc::close(self.listener);
+            // Once served, the path may be another producer's by now.
+            if !served {
+                libc::unlink(self.path.as_ptr());
+            }
+        }
+    }
+}
+
+fn serve(listener: libc::c_int, fd: libc::c_int, path: &CString) -> bool {
+    loop {
+        let conn = unsafe {
+            libc::accept4(
+                listener,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            )
+        };
+        if conn < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return false;
+        }
+        let served = answer(conn, fd).unwrap_or(false);
+        unsafe { libc::close(conn) };
+        if served {
+            // Nobody else gets to connect.
+            unsafe { libc::unlink(path.as_ptr()) };
+            return true;
+        }
+    }
+}
+
+// Answers one request; true once `fd` has been sent.
+fn answer(conn: libc::c_int, fd: libc::c_int) -> io::Result<bool> {
+    if peer_cred(conn)?.uid != unsafe { libc::getuid() } {
+        return Ok(false);
+    }
+    let timeout = libc::timeval {
+        tv_sec: REQUEST_TIMEOUT.as_secs() as libc::time_t,
+        tv_usec: 0,
+    };
+    unsafe {
+        libc::setsockopt(
+            conn,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    match read_byte(conn)? {
+        PROBE => write_byte(conn, LISTENING).map(|()| false),
+        FETCH => send_fd(conn, fd).map(|()| true),
+        _ => Ok(false),
+    }
+}
+
+/// Asks the producer listening on `path` for its fd. `NotFound` if nobody
+/// listens (yet, or any more).
+pub fn fetch(path: &CStr) -> io::Result<libc::c_int> {
+    let conn = connect(path)?;
+    let fd = write_byte(conn, FETCH).and_then(|()| recv_fd(conn));
+    unsafe { libc::close(conn) };
+    let fd = fd?;
+    let seals = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+    if seals < 0 || seals & SIZE_SEALS != SIZE_SEALS {
+        unsafe { libc::close(fd) };
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the producer's memfd is not sealed against resizing",
+        ));
+    }
+    Ok(fd)
+}
+
+/// The pid of the producer listening on `path`, if one is.
+pub fn probe(path: &CStr) -> Option<u32> {
+    let conn = connect(path).ok()?;
+    let listening = write_byte(conn, PROBE)
+        .and_then(|()| read_byte(conn))
+        .is_ok_and(|b| b == LISTENING);
+    // The credentials the producer listened with.
+    let pid = peer_cred(conn).map(|cred| cred.pid as u32);
+    unsafe { libc::close(conn) };
+    pid.ok().filter(|_| listening)
+}
+
+// Who is at the other end of a connected socket.
+fn peer_cred(conn: libc::c_int) -> io::Result<libc::ucred> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            conn,
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred)
+}
+
+/// Whether a socket is at `path`, which a new producer may replace. Fails
+/// with `InvalidInput` if something else is there: a mistyped path must not
+/// remove a file.
+pub fn check_path(path: &CStr) -> io::Result<bool> {
+    let mut st: libc::stat = unsafe { std::mem::zeroed() };
+    if unsafe { libc::lstat(path.as_ptr(), &mut st) } != 0 {
+        let err = io::Error::last_os_error();
+        return match err.kind() {
+            io::ErrorKind::NotFound => Ok(false),
+            _ => Err(err),
+        };
+    }
+    if st.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} exists and is not a socket", path.to_string_lossy()),
+        ));
+    }
+    Ok(true)
+}
+
+fn sockaddr(path: &CStr) -> io::Result<libc::sockaddr_un> {
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    let bytes = path.to_bytes();
+    // Room for the terminating nul.
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "socket path is longer than {} bytes",
+                addr.sun_path.len() - 1
+            ),
+        ));
+    }
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    for (dst, &src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = src as libc::c_char;
+    }
+    Ok(addr)
+}
+
+fn socket() -> io::Result<libc::c_int> {
+    let sock = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if sock < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(sock)
+}
+
+fn connect(path: &CStr) -> io::Result<libc::c_int> {
+    let addr = sockaddr(path)?;
+    let sock = socket()?;
+    let rc = unsafe {
+        libc::connect(
+            sock,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(sock) };
+        // A socket left behind by a producer that is gone.
+        return Err(match err.raw_os_error() {
+            Some(libc::ECONNREFUSED) => io::Error::from(io::ErrorKind::NotFound),
+            _ => err,
+        });
+    }
+    Ok(sock)
+}
+
+fn read_byte(sock: libc::c_int) -> io::Result<u8> {
+    let mut byte = 0u8;
+    match unsafe { libc::read(sock, &mut byte as *mut u8 as *mut libc::c_void, 1) } {
+        1 => Ok(byte),
+        0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+fn write_byte(sock: libc::c_int, byte: u8) -> io::Result<()> {
+    let rc = unsafe {
+        libc::send(
+            sock,
+            &byte as *const u8 as *const libc::c_void,
+            1,
+            libc::MSG_NOSIGNAL,
+        )
+    };
+    if rc != 1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+const FD_SPACE: usize = unsafe { libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) } as usize;
+
+// Control buffer for one fd, aligned for `cmsghdr`.
+#[repr(C, align(8))]
+struct FdControl([u8; FD_SPACE]);
+
+fn send_fd(sock: libc::c_int, fd: libc::c_int) -> io::Result<()> {
+    let mut byte = LISTENING;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control = FdControl([0; FD_SPACE]);
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = FD_SPACE;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<libc::c_int>() as u32) as usize;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, fd);
+        if libc::sendmsg(sock, &msg, libc::MSG_NOSIGNAL) != 1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn recv_fd(sock: libc::c_int) -> io::Result<libc::c_int> {
+    let mut byte = 0u8;
+    let mut iov = libc::iovec {
+        iov_base: &mut byte as *mut u8 as *mut libc::c_void,
+        iov_len: 1,
+    };
+    let mut control = FdControl([0; FD_SPACE]);
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = FD_SPACE;
+    unsafe {
+        match libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC) {
+            n if n < 0 => return Err(io::Error::last_os_error()),
+            // Refused (another user) or already handed out.
+            0 => return Err(io::Error::from(io::ErrorKind::NotFound)),
+            _ => {}
+        }
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the producer sent no file descriptor",
+            ));
+        }
+        Ok(ptr::read_unaligned(
+            libc::CMSG_DATA(cmsg) as *const libc::c_int
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memfd(seals: libc::c_int) -> libc::c_int {
+        let name = CString::new("handoff-test").unwrap();
+        unsafe {
+            let fd = libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING);
+            assert!(fd >= 0);
+            assert_eq!(libc::ftruncate(fd, 4096), 0);
+            if seals != 0 {
+                assert_eq!(libc::fcntl(fd, libc::F_ADD_SEALS, seals), 0);
+            }
+            fd
+        }
+    }
+
+    fn socket_path(tag: &str) -> CString {
+        let path = std::env::temp_dir().join(format!("handoff-{}-{}", std::process::id(), tag));
+        CString::new(path.into_os_string().into_encoded_bytes()).unwrap()
+    }
+
+    fn same_file(a: libc::c_int, b: libc::c_int) -> bool {
+        let mut sa: libc::stat = unsafe { std::mem::zeroed() };
+        let mut sb: libc::stat = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::fstat(a, &mut sa) == 0 && libc::fstat(b, &mut sb) == 0 };
+        ok && (sa.st_dev, sa.st_ino) == (sb.st_dev, sb.st_ino)
+    }
+
+    #[test]
+    fn hands_the_fd_out_once() {
+        let path = socket_path("once");
+        let fd = memfd(SIZE_SEALS);
+        assert_eq!(fetch(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+        let server = Server::start(&path, fd).unwrap();
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        assert_eq!(unsafe { libc::stat(path.as_ptr(), &mut st) }, 0);
+        assert_eq!(st.st_mode & 0o777, 0o600);
+        assert_eq!(probe(&path), Some(std::process::id()));
+        assert!(probe(&path).is_some());
+
+        let received = fetch(&path).unwrap();
+        assert!(same_file(fd, received));
+        // The path is gone once the fd was handed out.
+        assert!(probe(&path).is_none());
+        assert_eq!(fetch(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        drop(server);
+        unsafe {
+            libc::close(received);
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn rejects_unsealed_memfd() {
+        let path = socket_path("unsealed");
+        let fd = memfd(0);
+        let server = Server::start(&path, fd).unwrap();
+        assert_eq!(fetch(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        drop(server);
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let path = socket_path("file");
+        std::fs::write(path.to_str().unwrap(), "notes").unwrap();
+        let fd = memfd(SIZE_SEALS);
+        let err = Server::start(&path, fd).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(std::fs::read_to_string(path.to_str().unwrap()).unwrap(), "notes");
+        std::fs::remove_file(path.to_str().unwrap()).unwrap();
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn stale_socket_is_not_listening() {
+        let path = socket_path("stale");
+        let fd = memfd(SIZE_SEALS);
+        let server = Server::start(&path, fd).unwrap();
+        // A socket file nothing accepts on, as a killed producer leaves it.
+        unsafe { libc::shutdown(server.listener, libc::SHUT_RDWR) };
+        assert!(probe(&path).is_none());
+        assert_eq!(fetch(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        drop(server);
+        assert_eq!(unsafe { libc::access(path.as_ptr(), libc::F_OK) }, -1);
+        unsafe { libc::close(fd) };
+    }
+}
@@ -1,11 +1,98 @@
 use std::arch::x86_64::{_mm_lfence, _mm_mfence, _rdtsc};
 use std::sync::atomic::{AtomicU64, AtomicU32};
 
+pub mod affinity;
+pub mod backend;
+pub mod checkpoint;
+pub mod cli;
+pub mod clock;
+pub mod copy;
+pub mod error;
+pub mod futex;
+pub mod handoff;
+pub mod numa;
+pub mod pages;
+pub mod pingpong;
+pub mod prefault;
+pub mod ring;
+pub mod sampler;
+pub mod shm;
+pub mod tsc;
+pub mod wait;
+
+pub use error::RingError;
+pub use pages::Pages;
+pub use ring::{HeaderLayout, RingConsumer, RingOptions, RingProducer};
+
+/// "ARCARING" in little-endian ASCII; first word of every ring segment.
+pub const RING_MAGIC: u64 = u64::from_le_bytes(*b"ARCARING");
+
+/// Bumped whenever `SegmentInfo` or the header layouts change meaning.
+pub const RING_VERSION: u32 = 3;
+
+/// Self-description at offset 0 of a ring segment, in its own 128-byte slot
+/// ahead of the index header. Written once by the creator, which sets `ready`
+/// last; everything else is read-only afterwards. See the handshake notes at
+/// the top of `ring.rs`.
+#[repr(C)]
+pub struct SegmentInfo {
+    pub magic: u64,
+    pub version: u32,
+    /// `HeaderLayout` of the index header that follows.
+    pub layout: u32,
+    /// Bytes from the start of the segment to the data region.
+    pub header_size: u64,
+    /// Size of the data region in bytes.
+    pub capacity: u64,
+    /// `SEGMENT_MIRRORED` and friends.
+    pub flags: u32,
+    /// 0 while the creator is still initialising, 1 once every other field
+    /// (and the index header) is valid.
+    pub ready: AtomicU32,
+    /// Process that created the segment, written as soon as it is mapped;
+    /// a segment whose creator is gone is left over from a crashed run.
+    pub creator_pid: u32,
+}
+
+/// The data region starts on a page boundary so it can be mapped twice.
+pub const SEGMENT_MIRRORED: u32 = 1 << 0;
+
+/// Both sides sleep on the header's futex words instead of spinning.
+pub const SEGMENT_BLOCKING: u32 = 1 << 1;
+
 #[repr(C)]
 pub struct ShmHeader {
     pub start_index: AtomicU64,
     pub end_index: AtomicU64,
     pub transfer_started: AtomicU32,
+    /// Futex word, 1 while the consumer sleeps on an empty ring.
+    pub consumer_waiting: AtomicU32,
+    /// Futex word, 1 while the producer sleeps on a full ring.
+    pub producer_waiting: AtomicU32,
+}
+
+/// Aligns and pads `T` to 128 bytes: its own cache line plus the neighbour
+/// the adjacent-line prefetcher pulls in with it.
+#[repr(C, align(128))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> std::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+/// `ShmHeader` with every field in its own 128-byte slot, so the producer's
+/// and the consumer's index never share (or prefetch) a cache line.
+#[repr(C)]
+pub struct PaddedShmHeader {
+    pub start_index: CachePadded<AtomicU64>,
+    pub end_index: CachePadded<AtomicU64>,
+    pub transfer_started: CachePadded<AtomicU32>,
+    pub consumer_waiting: CachePadded<AtomicU32>,
+    pub producer_waiting: CachePadded<AtomicU32>,
 }
 
 #[inline]
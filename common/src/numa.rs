@@ -0,0 +1,154 @@
+// numa.rs
+//
+// NUMA placement with the raw memory policy syscalls (no libnuma):
+// `mbind` for one mapping, `set_mempolicy` for everything the process
+// allocates afterwards, `get_mempolicy` to see where a page really landed.
+//
+// A policy set with `mbind` on a MAP_SHARED mapping of a shm object belongs
+// to the object, not to the mapping, so every process that maps the segment
+// later allocates its pages on the same node. It only places pages that are
+// faulted in after the call; bind before touching the memory.
+
+use std::fs;
+use std::io;
+
+// 1024 nodes, as many as CONFIG_NODES_SHIFT allows.
+const MASK_WORDS: usize = 16;
+
+// get_mempolicy(2) flags, not exported by libc.
+const MPOL_F_NODE: libc::c_ulong = 1 << 0;
+const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+
+fn node_mask(node: usize) -> io::Result<[libc::c_ulong; MASK_WORDS]> {
+    let bits = 8 * std::mem::size_of::<libc::c_ulong>();
+    if node >= MASK_WORDS * bits {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("NUMA node {} is out of range", node),
+        ));
+    }
+    let mut mask = [0; MASK_WORDS];
+    mask[node / bits] |= 1 << (node % bits);
+    Ok(mask)
+}
+
+// The kernel ignores the last bit of `maxnode`, hence the + 1.
+fn max_node() -> libc::c_ulong {
+    (MASK_WORDS * 8 * std::mem::size_of::<libc::c_ulong>() + 1) as libc::c_ulong
+}
+
+/// Allocates the pages of `[addr, addr + len)` on `node` only. `addr` must
+/// be page aligned.
+///
+/// # Safety
+/// `addr..addr + len` must be a mapping owned by the caller.
+pub unsafe fn bind(addr: *mut libc::c_void, len: usize, node: usize) -> io::Result<()> {
+    let mask = node_mask(node)?;
+    let rc = libc::syscall(
+        libc::SYS_mbind,
+        addr,
+        len,
+        libc::MPOL_BIND,
+        mask.as_ptr(),
+        max_node(),
+        0,
+    );
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Allocates every page the calling process faults in from now on on `node`.
+pub fn bind_process(node: usize) -> io::Result<()> {
+    let mask = node_mask(node)?;
+    let rc = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            libc::MPOL_BIND,
+            mask.as_ptr(),
+            max_node(),
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The node holding the page at `addr`, faulting it in if it is not yet.
+///
+/// # Safety
+/// `addr` must point into a live mapping.
+pub unsafe fn node_of_addr(addr: *const libc::c_void) -> io::Result<usize> {
+    let mut node: libc::c_int = 0;
+    let rc = libc::syscall(
+        libc::SYS_get_mempolicy,
+        &mut node,
+        std::ptr::null_mut::<libc::c_ulong>(),
+        0,
+        addr,
+        MPOL_F_NODE | MPOL_F_ADDR,
+    );
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(node as usize)
+}
+
+/// A node lookup as the benchmarks print it, `?` if there is no answer.
+pub fn show(node: io::Result<usize>) -> String {
+    node.map_or_else(|_| "?".to_string(), |node| node.to_string())
+}
+
+/// The node `cpu` belongs to, from sysfs; `None` without NUMA support.
+pub fn node_of_cpu(cpu: usize) -> Option<usize> {
+    fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", cpu))
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()
+        })
+}
+
+/// The CPU the calling thread is running on right now.
+pub fn current_cpu() -> usize {
+    unsafe { libc::sched_getcpu() as usize }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ring::page_size;
+
+    #[test]
+    fn binds_mapping_to_node() {
+        let len = 4 * page_size();
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        unsafe {
+            bind(addr, len, 0).unwrap();
+            assert_eq!(node_of_addr(addr).unwrap(), 0);
+            libc::munmap(addr, len);
+        }
+    }
+
+    #[test]
+    fn rejects_huge_node() {
+        assert!(node_mask(1 << 20).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// pages.rs
+//
+// What a ring segment's memory is made of:
+//
+//   4k   regular pages, a POSIX shm object
+//   thp  the same object, with MADV_HUGEPAGE on every mapping of it; the
+//        kernel only honours it if shmem THP is not disabled
+//   2m   explicit huge pages: a file on a hugetlbfs mount with that page
+//   1g   size, from the pre-allocated pool in /sys/kernel/mm/hugepages
+//
+// hugetlbfs files are reserved in full when they are mapped, so an empty
+// pool is an `mmap` failure with a bare ENOMEM; `check_pool` turns that into
+// an error that says which pool and how many pages.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const KIB: usize = 1024;
+const MIB: usize = 1024 * KIB;
+const GIB: usize = 1024 * MIB;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Pages {
+    #[default]
+    Regular,
+    Transparent,
+    Huge2M,
+    Huge1G,
+}
+
+impl Pages {
+    /// Page size of an explicit huge page backing, `None` for the shm ones.
+    pub fn huge_size(self) -> Option<usize> {
+        match self {
+            Pages::Regular | Pages::Transparent => None,
+            Pages::Huge2M => Some(2 * MIB),
+            Pages::Huge1G => Some(GIB),
+        }
+    }
+}
+
+impl FromStr for Pages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "4k" => Ok(Pages::Regular),
+            "thp" => Ok(Pages::Transparent),
+            "2m" => Ok(Pages::Huge2M),
+            "1g" => Ok(Pages::Huge1G),
+            _ => Err(format!("unknown page backing `{}` (4k|thp|2m|1g)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Pages {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Pages::Regular => "4 KiB pages",
+            Pages::Transparent => "transparent huge pages",
+            Pages::Huge2M => "2 MiB huge pages",
+            Pages::Huge1G => "1 GiB huge pages",
+        })
+    }
+}
+
+// "2048k", "2M", "1G" as used in mount options and meminfo.
+fn parse_size(s: &str) -> Option<usize> {
+    let s = s.trim();
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let unit = match unit.trim().to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" | "kb" => KIB,
+        "m" | "mb" => MIB,
+        "g" | "gb" => GIB,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok().map(|n| n * unit)
+}
+
+// Huge page size a hugetlbfs mount without `pagesize=` uses.
+fn default_huge_size() -> Option<usize> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Hugepagesize:"))
+        .and_then(parse_size)
+}
+
+/// A hugetlbfs mount point whose files use `size` pages.
+pub fn hugetlbfs_mount(size: usize) -> io::Result<PathBuf> {
+    let mounts = fs::read_to_string("/proc/mounts")?;
+    for line in mounts.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 4 || fields[2] != "hugetlbfs" {
+            continue;
+        }
+        let page = fields[3]
+            .split(',')
+            .find_map(|opt| opt.strip_prefix("pagesize="))
+            .map_or_else(default_huge_size, parse_size);
+        if page == Some(size) {
+            return Ok(PathBuf::from(fields[1]));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "no hugetlbfs mount with {} KiB pages (mount -t hugetlbfs -o pagesize={}K none <dir>)",
+            size / KIB,
+            size / KIB
+        ),
+    ))
+}
+
+/// Fails unless the pool of `size` pages can back `len` more bytes.
+pub fn check_pool(size: usize, len: usize) -> io::Result<()> {
+    let dir = format!("/sys/kernel/mm/hugepages/hugepages-{}kB", size / KIB);
+    let read = |file: &str| -> io::Result<usize> {
+        let path = format!("{}/{}", dir, file);
+        let text = fs::read_to_string(&path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("{}: {} (no {} KiB huge pages?)", path, e, size / KIB),
+            )
+        })?;
+        text.trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+    };
+    let available = read("free_hugepages")?.saturating_sub(read("resv_hugepages")?);
+    let needed = len.div_ceil(size);
+    if available < needed {
+        return Err(io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!(
+                "need {} free {} KiB huge pages, the pool has {} (raise {}/nr_hugepages)",
+                needed,
+                size / KIB,
+                available,
+                dir
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if the kernel would ignore MADV_HUGEPAGE on shared memory.
+pub fn check_shmem_thp() -> io::Result<()> {
+    let path = "/sys/kernel/mm/transparent_hugepage/shmem_enabled";
+    let text = fs::read_to_string(path)?;
+    // The active setting is the bracketed one: "always [never] advise ...".
+    let mode = text
+        .split_whitespace()
+        .find_map(|w| w.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or("");
+    match mode {
+        "never" | "deny" => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "shmem THP is disabled ({} is `{}`, needs `advise`)",
+                path, mode
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("2048 kB"), Some(2 * MIB));
+        assert_eq!(parse_size("2M"), Some(2 * MIB));
+        assert_eq!(parse_size("1G"), Some(GIB));
+        assert_eq!(parse_size("4x"), None);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for pages in [
+            Pages::Regular,
+            Pages::Transparent,
+            Pages::Huge2M,
+            Pages::Huge1G,
+        ] {
+            let name = match pages {
+                Pages::Regular => "4k",
+                Pages::Transparent => "thp",
+                Pages::Huge2M => "2m",
+                Pages::Huge1G => "1g",
+            };
+            assert_eq!(name.parse::<Pages>(), Ok(pages));
+        }
+        assert!("64k".parse::<Pages>().is_err());
+    }
+}
@@ -0,0 +1,250 @@
+// pingpong.rs
+//
+// Round-trip latency engine shared by the latency benches. Two sides take
+// turns on one counter: `ping` moves it from even to odd, `pong` answers by
+// moving it back to even. One round trip is ping's increment plus pong's
+// answer as seen by ping. How a side waits for its turn is a `Waiter`; the
+// rest of the harness is here, so every strategy is timed the same way.
+//
+// `stop` sets `done` and then makes the counter odd once more, so a pong
+// side waiting for its turn wakes up, sees `done` and returns.
+//
+// The ping side notes its CPU (RDTSCP's TSC_AUX) at the start and end of
+// the run and around every active sample, outside the timed part. Samples
+// during which it moved are left out of `active_ns`, since they time the
+// move too.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::clock::{self, aux_cpu, monotonic_raw_ns, read_tscp, Migrations, MonotonicRaw};
+use crate::wait::{Futex, Pause, Spin, SpinThenFutex, WaitStrategy, WaitWord, Waiter, Yield};
+
+#[repr(C)]
+#[derive(Default)]
+pub struct PingPong {
+    pub counter: WaitWord,
+    pub done: AtomicU32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub round_trips: u64,
+    /// Wall time of the whole run.
+    pub total_ns: u64,
+    /// Time spent publishing our turn (increment + wake) only, leaving out
+    /// the time spent waiting; `None` unless asked for.
+    pub active_ns: Option<u64>,
+    /// What one clock reading costs on the ping side; each round trip's
+    /// active time includes one. Measured along with `active_ns`.
+    pub clock_overhead_ns: Option<f64>,
+    /// CPU changes seen on the ping side, only between the start and the
+    /// end of the run unless `active_ns` is measured.
+    pub migrations: u64,
+    /// Active samples left out of `active_ns` because they span a change.
+    pub discarded: u64,
+}
+
+impl Stats {
+    pub fn avg_ns(&self) -> u64 {
+        self.total_ns / self.round_trips.max(1)
+    }
+
+    pub fn avg_active_ns(&self) -> Option<u64> {
+        let samples = self.round_trips - self.discarded;
+        self.active_ns.map(|ns| ns / samples.max(1))
+    }
+
+    /// `avg_active_ns` without the clock reading it includes.
+    pub fn avg_active_net_ns(&self) -> Option<u64> {
+        let overhead = self.clock_overhead_ns?.round() as u64;
+        self.avg_active_ns().map(|ns| ns.saturating_sub(overhead))
+    }
+}
+
+/// Runs `round_trips` timed round trips from the even side. The counter must
+/// be even (ping's turn) on entry. `time_active` adds two clock reads per
+/// round trip to measure `Stats::active_ns`, and measures what those cost
+/// beforehand.
+pub fn ping_with<W: Waiter>(
+    shared: &PingPong,
+    waiter: &mut W,
+    round_trips: u32,
+    time_active: bool,
+) -> Stats {
+    let mut value = shared.counter.value.load(Ordering::Acquire);
+    assert!(value.is_multiple_of(2), "ping started on an odd counter ({})", value);
+    let mut active_ns = 0;
+    let overhead = time_active.then(|| clock::overhead(&MonotonicRaw).ns);
+    let mut migrations = Migrations::default();
+    let mut discarded = 0;
+    let cpu = || aux_cpu(read_tscp().1);
+
+    migrations.on_cpu(cpu());
+    let t0 = monotonic_raw_ns();
+    for _ in 0..round_trips {
+        let a0 = if time_active {
+            migrations.on_cpu(cpu());
+            monotonic_raw_ns()
+        } else {
+            0
+        };
+        value = value.wrapping_add(1);
+        shared.counter.store_and_wake(value);
+        if time_active {
+            let a1 = monotonic_raw_ns();
+            if migrations.on_cpu(cpu()).is_some() {
+                discarded += 1;
+            } else {
+                active_ns += a1 - a0;
+            }
+        }
+        value = waiter.wait_change(&shared.counter, value);
+    }
+    let t1 = monotonic_raw_ns();
+    migrations.on_cpu(cpu());
+
+    Stats {
+        round_trips: round_trips as u64,
+        total_ns: t1 - t0,
+        active_ns: time_active.then_some(active_ns),
+        clock_overhead_ns: overhead,
+        migrations: migrations.count,
+        discarded,
+    }
+}
+
+/// Answers every odd value until `stop` is called; returns the number of
+/// answers. May start before or after the ping side.
+pub fn pong_with<W: Waiter>(shared: &PingPong, waiter: &mut W) -> u64 {
+    let mut value = shared.counter.value.load(Ordering::Acquire);
+    let mut answered = 0;
+    loop {
+        while value.is_multiple_of(2) {
+            value = waiter.wait_change(&shared.counter, value);
+        }
+        if shared.done.load(Ordering::SeqCst) != 0 {
+            return answered;
+        }
+        value = value.wrapping_add(1);
+        shared.counter.store_and_wake(value);
+        answered += 1;
+    }
+}
+
+/// Tells the pong side to return. Call from the ping side once it is done.
+pub fn stop(shared: &PingPong) {
+    shared.done.store(1, Ordering::SeqCst);
+    let value = shared.counter.value.load(Ordering::Acquire);
+    shared.counter.store_and_wake(value.wrapping_add(1));
+}
+
+/// `ping_with` for a strategy picked at run time. `spin_budget` is only used
+/// by `WaitStrategy::SpinThenFutex`.
+pub fn ping(
+    shared: &PingPong,
+    strategy: WaitStrategy,
+    spin_budget: u32,
+    round_trips: u32,
+    time_active: bool,
+) -> Stats {
+    match strategy {
+        WaitStrategy::Spin => ping_with(shared, &mut Spin, round_trips, time_active),
+        WaitStrategy::Pause => ping_with(shared, &mut Pause, round_trips, time_active),
+        WaitStrategy::Yield => ping_with(shared, &mut Yield, round_trips, time_active),
+        WaitStrategy::Futex => ping_with(shared, &mut Futex, round_trips, time_active),
+        WaitStrategy::SpinThenFutex => ping_with(
+            shared,
+            &mut SpinThenFutex { budget: spin_budget },
+            round_trips,
+            time_active,
+        ),
+    }
+}
+
+/// `pong_with` for a strategy picked at run time.
+pub fn pong(shared: &PingPong, strategy: WaitStrategy, spin_budget: u32) -> u64 {
+    match strategy {
+        WaitStrategy::Spin => pong_with(shared, &mut Spin),
+        WaitStrategy::Pause => pong_with(shared, &mut Pause),
+        WaitStrategy::Yield => pong_with(shared, &mut Yield),
+        WaitStrategy::Futex => pong_with(shared, &mut Futex),
+        WaitStrategy::SpinThenFutex => {
+            pong_with(shared, &mut SpinThenFutex { budget: spin_budget })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    // Few round trips: the spinning strategies only hand over when the
+    // scheduler preempts them on a machine with one CPU.
+    const ROUND_TRIPS: u32 = 50;
+
+    fn round_trip(strategy: WaitStrategy, spin_budget: u32, pong_delay: Duration) {
+        let shared = Arc::new(PingPong::default());
+        let pong_side = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                thread::sleep(pong_delay);
+                pong(&shared, strategy, spin_budget)
+            })
+        };
+
+        let stats = ping(&shared, strategy, spin_budget, ROUND_TRIPS, true);
+        stop(&shared);
+
+        assert_eq!(pong_side.join().unwrap(), ROUND_TRIPS as u64);
+        assert_eq!(stats.round_trips, ROUND_TRIPS as u64);
+        assert!(stats.active_ns.unwrap() <= stats.total_ns);
+        assert!(stats.discarded <= stats.migrations);
+        assert_eq!(
+            shared.counter.value.load(Ordering::Relaxed),
+            2 * ROUND_TRIPS + 1
+        );
+        assert_eq!(shared.counter.sleepers.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn spin() {
+        round_trip(WaitStrategy::Spin, 0, Duration::ZERO);
+    }
+
+    #[test]
+    fn pause() {
+        round_trip(WaitStrategy::Pause, 0, Duration::ZERO);
+    }
+
+    #[test]
+    fn sched_yield() {
+        round_trip(WaitStrategy::Yield, 0, Duration::ZERO);
+    }
+
+    #[test]
+    fn futex() {
+        round_trip(WaitStrategy::Futex, 0, Duration::ZERO);
+    }
+
+    #[test]
+    fn spin_then_futex() {
+        round_trip(WaitStrategy::SpinThenFutex, 100, Duration::ZERO);
+    }
+
+    // Ping's first increment lands before pong has loaded the counter.
+    #[test]
+    fn pong_starts_late() {
+        round_trip(WaitStrategy::Futex, 0, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn strategy_names_round_trip() {
+        for strategy in WaitStrategy::ALL {
+            assert_eq!(strategy.name().parse::<WaitStrategy>(), Ok(strategy));
+        }
+        assert!("busy".parse::<WaitStrategy>().is_err());
+    }
+}
@@ -1,3 +1,6 @@
+import csv
+import sys
+
 import matplotlib.pyplot as plt
 
 # Writer TSC values
@@ -30,18 +33,28 @@ reader_tsc = [
     2376748281899400,
 ]
 
+# Or one run's CSV checkpoint files (--checkpoint-format=csv):
+#   python plot.py writer.csv reader.csv
+def load_ticks(path):
+    with open(path, newline="") as f:
+        return [int(row["ticks"]) for row in csv.DictReader(f)]
+
+if len(sys.argv) == 3:
+    writer_tsc, reader_tsc = load_ticks(sys.argv[1]), load_ticks(sys.argv[2])
+
 # Compute deltas (cycles between checkpoints)
 writer_deltas = [writer_tsc[i] - writer_tsc[i-1] for i in range(1, len(writer_tsc))]
 reader_deltas = [reader_tsc[i] - reader_tsc[i-1] for i in range(1, len(reader_tsc))]
 
-# X axis (checkpoint index)
-x = list(range(1, len(writer_tsc)))
+# X axis (checkpoint index); the sides may have a different number
+def x(deltas):
+    return list(range(1, len(deltas) + 1))
 
 # Plot
 plt.figure()
 
-plt.plot(x, writer_deltas, marker='o', label='Writer')
-plt.plot(x, reader_deltas, marker='o', label='Reader')
+plt.plot(x(writer_deltas), writer_deltas, marker='o', label='Writer')
+plt.plot(x(reader_deltas), reader_deltas, marker='o', label='Reader')
 
 plt.xlabel("Checkpoint")
 plt.ylabel("Cycles (delta TSC)")
@@ -0,0 +1,190 @@
+// prefault.rs
+//
+// Getting memory resident before a timed run, and counting the page faults
+// that happen anyway:
+//
+//   populate  MAP_POPULATE on the ring mapping; the kernel faults it in
+//   lock      mlock, so nothing is reclaimed or migrated during the run
+//   warm      touch every page from user space once
+//
+// Each process maps the ring itself, so each side prefaults its own view;
+// a page the peer already faulted in still costs a minor fault here.
+// Private buffers (the reader's sink) have to be written, not read, since a
+// read maps the shared zero page and the first write faults again.
+
+use std::fmt;
+use std::io;
+use std::ptr;
+
+use crate::cli::Args;
+use crate::ring::page_size;
+
+/// What to do to a mapping before the transfer starts.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Prefault {
+    pub populate: bool,
+    pub lock: bool,
+    pub warm: bool,
+}
+
+impl Prefault {
+    /// `--populate`, `--mlock` and `--warm`.
+    pub fn from_args(cli: &Args) -> Self {
+        Prefault {
+            populate: cli.flag("populate"),
+            lock: cli.flag("mlock"),
+            warm: cli.flag("warm"),
+        }
+    }
+
+    /// Populates, locks and warms `addr..addr + len`; `populated` if it was
+    /// mapped with MAP_POPULATE already.
+    ///
+    /// # Safety
+    /// `addr..addr + len` must be a writable shared mapping owned by the caller.
+    pub unsafe fn mapping(
+        &self,
+        addr: *mut libc::c_void,
+        len: usize,
+        populated: bool,
+    ) -> io::Result<()> {
+        if self.populate && !populated && libc::madvise(addr, len, libc::MADV_POPULATE_WRITE) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if self.lock {
+            lock(addr, len)?;
+        }
+        if self.warm {
+            touch(addr as *const u8, len);
+        }
+        Ok(())
+    }
+
+    /// Locks and writes `buf`, a private buffer about to be filled.
+    pub fn buffer(&self, buf: &mut [u8]) -> io::Result<()> {
+        if self.lock {
+            unsafe { lock(buf.as_ptr() as *const libc::c_void, buf.len())? };
+        }
+        if self.warm || self.populate {
+            for i in (0..buf.len()).step_by(page_size()) {
+                unsafe { ptr::write_volatile(&mut buf[i], 0) };
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Prefault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps: Vec<&str> = [
+            (self.populate, "populate"),
+            (self.lock, "mlock"),
+            (self.warm, "warm"),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|&(_, name)| name)
+        .collect();
+        if steps.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&steps.join("+"))
+        }
+    }
+}
+
+/// `mlock` with a hint about the limit when it is refused.
+///
+/// # Safety
+/// `addr..addr + len` must be mapped.
+pub unsafe fn lock(addr: *const libc::c_void, len: usize) -> io::Result<()> {
+    if libc::mlock(addr, len) != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENOMEM) | Some(libc::EPERM) => io::Error::new(
+                err.kind(),
+                format!("mlock of {} bytes: {} (check `ulimit -l`)", len, err),
+            ),
+            _ => err,
+        });
+    }
+    Ok(())
+}
+
+/// Reads one byte of every page of `addr..addr + len`, faulting it in.
+///
+/// # Safety
+/// `addr..addr + len` must be mapped and readable.
+pub unsafe fn touch(addr: *const u8, len: usize) {
+    for offset in (0..len).step_by(page_size()) {
+        ptr::read_volatile(addr.add(offset));
+    }
+}
+
+/// Page faults taken by the calling process so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Faults {
+    pub minor: u64,
+    pub major: u64,
+}
+
+impl Faults {
+    pub fn now() -> Self {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+        Faults {
+            minor: usage.ru_minflt as u64,
+            major: usage.ru_majflt as u64,
+        }
+    }
+
+    /// Faults taken between `earlier` and now.
+    pub fn since(earlier: Faults) -> Self {
+        let now = Faults::now();
+        Faults {
+            minor: now.minor - earlier.minor,
+            major: now.major - earlier.major,
+        }
+    }
+}
+
+impl fmt::Display for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} minor, {} major", self.minor, self.major)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warm_buffer_takes_no_faults_later() {
+        let pages = 64;
+        // Large enough to come from a fresh, untouched mmap.
+        let mut buf = vec![0u8; pages * page_size()];
+        let prefault = Prefault {
+            warm: true,
+            ..Prefault::default()
+        };
+        prefault.buffer(&mut buf).unwrap();
+
+        let before = Faults::now();
+        for i in (0..buf.len()).step_by(page_size()) {
+            unsafe { ptr::write_volatile(&mut buf[i], 1) };
+        }
+        // Other test threads share the process counters.
+        assert!(Faults::since(before).minor < pages as u64);
+    }
+
+    #[test]
+    fn describes_steps() {
+        let prefault = Prefault {
+            populate: true,
+            warm: true,
+            ..Prefault::default()
+        };
+        assert_eq!(prefault.to_string(), "populate+warm");
+        assert_eq!(Prefault::default().to_string(), "none");
+    }
+}
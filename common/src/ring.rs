@@ -0,0 +1,1954 @@
+// ring.rs
+//
+// Single-producer / single-consumer byte ring over a shared memory object
+// (POSIX shm by default; the segment name picks the backend, see
+// `backend.rs`) laid out as
+//
+//   | SegmentInfo (128 B) | index header | pad | data (capacity bytes) |
+//                                              ^ SegmentInfo::header_size
+//
+// Creation handshake (producer creates, consumer attaches, either may start
+// first):
+//
+//   producer                              consumer
+//   shm_open(O_CREAT | O_EXCL)            shm_open until the name exists
+//   ftruncate(segment_len)                wait for size >= SegmentInfo
+//   fill SegmentInfo, zero the indices    wait for `ready`
+//   ready = 1 (Release)          ------>  (Acquire) validate, map the rest
+//
+// Nothing but the 128-byte info slot is mapped before `ready`, and only
+// after the size check, so the consumer never touches a page past the end
+// of the object (SIGBUS). `O_EXCL` means the producer never adopts someone
+// else's indices or `transfer_started`. The producer writes its pid into
+// `SegmentInfo::creator_pid` as soon as the object is mapped, before the
+// slow part (binding, prefaulting), and an existing object is only replaced
+// if it is a leftover: a ring, ready or not, whose creator is no longer
+// running, something that is not a ring, or an object without a creator
+// pid that has not changed for `INIT_GRACE`. Anything else, including a
+// segment another producer is still setting up, is an `InUse` error, so of
+// two producers started together exactly one gets the name. A consumer
+// that finds a leftover, or whose object is replaced under it while it
+// waits, goes back to waiting for the name. Each side unlinks the name when
+// it is dropped, except the consumer of a unix segment, whose socket belongs
+// to the producer's handoff server.
+//
+// Once attached, every field is checked against what the consumer expects,
+// so a wrong size or a foreign segment is an error, not a fault.
+//
+// `start_index` and `end_index` only ever grow; the byte at logical
+// position `i` lives at `data[i % capacity]`.
+//
+// Two header layouts exist, picked with `RingOptions::layout`:
+//   Legacy: `ShmHeader`, all words in one cache line, both indices
+//           loaded on every call, `%` for the slot.
+//   Padded: `PaddedShmHeader`, one 128-byte slot per word, each side keeps
+//           its own index locally and a cached copy of the peer's that is
+//           only reloaded when it says the ring is full/empty, and a
+//           power-of-two capacity so the slot is a mask.
+//
+//   producer: copy into data[end..]   -> publish end_index   (Release)
+//   consumer: copy out of data[start..] -> publish start_index (Release)
+//
+// `write`/`read` do the copy. `reserve`/`commit` and `peek`/`release` split
+// the same steps so callers can fill or parse the shared region in place.
+//
+// `transfer_started` is the start/stop handshake: the consumer sets it to 1
+// once it is ready to read and back to 0 when it has read everything.
+//
+// With `RingOptions::blocking`, a side that finds the ring empty (consumer)
+// or full (producer) and calls `wait_for_data`/`wait_for_space` sets its
+// `*_waiting` word, re-checks the peer's index and sleeps on the word with
+// FUTEX_WAIT. After publishing an index the peer looks at the word and only
+// if it is set clears it and issues FUTEX_WAKE, so a side that never sleeps
+// costs its peer a fence and a load, not a syscall:
+//
+//   waiter: waiting = 1; fence(SeqCst); still empty/full? -> futex_wait
+//   peer:   publish index; fence(SeqCst); waiting != 0? -> waiting = 0, wake
+//
+// Either the peer sees `waiting` or the waiter sees the new index. A sleep
+// lasts at most `SLEEP_LIMIT`, after which a consumer looks at the producer's
+// pid, so one whose producer died returns `PeerGone` instead of hanging.
+//
+// `RingOptions::numa_node` binds the whole segment to one node with `mbind`
+// before the producer writes the header, so no page is placed by first
+// touch. The policy stays with the shm object, so the consumer, which
+// ignores the option, faults its pages in on the same node.
+//
+// `RingOptions::pages` picks the memory behind the segment (see `pages.rs`).
+// With explicit huge pages the object is a file on hugetlbfs instead of a
+// shm object, and every length and offset the segment is mapped or resized
+// with is rounded to the huge page size (`Geometry::page`); the handshake is
+// the same.
+//
+// `RingOptions::prefault` is applied by each side to its own mapping right
+// after `mmap`, so it is done before the producer publishes `ready` and
+// before the consumer can call `signal_start`.
+//
+// `RingOptions::prefetch` makes `read` issue `prefetcht0` for that many
+// cache lines past the bytes it is about to copy, so the lines the producer
+// wrote next are on their way while this chunk is copied. It never looks
+// past the end index it loaded (the peek is widened by the distance), so it
+// only asks for lines the producer has finished with.
+//
+// `RingOptions::copy` is the kernel `write` and `read` copy with (see
+// `copy.rs`); each side checks its own at create/open time. `reserve` and
+// `peek` callers copy however they like.
+//
+// The handshake is written in POSIX terms; the other backends do the same
+// steps with their own calls (a sysv segment is sized by `shmget` itself).
+// A unix segment skips the waiting: the producer only starts listening on
+// its socket once `ready` is set (see `handoff.rs`).
+//
+// Setup fails with a `RingError` (see `error.rs`). The consumer waits for a
+// missing segment until `RingOptions::open_timeout`, and once running can
+// ask `check_producer` whether the creator is still alive.
+
+use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::ptr;
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{Backend, Handle};
+use crate::copy::CopyKernel;
+use crate::error::RingError;
+use crate::futex::{futex_wait_for, futex_wake};
+use crate::handoff::Server;
+use crate::numa;
+use crate::pages::{self, Pages};
+use crate::prefault::Prefault;
+use crate::{
+    CachePadded, PaddedShmHeader, SegmentInfo, ShmHeader, RING_MAGIC, RING_VERSION,
+    SEGMENT_BLOCKING, SEGMENT_MIRRORED,
+};
+
+/// How often `RingConsumer::open` retries while the producer has not created
+/// (or finished initialising) the segment yet.
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a blocked consumer sleeps before it checks that the producer is
+/// still alive.
+const SLEEP_LIMIT: Duration = Duration::from_millis(100);
+
+// `SegmentInfo` gets its own 128-byte slot; the index header follows.
+const INFO_LEN: usize = size_of::<CachePadded<SegmentInfo>>();
+const INDEX_OFFSET: usize = INFO_LEN;
+
+const CACHE_LINE: usize = 64;
+
+// Data starts on its own cache-line pair when not mirrored.
+const DATA_ALIGN: usize = 128;
+
+pub use crate::backend::SegmentName;
+
+/// Turns a user supplied name into the `/name` form `shm_open` expects.
+pub fn shm_name(name: &str) -> Result<CString, RingError> {
+    let name = if name.starts_with('/') {
+        name.to_string()
+    } else {
+        format!("/{}", name)
+    };
+    CString::new(name).map_err(|e| RingError::InvalidConfig(format!("segment name: {}", e)))
+}
+
+/// Which header sits in front of the data region.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderLayout {
+    /// `ShmHeader`, as the original benchmarks used it.
+    #[default]
+    Legacy,
+    /// `PaddedShmHeader` with cached peer indices and masking.
+    Padded,
+}
+
+impl HeaderLayout {
+    pub fn header_size(self) -> usize {
+        match self {
+            HeaderLayout::Legacy => size_of::<ShmHeader>(),
+            HeaderLayout::Padded => size_of::<PaddedShmHeader>(),
+        }
+    }
+}
+
+impl HeaderLayout {
+    // Encoding in `SegmentInfo::layout`.
+    fn to_raw(self) -> u32 {
+        match self {
+            HeaderLayout::Legacy => 0,
+            HeaderLayout::Padded => 1,
+        }
+    }
+
+    fn from_raw(raw: u32) -> Option<Self> {
+        match raw {
+            0 => Some(HeaderLayout::Legacy),
+            1 => Some(HeaderLayout::Padded),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for HeaderLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" => Ok(HeaderLayout::Legacy),
+            "padded" => Ok(HeaderLayout::Padded),
+            _ => Err(format!("unknown header layout `{}` (legacy|padded)", s)),
+        }
+    }
+}
+
+/// Knobs for both ends of a ring. The producer and the consumer must use the
+/// same options, since they decide where the data region lives.
+#[derive(Debug, Clone, Default)]
+pub struct RingOptions {
+    /// Map the data region twice back to back in virtual memory, so any range
+    /// of up to `capacity` bytes starting anywhere in the ring is contiguous
+    /// and no copy has to be split at the wrap. The capacity must be a
+    /// multiple of the page size; the header then takes a page of its own.
+    pub mirrored: bool,
+    pub layout: HeaderLayout,
+    /// Sleep on a futex in `wait_for_data`/`wait_for_space` instead of
+    /// spinning, at the price of a fence and a load on every commit/release.
+    pub blocking: bool,
+    /// NUMA node to allocate the segment on. Only the producer applies it.
+    pub numa_node: Option<usize>,
+    pub pages: Pages,
+    /// hugetlbfs mount for explicit huge pages; looked up in /proc/mounts by
+    /// page size if not given.
+    pub hugetlbfs: Option<PathBuf>,
+    /// Unlike the options above, each side may pick its own.
+    pub prefault: Prefault,
+    pub copy: CopyKernel,
+    /// Cache lines `read` prefetches ahead of what it copies, 0 for none.
+    /// Only the consumer applies it.
+    pub prefetch: usize,
+    /// How long the consumer waits for the producer to create the segment;
+    /// `None` waits forever.
+    pub open_timeout: Option<Duration>,
+}
+
+pub fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// Where the pieces of a ring segment live.
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    data_offset: usize,
+    capacity: usize,
+    mirrored: bool,
+    layout: HeaderLayout,
+    blocking: bool,
+    pages: Pages,
+    // Granularity of the object: the huge page size for hugetlbfs.
+    page: usize,
+}
+
+impl Geometry {
+    fn new(capacity: u64, options: &RingOptions) -> Result<Self, RingError> {
+        if capacity == 0 {
+            return Err(RingError::InvalidConfig(
+                "ring capacity must be non-zero".to_string(),
+            ));
+        }
+        if options.layout == HeaderLayout::Padded && !capacity.is_power_of_two() {
+            return Err(RingError::InvalidConfig(format!(
+                "padded ring capacity {} is not a power of two",
+                capacity
+            )));
+        }
+        let page = options.pages.huge_size().unwrap_or_else(page_size);
+        if options.mirrored {
+            if !capacity.is_multiple_of(page as u64) {
+                return Err(RingError::InvalidConfig(format!(
+                    "mirrored ring capacity {} is not a multiple of the page size {}",
+                    capacity, page
+                )));
+            }
+            // The data region must start on a page boundary of the object
+            // so it can be mapped on its own a second time.
+            Ok(Geometry {
+                data_offset: page,
+                capacity: capacity as usize,
+                mirrored: true,
+                layout: options.layout,
+                blocking: options.blocking,
+                pages: options.pages,
+                page,
+            })
+        } else {
+            Ok(Geometry {
+                data_offset: (INDEX_OFFSET + options.layout.header_size())
+                    .next_multiple_of(DATA_ALIGN),
+                capacity: capacity as usize,
+                mirrored: false,
+                layout: options.layout,
+                blocking: options.blocking,
+                pages: options.pages,
+                page,
+            })
+        }
+    }
+
+    // Offset of logical position `pos` in the data region.
+    #[inline]
+    fn slot(&self, pos: u64) -> usize {
+        match self.layout {
+            HeaderLayout::Legacy => (pos % self.capacity as u64) as usize,
+            HeaderLayout::Padded => (pos & (self.capacity as u64 - 1)) as usize,
+        }
+    }
+
+    // Size of the shared object.
+    fn segment_len(&self) -> usize {
+        self.data_offset + self.capacity
+    }
+
+    // What the object is sized and mapped with.
+    fn object_len(&self) -> usize {
+        self.segment_len().next_multiple_of(self.page)
+    }
+
+    // Size of our view of it (the mirror adds a second copy of the data).
+    fn map_len(&self) -> usize {
+        if self.mirrored {
+            self.segment_len() + self.capacity
+        } else {
+            self.object_len()
+        }
+    }
+}
+
+/// Size of the shared object backing a ring of `capacity` data bytes.
+pub fn segment_size(capacity: u64, options: &RingOptions) -> Result<usize, RingError> {
+    Geometry::new(capacity, options).map(|g| g.segment_len())
+}
+
+// A setup check that failed because of how this machine is configured.
+fn config(err: io::Error) -> RingError {
+    RingError::InvalidConfig(err.to_string())
+}
+
+// How long a consumer keeps looking for the producer's segment.
+struct Patience {
+    start: Instant,
+    timeout: Option<Duration>,
+}
+
+impl Patience {
+    fn new(timeout: Option<Duration>) -> Self {
+        Patience {
+            start: Instant::now(),
+            timeout,
+        }
+    }
+
+    // Sleeps before the next retry, or fails once the timeout has passed.
+    fn wait(&self, object: &SegmentName) -> Result<(), RingError> {
+        let waited = self.start.elapsed();
+        if self.timeout.is_some_and(|timeout| waited >= timeout) {
+            return Err(RingError::Timeout {
+                name: object.display().into_owned(),
+                waited,
+            });
+        }
+        thread::sleep(OPEN_RETRY_INTERVAL);
+        Ok(())
+    }
+}
+
+// Owns the handle and the mapping of one ring segment.
+struct Mapping {
+    object: SegmentName,
+    handle: Handle,
+    ptr: *mut libc::c_void,
+    geometry: Geometry,
+    unlink_on_drop: bool,
+    // Hands a unix segment to its consumer; set once it is ready.
+    handoff: Option<Server>,
+}
+
+impl Mapping {
+    fn create(
+        object: SegmentName,
+        geometry: Geometry,
+        node: Option<usize>,
+        prefault: Prefault,
+    ) -> Result<Self, RingError> {
+        match geometry.pages {
+            Pages::Regular => {}
+            Pages::Transparent => pages::check_shmem_thp().map_err(config)?,
+            Pages::Huge2M | Pages::Huge1G => {
+                pages::check_pool(geometry.page, geometry.object_len()).map_err(config)?
+            }
+        }
+        let handle = create_exclusive(&object, &geometry)?;
+        if let Err(source) = object.resize(handle, geometry.object_len()) {
+            object.close(handle);
+            let _ = object.unlink();
+            return Err(RingError::Truncate {
+                name: object.display().into_owned(),
+                source,
+            });
+        }
+        // Prefaulting waits until the object is claimed, and with a node
+        // until after `mbind`: the policy has to be in place before anything
+        // is faulted in.
+        let mut map = Self::map(object.clone(), handle, geometry, Prefault::default(), true)
+            .inspect_err(|_| {
+                let _ = object.unlink();
+            })?;
+        map.claim();
+        // Dropping `map` unlinks the name on failure.
+        if let Some(node) = node {
+            unsafe { numa::bind(map.ptr, geometry.object_len(), node) }
+                .map_err(|e| map.object.map_error(e))?;
+        }
+        map.prefault(&prefault, false)?;
+        map.describe();
+        map.handoff = map
+            .object
+            .serve(handle)
+            .map_err(|e| map.object.open_error(e))?;
+        if map.handoff.is_some() {
+            // The server removes the socket itself.
+            map.unlink_on_drop = false;
+        }
+        Ok(map)
+    }
+
+    fn open(
+        object: SegmentName,
+        geometry: Geometry,
+        prefault: Prefault,
+        timeout: Option<Duration>,
+    ) -> Result<Self, RingError> {
+        let patience = Patience::new(timeout);
+        loop {
+            let handle = loop {
+                match object.open(true) {
+                    Ok(handle) => break handle,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => patience.wait(&object)?,
+                    Err(err) => return Err(object.open_error(err)),
+                }
+            };
+            match check_segment(handle, &object, &geometry, &patience) {
+                Ok(true) => {
+                    // A new producer may be serving the socket already.
+                    let unlink = object.backend() != Backend::Unix;
+                    return Self::map(object, handle, geometry, prefault, unlink);
+                }
+                Ok(false) => {
+                    object.close(handle);
+                    patience.wait(&object)?;
+                }
+                Err(err) => {
+                    object.close(handle);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn map(
+        object: SegmentName,
+        handle: Handle,
+        geometry: Geometry,
+        prefault: Prefault,
+        unlink_on_drop: bool,
+    ) -> Result<Self, RingError> {
+        let populated = prefault.populate && object.populates();
+        let flags = if populated { libc::MAP_POPULATE } else { 0 };
+        let mapped = if geometry.mirrored {
+            unsafe { map_mirrored(&object, handle, &geometry, flags) }
+        } else {
+            unsafe { object.map(handle, ptr::null_mut(), geometry.map_len(), 0, true, flags) }
+        };
+        let ptr = match mapped {
+            Ok(ptr) => ptr,
+            Err(err) => {
+                object.close(handle);
+                return Err(object.map_error(err));
+            }
+        };
+        if geometry.pages == Pages::Transparent {
+            // Advice is per mapping, so both sides give it.
+            unsafe { libc::madvise(ptr, geometry.map_len(), libc::MADV_HUGEPAGE) };
+        }
+        let map = Mapping {
+            object,
+            handle,
+            ptr,
+            geometry,
+            unlink_on_drop,
+            handoff: None,
+        };
+        map.prefault(&prefault, populated)?;
+        Ok(map)
+    }
+
+    // The whole view, mirror included. `populated` if the mapping was made
+    // with MAP_POPULATE already.
+    fn prefault(&self, prefault: &Prefault, populated: bool) -> Result<(), RingError> {
+        unsafe { prefault.mapping(self.ptr, self.geometry.map_len(), populated) }
+            .map_err(|e| self.object.map_error(e))
+    }
+
+    // Marks a new object as ours while it is set up, for a producer racing
+    // us for the name (see `create_exclusive`).
+    fn claim(&self) {
+        let info = self.ptr as *mut SegmentInfo;
+        unsafe { ptr::addr_of_mut!((*info).creator_pid).write(libc::getpid() as u32) };
+    }
+
+    // Writes `SegmentInfo`, resets the index header and then sets `ready`.
+    fn describe(&self) {
+        let geometry = &self.geometry;
+        let info = self.ptr as *mut SegmentInfo;
+        unsafe {
+            (*info).ready.store(0, Ordering::Relaxed);
+            ptr::addr_of_mut!((*info).magic).write(RING_MAGIC);
+            ptr::addr_of_mut!((*info).version).write(RING_VERSION);
+            ptr::addr_of_mut!((*info).layout).write(geometry.layout.to_raw());
+            ptr::addr_of_mut!((*info).header_size).write(geometry.data_offset as u64);
+            ptr::addr_of_mut!((*info).capacity).write(geometry.capacity as u64);
+            let mut flags = 0;
+            if geometry.mirrored {
+                flags |= SEGMENT_MIRRORED;
+            }
+            if geometry.blocking {
+                flags |= SEGMENT_BLOCKING;
+            }
+            ptr::addr_of_mut!((*info).flags).write(flags);
+            ptr::addr_of_mut!((*info).creator_pid).write(libc::getpid() as u32);
+        }
+        self.start_index().store(0, Ordering::Relaxed);
+        self.end_index().store(0, Ordering::Relaxed);
+        self.transfer_started().store(0, Ordering::Relaxed);
+        self.consumer_waiting().store(0, Ordering::Relaxed);
+        self.producer_waiting().store(0, Ordering::Relaxed);
+        unsafe { (*info).ready.store(1, Ordering::Release) };
+    }
+
+    fn index_header(&self) -> *const u8 {
+        unsafe { (self.ptr as *const u8).add(INDEX_OFFSET) }
+    }
+
+    fn start_index(&self) -> &AtomicU64 {
+        let header = self.index_header();
+        unsafe {
+            match self.geometry.layout {
+                HeaderLayout::Legacy => &(*(header as *const ShmHeader)).start_index,
+                HeaderLayout::Padded => &(*(header as *const PaddedShmHeader)).start_index,
+            }
+        }
+    }
+
+    fn end_index(&self) -> &AtomicU64 {
+        let header = self.index_header();
+        unsafe {
+            match self.geometry.layout {
+                HeaderLayout::Legacy => &(*(header as *const ShmHeader)).end_index,
+                HeaderLayout::Padded => &(*(header as *const PaddedShmHeader)).end_index,
+            }
+        }
+    }
+
+    fn transfer_started(&self) -> &AtomicU32 {
+        let header = self.index_header();
+        unsafe {
+            match self.geometry.layout {
+                HeaderLayout::Legacy => &(*(header as *const ShmHeader)).transfer_started,
+                HeaderLayout::Padded => &(*(header as *const PaddedShmHeader)).transfer_started,
+            }
+        }
+    }
+
+    fn consumer_waiting(&self) -> &AtomicU32 {
+        let header = self.index_header();
+        unsafe {
+            match self.geometry.layout {
+                HeaderLayout::Legacy => &(*(header as *const ShmHeader)).consumer_waiting,
+                HeaderLayout::Padded => &(*(header as *const PaddedShmHeader)).consumer_waiting,
+            }
+        }
+    }
+
+    fn producer_waiting(&self) -> &AtomicU32 {
+        let header = self.index_header();
+        unsafe {
+            match self.geometry.layout {
+                HeaderLayout::Legacy => &(*(header as *const ShmHeader)).producer_waiting,
+                HeaderLayout::Padded => &(*(header as *const PaddedShmHeader)).producer_waiting,
+            }
+        }
+    }
+
+    fn caches_peer(&self) -> bool {
+        self.geometry.layout == HeaderLayout::Padded
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { (self.ptr as *mut u8).add(self.geometry.data_offset) }
+    }
+
+    fn data_node(&self) -> io::Result<usize> {
+        unsafe { numa::node_of_addr(self.data() as *const libc::c_void) }
+    }
+}
+
+// Creates `object` exclusively, replacing a leftover object that is not
+// a live segment.
+fn create_exclusive(object: &SegmentName, geometry: &Geometry) -> Result<Handle, RingError> {
+    let in_use = || RingError::InUse {
+        name: object.display().into_owned(),
+    };
+    object.check_creatable()?;
+    // A memfd never collides with a leftover, but replacing its pid file
+    // would take the name from a running producer.
+    if matches!(object.backend(), Backend::Memfd | Backend::Unix)
+        && occupied(object, geometry.page).map_err(|e| object.open_error(e))?
+    {
+        return Err(in_use());
+    }
+    loop {
+        let err = match object.create(geometry.object_len()) {
+            Ok(handle) => return Ok(handle),
+            Err(err) => err,
+        };
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(object.open_error(err));
+        }
+        if occupied(object, geometry.page).map_err(|e| object.open_error(e))? {
+            return Err(in_use());
+        }
+        if let Err(err) = object.unlink() {
+            if err.kind() != io::ErrorKind::NotFound {
+                return Err(object.open_error(err));
+            }
+        }
+    }
+}
+
+// False if `object` is a leftover that may be replaced (see the top of the
+// file): true for a ring whose creator still runs, ready or not, and for an
+// object still being sized or claimed by a creator less than `INIT_GRACE`
+// ago.
+fn occupied(object: &SegmentName, page: usize) -> io::Result<bool> {
+    // Opening would take the segment from its consumer; a producer only
+    // listens once it is ready.
+    if object.backend() == Backend::Unix {
+        return Ok(object.exists());
+    }
+    let handle = match object.open(false) {
+        Ok(handle) => handle,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let recent = || {
+        object
+            .changed(handle)
+            .map(|changed| changed.elapsed().map_or(true, |age| age < INIT_GRACE))
+    };
+    let used = match object.len(handle) {
+        Ok(len) if len >= INFO_LEN => map_info(object, handle, page).and_then(|info| {
+            let (ready, magic, pid) = unsafe {
+                (
+                    (*info).ready.load(Ordering::Acquire),
+                    (*info).magic,
+                    (*info).creator_pid,
+                )
+            };
+            unsafe { object.unmap(info as *mut libc::c_void, page) };
+            match (ready, pid) {
+                (0, 0) => recent(),
+                (0, pid) => Ok(process_alive(pid)),
+                _ => Ok(magic == RING_MAGIC && process_alive(pid)),
+            }
+        }),
+        Ok(_) => recent(),
+        Err(err) => Err(err),
+    };
+    object.close(handle);
+    used
+}
+
+// How long an object can go without a creator pid before it counts as a
+// leftover; creating, sizing and mapping it takes far less.
+const INIT_GRACE: Duration = Duration::from_secs(2);
+
+fn process_alive(pid: u32) -> bool {
+    if pid == 0 {
+        return false;
+    }
+    // EPERM still means the process exists.
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    rc == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// True if `object` still names the object behind `handle`.
+fn same_object(object: &SegmentName, handle: Handle) -> io::Result<bool> {
+    let other = match object.open(false) {
+        Ok(other) => other,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let same = object
+        .id(handle)
+        .and_then(|a| object.id(other).map(|b| a == b));
+    object.close(other);
+    same
+}
+
+// Maps the first `page` bytes, the least a hugetlbfs file can be mapped
+// with (a sysv segment is attached whole anyway).
+fn map_info(object: &SegmentName, handle: Handle, page: usize) -> io::Result<*const SegmentInfo> {
+    unsafe { object.map(handle, ptr::null_mut(), page, 0, false, 0) }
+        .map(|ptr| ptr as *const SegmentInfo)
+}
+
+// Waits until the creator has sized the object and published its
+// `SegmentInfo`, then checks it against the geometry we are about to map.
+// `Ok(false)` means the object is a leftover or was replaced while we
+// waited; the caller should look the name up again.
+fn check_segment(
+    handle: Handle,
+    object: &SegmentName,
+    geometry: &Geometry,
+    patience: &Patience,
+) -> Result<bool, RingError> {
+    let io = |e| object.open_error(e);
+    while object.len(handle).map_err(io)? < INFO_LEN {
+        if !same_object(object, handle).map_err(io)? {
+            return Ok(false);
+        }
+        patience.wait(object)?;
+    }
+
+    let info_ptr = map_info(object, handle, geometry.page).map_err(|e| object.map_error(e))?;
+    let info = unsafe { &*info_ptr };
+    let result = loop {
+        if info.ready.load(Ordering::Acquire) != 0 {
+            break if process_alive(info.creator_pid) {
+                object.len(handle).map_err(io).and_then(|len| {
+                    validate(info, len, geometry)
+                        .map(|()| true)
+                        .map_err(|reason| RingError::LayoutMismatch {
+                            name: object.display().into_owned(),
+                            reason,
+                        })
+                })
+            } else {
+                Ok(false)
+            };
+        }
+        match same_object(object, handle) {
+            Ok(true) => {
+                if let Err(err) = patience.wait(object) {
+                    break Err(err);
+                }
+            }
+            other => break other.map_err(io),
+        }
+    };
+    unsafe { object.unmap(info_ptr as *mut libc::c_void, geometry.page) };
+    result
+}
+
+// Why `info` does not describe a segment with our geometry, if it does not.
+fn validate(info: &SegmentInfo, file_len: usize, geometry: &Geometry) -> Result<(), String> {
+    let mismatch = Err;
+
+    if info.magic != RING_MAGIC {
+        return mismatch(format!(
+            "not a ring segment: magic {:#018x}, expected {:#018x}",
+            info.magic, RING_MAGIC
+        ));
+    }
+    if info.version != RING_VERSION {
+        return mismatch(format!(
+            "ring segment version {}, this build understands {}",
+            info.version, RING_VERSION
+        ));
+    }
+    match HeaderLayout::from_raw(info.layout) {
+        Some(layout) if layout == geometry.layout => {}
+        Some(layout) => {
+            return mismatch(format!(
+                "segment uses the {:?} header layout, expected {:?}",
+                layout, geometry.layout
+            ))
+        }
+        None => return mismatch(format!("unknown header layout {}", info.layout)),
+    }
+    let mirrored = info.flags & SEGMENT_MIRRORED != 0;
+    if mirrored != geometry.mirrored {
+        return mismatch(format!(
+            "segment is {}mirrored, expected {}mirrored",
+            if mirrored { "" } else { "not " },
+            if geometry.mirrored { "" } else { "not " }
+        ));
+    }
+    let blocking = info.flags & SEGMENT_BLOCKING != 0;
+    if blocking != geometry.blocking {
+        return mismatch(format!(
+            "segment is {}blocking, expected {}blocking",
+            if blocking { "" } else { "non-" },
+            if geometry.blocking { "" } else { "non-" }
+        ));
+    }
+    if info.header_size != geometry.data_offset as u64 {
+        return mismatch(format!(
+            "segment header is {} bytes, expected {}",
+            info.header_size, geometry.data_offset
+        ));
+    }
+    if info.capacity != geometry.capacity as u64 {
+        return mismatch(format!(
+            "segment capacity is {} bytes, expected {}",
+            info.capacity, geometry.capacity
+        ));
+    }
+    if file_len < geometry.segment_len() {
+        return mismatch(format!(
+            "segment is {} bytes, its header describes {}",
+            file_len,
+            geometry.segment_len()
+        ));
+    }
+    Ok(())
+}
+
+// Lays out [header page | data | data again] in one address range:
+//
+//   base                 base + off            base + off + cap
+//   | header (offset 0)  | data (offset off)   | data (offset off) again |
+//
+// The whole range is reserved first so nothing else can land in between.
+unsafe fn map_mirrored(
+    object: &SegmentName,
+    handle: Handle,
+    geometry: &Geometry,
+    extra_flags: libc::c_int,
+) -> io::Result<*mut libc::c_void> {
+    // hugetlbfs views must start on a huge page boundary: reserve enough to
+    // align, then give the slack on either side back.
+    let slack = geometry.page - page_size();
+    let raw = libc::mmap(
+        ptr::null_mut(),
+        geometry.map_len() + slack,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1,
+        0,
+    );
+    if raw == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    let head = (raw as usize).next_multiple_of(geometry.page) - raw as usize;
+    let base = (raw as *mut u8).add(head) as *mut libc::c_void;
+    if head > 0 {
+        libc::munmap(raw, head);
+    }
+    if slack > head {
+        libc::munmap(
+            (base as *mut u8).add(geometry.map_len()) as *mut libc::c_void,
+            slack - head,
+        );
+    }
+
+    let mirror = (base as *mut u8).add(geometry.segment_len()) as *mut libc::c_void;
+    let flags = libc::MAP_FIXED | extra_flags;
+    let mapped = object
+        .map(handle, base, geometry.segment_len(), 0, true, flags)
+        .and_then(|_| {
+            object.map(
+                handle,
+                mirror,
+                geometry.capacity,
+                geometry.data_offset,
+                true,
+                flags,
+            )
+        });
+    if let Err(err) = mapped {
+        libc::munmap(base, geometry.map_len());
+        return Err(err);
+    }
+    Ok(base)
+}
+
+// Registers in `word` and sleeps on it while `blocked()` holds, for at most
+// `SLEEP_LIMIT`. The peer clears the word before waking us, so a wake that
+// races with the check makes the wait return at once. True if it timed out.
+fn sleep_while(word: &AtomicU32, blocked: impl Fn() -> bool) -> bool {
+    word.store(1, Ordering::SeqCst);
+    fence(Ordering::SeqCst);
+    let timed_out = blocked()
+        && unsafe { futex_wait_for(word, 1, SLEEP_LIMIT) } == -1
+        && io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT);
+    word.store(0, Ordering::Relaxed);
+    timed_out
+}
+
+// Wakes the peer if it registered in `word`. Call after publishing an index.
+fn wake(word: &AtomicU32) {
+    fence(Ordering::SeqCst);
+    if word.load(Ordering::Relaxed) != 0 && word.swap(0, Ordering::SeqCst) != 0 {
+        unsafe { futex_wake(word, 1) };
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // Stop handing out the fd before closing it.
+        self.handoff = None;
+        unsafe { self.object.unmap(self.ptr, self.geometry.map_len()) };
+        self.object.close(self.handle);
+        if self.unlink_on_drop {
+            let _ = self.object.unlink();
+        }
+    }
+}
+
+/// Writing end of the ring. Creates the shared segment.
+pub struct RingProducer {
+    map: Mapping,
+    capacity: u64,
+    // Our own index; only the padded layout trusts it over the shared copy.
+    end: u64,
+    // Last start_index seen (padded layout only).
+    cached_start: u64,
+    // Bytes handed out by the last `reserve`.
+    reserved: usize,
+    copy: CopyKernel,
+}
+
+// The mapping is only ever touched through `&self`/`&mut self` of the single
+// owner, so moving the producer to another thread is fine.
+unsafe impl Send for RingProducer {}
+
+impl RingProducer {
+    /// Creates the segment `name` with `capacity` data bytes, replacing a
+    /// leftover from a run that is no longer alive. Fails with `InUse` if
+    /// another producer is using the name.
+    pub fn create(name: &str, capacity: u64) -> Result<Self, RingError> {
+        Self::create_with(name, capacity, &RingOptions::default())
+    }
+
+    pub fn create_with(
+        name: &str,
+        capacity: u64,
+        options: &RingOptions,
+    ) -> Result<Self, RingError> {
+        options.copy.check()?;
+        let geometry = Geometry::new(capacity, options)?;
+        let map = Mapping::create(
+            SegmentName::new(name, options)?,
+            geometry,
+            options.numa_node,
+            options.prefault,
+        )?;
+        let end = map.end_index().load(Ordering::Relaxed);
+        let cached_start = map.start_index().load(Ordering::Acquire);
+        Ok(RingProducer {
+            map,
+            capacity,
+            end,
+            cached_start,
+            reserved: 0,
+            copy: options.copy,
+        })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn is_mirrored(&self) -> bool {
+        self.map.geometry.mirrored
+    }
+
+    pub fn layout(&self) -> HeaderLayout {
+        self.map.geometry.layout
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.map.geometry.blocking
+    }
+
+    pub fn pages(&self) -> Pages {
+        self.map.geometry.pages
+    }
+
+    /// The object the ring lives in.
+    pub fn segment(&self) -> &SegmentName {
+        &self.map.object
+    }
+
+    pub fn copy_kernel(&self) -> CopyKernel {
+        self.copy
+    }
+
+    /// NUMA node holding the first page of the data region.
+    pub fn data_node(&self) -> io::Result<usize> {
+        self.map.data_node()
+    }
+
+    /// Spins until the consumer sets `transfer_started`.
+    pub fn wait_for_consumer(&self) {
+        while self.map.transfer_started().load(Ordering::Acquire) == 0 {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Spins until the consumer clears `transfer_started` after reading
+    /// everything.
+    pub fn wait_for_consumer_done(&self) {
+        while self.map.transfer_started().load(Ordering::Relaxed) != 0 {
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Call after `write`/`reserve` found the ring full. Spins once when not
+    /// blocking; otherwise sleeps until the consumer releases something.
+    pub fn wait_for_space(&self) {
+        if !self.map.geometry.blocking {
+            std::hint::spin_loop();
+            return;
+        }
+        let map = &self.map;
+        sleep_while(map.producer_waiting(), || {
+            let start = map.start_index().load(Ordering::Acquire);
+            map.end_index().load(Ordering::Relaxed) - start >= self.capacity
+        });
+    }
+
+    /// Free space as two slices into the shared data region: the part up to
+    /// the end of the buffer and the part that wrapped to its start (always
+    /// empty for a mirrored ring). Together they hold at most `n` bytes, fewer
+    /// if the ring is fuller than that. Fill them in place, then `commit` the
+    /// bytes written.
+    pub fn reserve(&mut self, n: usize) -> (&mut [u8], &mut [u8]) {
+        let end_idx = if self.map.caches_peer() {
+            self.end
+        } else {
+            self.map.end_index().load(Ordering::Acquire)
+        };
+
+        let mut unused_len = self.capacity - (end_idx - self.cached_start);
+        if !self.map.caches_peer() || unused_len < n as u64 {
+            self.cached_start = self.map.start_index().load(Ordering::Acquire);
+            unused_len = self.capacity - (end_idx - self.cached_start);
+        }
+        let len = (n as u64).min(unused_len) as usize;
+
+        // Calculate write position with wrap-around; the mirror makes the
+        // whole range contiguous
+        let write_start = self.map.geometry.slot(end_idx);
+        let l = if self.is_mirrored() {
+            len
+        } else {
+            len.min(self.capacity as usize - write_start)
+        };
+        let data_start = self.map.data();
+        self.end = end_idx;
+        self.reserved = len;
+
+        unsafe {
+            (
+                slice::from_raw_parts_mut(data_start.add(write_start), l),
+                slice::from_raw_parts_mut(data_start, len - l),
+            )
+        }
+    }
+
+    /// Publishes `n` bytes previously filled through `reserve`.
+    pub fn commit(&mut self, n: usize) {
+        assert!(
+            n <= self.reserved,
+            "commit of {} bytes exceeds the {} reserved",
+            n,
+            self.reserved
+        );
+        self.reserved = 0;
+        self.end += n as u64;
+
+        // Barrier: smp_wmb() - ensure data writes complete before index update
+        fence(Ordering::Release);
+
+        self.map.end_index().store(self.end, Ordering::Release);
+        if self.map.geometry.blocking {
+            wake(self.map.consumer_waiting());
+        }
+    }
+
+    /// Copies as much of `src` as currently fits and publishes it. Returns the
+    /// number of bytes written, 0 if the ring is full.
+    pub fn write(&mut self, src: &[u8]) -> usize {
+        let copy = self.copy;
+        let (first, second) = self.reserve(src.len());
+        let l = first.len();
+        let len = l + second.len();
+        if len == 0 {
+            return 0;
+        }
+
+        // First part (until wrap or end of chunk)
+        copy.copy(first, &src[..l]);
+        // Second part (wrapped around to beginning)
+        copy.copy(second, &src[l..len]);
+
+        self.commit(len);
+        len
+    }
+}
+
+// Prefetches `lines` cache lines from `offset` on in the readable bytes
+// `first` followed by `second`, stopping at their end.
+fn prefetch_ahead(first: &[u8], second: &[u8], offset: usize, lines: usize) {
+    let end = (offset + lines * CACHE_LINE).min(first.len() + second.len());
+    for pos in (offset..end).step_by(CACHE_LINE) {
+        let line = match pos.checked_sub(first.len()) {
+            None => &first[pos],
+            Some(wrapped) => &second[wrapped],
+        };
+        unsafe { _mm_prefetch(line as *const u8 as *const i8, _MM_HINT_T0) };
+    }
+}
+
+/// Reading end of the ring. Attaches to the segment created by the producer
+/// and removes it when dropped.
+pub struct RingConsumer {
+    map: Mapping,
+    capacity: u64,
+    // Our own index; only the padded layout trusts it over the shared copy.
+    start: u64,
+    // Last end_index seen (padded layout only).
+    cached_end: u64,
+    // Bytes handed out by the last `peek`.
+    peeked: usize,
+    copy: CopyKernel,
+    // Cache lines to prefetch ahead in `read`.
+    prefetch: usize,
+}
+
+unsafe impl Send for RingConsumer {}
+
+impl RingConsumer {
+    /// Opens the segment `name`, waiting for the producer to create and
+    /// initialise it. `capacity` must match the producer's.
+    pub fn open(name: &str, capacity: u64) -> Result<Self, RingError> {
+        Self::open_with(name, capacity, &RingOptions::default())
+    }
+
+    /// `open` with options; gives up with `Timeout` after
+    /// `options.open_timeout`.
+    pub fn open_with(name: &str, capacity: u64, options: &RingOptions) -> Result<Self, RingError> {
+        options.copy.check()?;
+        let geometry = Geometry::new(capacity, options)?;
+        let map = Mapping::open(
+            SegmentName::new(name, options)?,
+            geometry,
+            options.prefault,
+            options.open_timeout,
+        )?;
+        let start = map.start_index().load(Ordering::Relaxed);
+        let cached_end = map.end_index().load(Ordering::Acquire);
+        Ok(RingConsumer {
+            map,
+            capacity,
+            start,
+            cached_end,
+            peeked: 0,
+            copy: options.copy,
+            prefetch: options.prefetch,
+        })
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    pub fn is_mirrored(&self) -> bool {
+        self.map.geometry.mirrored
+    }
+
+    pub fn layout(&self) -> HeaderLayout {
+        self.map.geometry.layout
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        self.map.geometry.blocking
+    }
+
+    pub fn pages(&self) -> Pages {
+        self.map.geometry.pages
+    }
+
+    /// The object the ring lives in.
+    pub fn segment(&self) -> &SegmentName {
+        &self.map.object
+    }
+
+    pub fn copy_kernel(&self) -> CopyKernel {
+        self.copy
+    }
+
+    /// NUMA node holding the first page of the data region.
+    pub fn data_node(&self) -> io::Result<usize> {
+        self.map.data_node()
+    }
+
+    /// Sets `transfer_started`, telling the producer to begin writing.
+    pub fn signal_start(&self) {
+        self.map.transfer_started().store(1, Ordering::Release);
+    }
+
+    /// Clears `transfer_started`, telling the producer everything was read.
+    pub fn signal_done(&self) {
+        self.map.transfer_started().store(0, Ordering::Relaxed);
+    }
+
+    /// `PeerGone` if the process that created the segment has exited. Cheap
+    /// enough to call every few thousand empty polls.
+    pub fn check_producer(&self) -> Result<(), RingError> {
+        let pid = unsafe { (*(self.map.ptr as *const SegmentInfo)).creator_pid };
+        if process_alive(pid) {
+            Ok(())
+        } else {
+            Err(RingError::PeerGone {
+                name: self.map.object.display().into_owned(),
+                pid,
+            })
+        }
+    }
+
+    /// Call after `read`/`peek` found the ring empty. Spins once when not
+    /// blocking; otherwise sleeps until the producer commits something, and
+    /// every `SLEEP_LIMIT` without a commit runs `check_producer`.
+    pub fn wait_for_data(&self) -> Result<(), RingError> {
+        if !self.map.geometry.blocking {
+            std::hint::spin_loop();
+            return Ok(());
+        }
+        let map = &self.map;
+        let timed_out = sleep_while(map.consumer_waiting(), || {
+            map.end_index().load(Ordering::Acquire) == map.start_index().load(Ordering::Relaxed)
+        });
+        if timed_out {
+            self.check_producer()?;
+        }
+        Ok(())
+    }
+
+    /// Readable bytes as two slices into the shared data region, in order:
+    /// the part up to the end of the buffer and the part that wrapped to its
+    /// start (always empty for a mirrored ring). Parse them in place, then
+    /// `release` what was consumed.
+    pub fn peek(&mut self) -> (&[u8], &[u8]) {
+        self.peek_at_least(1)
+    }
+
+    // With a cached end index, only goes back to shared memory when fewer
+    // than `want` bytes are known to be readable.
+    fn peek_at_least(&mut self, want: usize) -> (&[u8], &[u8]) {
+        let start_idx = if self.map.caches_peer() {
+            self.start
+        } else {
+            self.map.start_index().load(Ordering::Acquire)
+        };
+
+        if !self.map.caches_peer() || self.cached_end - start_idx < want as u64 {
+            self.cached_end = self.map.end_index().load(Ordering::Acquire);
+        }
+        let len = (self.cached_end - start_idx) as usize;
+
+        // Calculate read position with wrap-around; the mirror makes the
+        // whole range contiguous
+        let read_start = self.map.geometry.slot(start_idx);
+        let l = if self.is_mirrored() {
+            len
+        } else {
+            len.min(self.capacity as usize - read_start)
+        };
+        let data_start = self.map.data();
+        self.start = start_idx;
+        self.peeked = len;
+
+        unsafe {
+            (
+                slice::from_raw_parts(data_start.add(read_start), l),
+                slice::from_raw_parts(data_start, len - l),
+            )
+        }
+    }
+
+    /// Hands the first `n` peeked bytes back to the producer.
+    pub fn release(&mut self, n: usize) {
+        assert!(
+            n <= self.peeked,
+            "release of {} bytes exceeds the {} peeked",
+            n,
+            self.peeked
+        );
+        self.peeked = 0;
+        self.start += n as u64;
+
+        // Barrier: smp_wmb() - ensure data reads complete before index update
+        fence(Ordering::Release);
+
+        self.map.start_index().store(self.start, Ordering::Relaxed);
+        if self.map.geometry.blocking {
+            wake(self.map.producer_waiting());
+        }
+    }
+
+    /// Copies up to `dst.len()` available bytes out of the ring and releases
+    /// them to the producer. Returns the number of bytes read, 0 if the ring
+    /// is empty.
+    pub fn read(&mut self, dst: &mut [u8]) -> usize {
+        let (copy, prefetch) = (self.copy, self.prefetch);
+        let (first, second) = self.peek_at_least(dst.len().max(1) + prefetch * CACHE_LINE);
+        let len = dst.len().min(first.len() + second.len());
+        if len == 0 {
+            return 0;
+        }
+        if prefetch > 0 {
+            prefetch_ahead(first, second, len, prefetch);
+        }
+        let l = len.min(first.len());
+
+        // First part (until wrap or end of chunk)
+        copy.copy(&mut dst[..l], &first[..l]);
+        // Second part (wrapped around to beginning)
+        copy.copy(&mut dst[l..len], &second[..len - l]);
+
+        self.release(len);
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::JoinHandle;
+
+    const CAPACITY: u64 = 4096;
+    // Several laps around the ring.
+    const LEN: usize = 64 * 1024;
+    const DELAY: Duration = Duration::from_millis(250);
+
+    fn test_name(tag: &str) -> String {
+        format!("ring-test-{}-{}", std::process::id(), tag)
+    }
+
+    fn pattern() -> Vec<u8> {
+        (0..LEN).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Opens after `delay`, reads LEN bytes and hands them back.
+    fn spawn_consumer(name: &str, delay: Duration) -> JoinHandle<Vec<u8>> {
+        spawn_consumer_with(name, delay, RingOptions::default())
+    }
+
+    fn spawn_consumer_with(
+        name: &str,
+        delay: Duration,
+        options: RingOptions,
+    ) -> JoinHandle<Vec<u8>> {
+        let name = name.to_string();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let mut consumer = RingConsumer::open_with(&name, CAPACITY, &options).unwrap();
+            let mut out = vec![0u8; LEN];
+            let mut got = 0;
+            consumer.signal_start();
+            while got < LEN {
+                match consumer.read(&mut out[got..]) {
+                    0 if consumer.is_blocking() => consumer.wait_for_data().unwrap(),
+                    0 => thread::yield_now(),
+                    n => got += n,
+                }
+            }
+            consumer.signal_done();
+            out
+        })
+    }
+
+    fn produce(producer: &mut RingProducer) {
+        let src = pattern();
+        let mut sent = 0;
+        producer.wait_for_consumer();
+        while sent < LEN {
+            match producer.write(&src[sent..]) {
+                0 if producer.is_blocking() => producer.wait_for_space(),
+                0 => thread::yield_now(),
+                n => sent += n,
+            }
+        }
+        producer.wait_for_consumer_done();
+    }
+
+    // A finished, ready segment whose creator pid no longer exists, as a
+    // crashed run would leave it, with `transfer_started` still set.
+    fn leave_dead_segment(name: &str) {
+        let producer = RingProducer::create(name, CAPACITY).unwrap();
+        producer.map.transfer_started().store(1, Ordering::Relaxed);
+        let info = producer.map.ptr as *mut SegmentInfo;
+        // Above any pid_max the kernel allows.
+        unsafe { ptr::addr_of_mut!((*info).creator_pid).write(i32::MAX as u32) };
+        let mut map = producer.map;
+        map.unlink_on_drop = false;
+    }
+
+    #[test]
+    fn producer_first() {
+        let name = test_name("producer-first");
+        let mut producer = RingProducer::create(&name, CAPACITY).unwrap();
+        let consumer = spawn_consumer(&name, DELAY);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    #[test]
+    fn consumer_first() {
+        let name = test_name("consumer-first");
+        let consumer = spawn_consumer(&name, Duration::ZERO);
+        thread::sleep(DELAY);
+        let mut producer = RingProducer::create(&name, CAPACITY).unwrap();
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    // Stops the creator between every step; the waiting consumer must
+    // neither fault on the empty object nor attach before `ready`.
+    #[test]
+    fn slow_creator() {
+        let name = test_name("slow-creator");
+        let consumer = spawn_consumer(&name, Duration::ZERO);
+        thread::sleep(DELAY);
+
+        let geometry = Geometry::new(CAPACITY, &RingOptions::default()).unwrap();
+        let object = SegmentName::new(&name, &RingOptions::default()).unwrap();
+        let handle = create_exclusive(&object, &geometry).unwrap();
+        thread::sleep(DELAY);
+        object.resize(handle, geometry.segment_len()).unwrap();
+        let map = Mapping::map(object, handle, geometry, Prefault::default(), true).unwrap();
+        thread::sleep(DELAY);
+        assert!(!consumer.is_finished());
+        map.describe();
+
+        let mut producer = RingProducer {
+            map,
+            capacity: CAPACITY,
+            end: 0,
+            cached_start: 0,
+            reserved: 0,
+            copy: CopyKernel::Std,
+        };
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    // A second producer finds the first one's object before `ready`: still
+    // empty, then claimed. It must fail rather than replace it.
+    #[test]
+    fn racing_creator_keeps_unready_segment() {
+        let name = test_name("racing-creator");
+        let geometry = Geometry::new(CAPACITY, &RingOptions::default()).unwrap();
+        let object = SegmentName::new(&name, &RingOptions::default()).unwrap();
+        let handle = create_exclusive(&object, &geometry).unwrap();
+        let id = object.id(handle).unwrap();
+        let in_use = |step| {
+            let err = RingProducer::create(&name, CAPACITY).err().unwrap();
+            assert!(matches!(err, RingError::InUse { .. }), "{}: {}", step, err);
+        };
+
+        in_use("unsized");
+        object.resize(handle, geometry.segment_len()).unwrap();
+        in_use("sized");
+        let map =
+            Mapping::map(object.clone(), handle, geometry, Prefault::default(), true).unwrap();
+        map.claim();
+        in_use("claimed");
+        let reopened = object.open(false).unwrap();
+        assert_eq!(object.id(reopened).unwrap(), id);
+        object.close(reopened);
+
+        // The same half-built segment is a leftover once its creator is gone.
+        let info = map.ptr as *mut SegmentInfo;
+        unsafe { ptr::addr_of_mut!((*info).creator_pid).write(i32::MAX as u32) };
+        let replacement = RingProducer::create(&name, CAPACITY).unwrap();
+        assert_ne!(object.id(replacement.map.handle).unwrap(), id);
+        let mut map = map;
+        map.unlink_on_drop = false;
+    }
+
+    // Both call `create` at once; exactly one may get the name.
+    #[test]
+    fn concurrent_creators_one_wins() {
+        for round in 0..20 {
+            let name = test_name(&format!("concurrent-{}", round));
+            let barrier = std::sync::Barrier::new(2);
+            let results: Vec<_> = thread::scope(|s| {
+                let create = || {
+                    barrier.wait();
+                    RingProducer::create(&name, CAPACITY)
+                };
+                let a = s.spawn(create);
+                let b = s.spawn(create);
+                [a.join().unwrap(), b.join().unwrap()]
+            })
+            .into_iter()
+            .collect();
+            let won = results.iter().filter(|r| r.is_ok()).count();
+            assert_eq!(won, 1, "round {}", round);
+            for result in &results {
+                if let Err(err) = result {
+                    assert!(matches!(err, RingError::InUse { .. }), "{}", err);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_object_is_replaced() {
+        let name = test_name("garbage");
+        let cname = shm_name(&name).unwrap();
+        unsafe {
+            let fd = libc::shm_open(cname.as_ptr(), libc::O_CREAT | libc::O_RDWR, 0o666);
+            assert!(fd >= 0);
+            let junk = [0xFFu8; 4096];
+            assert_eq!(libc::write(fd, junk.as_ptr().cast(), junk.len()), 4096);
+            libc::close(fd);
+        }
+
+        let mut producer = RingProducer::create(&name, CAPACITY).unwrap();
+        assert_eq!(producer.map.transfer_started().load(Ordering::Relaxed), 0);
+        let consumer = spawn_consumer(&name, Duration::ZERO);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    #[test]
+    fn dead_segment_is_replaced() {
+        let name = test_name("dead-producer-first");
+        leave_dead_segment(&name);
+
+        let mut producer = RingProducer::create(&name, CAPACITY).unwrap();
+        assert_eq!(producer.map.transfer_started().load(Ordering::Relaxed), 0);
+        let consumer = spawn_consumer(&name, DELAY);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    // The consumer finds the leftover first and must wait for its
+    // replacement instead of attaching to it.
+    #[test]
+    fn consumer_skips_dead_segment() {
+        let name = test_name("dead-consumer-first");
+        leave_dead_segment(&name);
+
+        let consumer = spawn_consumer(&name, Duration::ZERO);
+        thread::sleep(DELAY);
+        assert!(!consumer.is_finished());
+        let mut producer = RingProducer::create(&name, CAPACITY).unwrap();
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    // Small writes into a small ring, so both sides keep finding it full or
+    // empty and go to sleep.
+    #[test]
+    fn blocking_round_trip() {
+        let name = test_name("blocking");
+        let options = RingOptions {
+            blocking: true,
+            ..RingOptions::default()
+        };
+        let mut producer = RingProducer::create_with(&name, CAPACITY, &options).unwrap();
+        let consumer = spawn_consumer_with(&name, Duration::ZERO, options);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+        assert_eq!(producer.map.consumer_waiting().load(Ordering::Relaxed), 0);
+        assert_eq!(producer.map.producer_waiting().load(Ordering::Relaxed), 0);
+    }
+
+    // With nothing coming, a sleeping consumer wakes up on its own and
+    // notices the producer is gone.
+    #[test]
+    fn blocked_consumer_sees_dead_producer() {
+        let name = test_name("blocked-dead-producer");
+        let options = RingOptions {
+            blocking: true,
+            ..RingOptions::default()
+        };
+        let producer = RingProducer::create_with(&name, CAPACITY, &options).unwrap();
+        let consumer = RingConsumer::open_with(&name, CAPACITY, &options).unwrap();
+        let info = producer.map.ptr as *mut SegmentInfo;
+        unsafe { ptr::addr_of_mut!((*info).creator_pid).write(i32::MAX as u32) };
+        let started = Instant::now();
+        let err = loop {
+            if let Err(err) = consumer.wait_for_data() {
+                break err;
+            }
+        };
+        assert!(matches!(err, RingError::PeerGone { .. }), "{}", err);
+        assert!(started.elapsed() >= SLEEP_LIMIT);
+    }
+
+    #[test]
+    fn blocking_mismatch_is_rejected() {
+        let name = test_name("blocking-mismatch");
+        let options = RingOptions {
+            blocking: true,
+            ..RingOptions::default()
+        };
+        let _producer = RingProducer::create_with(&name, CAPACITY, &options).unwrap();
+        let err = RingConsumer::open(&name, CAPACITY).err().unwrap();
+        assert!(matches!(err, RingError::LayoutMismatch { .. }), "{}", err);
+        assert_eq!(err.exit_code(), 6);
+    }
+
+    #[test]
+    fn open_times_out_without_producer() {
+        let options = RingOptions {
+            open_timeout: Some(Duration::from_millis(30)),
+            ..RingOptions::default()
+        };
+        let err = RingConsumer::open_with(&test_name("no-producer"), CAPACITY, &options)
+            .err()
+            .unwrap();
+        assert!(matches!(err, RingError::Timeout { .. }), "{}", err);
+    }
+
+    #[test]
+    fn numa_node_binds_segment() {
+        let name = test_name("numa");
+        let options = RingOptions {
+            numa_node: Some(0),
+            ..RingOptions::default()
+        };
+        let mut producer = RingProducer::create_with(&name, CAPACITY, &options).unwrap();
+        let consumer = spawn_consumer(&name, Duration::ZERO);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+        assert_eq!(producer.data_node().unwrap(), 0);
+    }
+
+    // Each side with its own kernel; the streaming one on the producer side
+    // relies on its `sfence` before the index is published.
+    #[test]
+    fn copy_kernels_round_trip() {
+        let kernels = CopyKernel::supported();
+        for (i, &kernel) in kernels.iter().enumerate() {
+            let name = test_name(&format!("copy-{}", kernel));
+            let options = |copy| RingOptions {
+                copy,
+                ..RingOptions::default()
+            };
+            let mut producer =
+                RingProducer::create_with(&name, CAPACITY, &options(kernel)).unwrap();
+            let peer = kernels[(i + 1) % kernels.len()];
+            let consumer = spawn_consumer_with(&name, Duration::ZERO, options(peer));
+            produce(&mut producer);
+            assert_eq!(
+                consumer.join().unwrap(),
+                pattern(),
+                "{} -> {}",
+                kernel,
+                peer
+            );
+        }
+    }
+
+    // Reads smaller than the prefetch distance, across the wrap, so the
+    // window is cut at the end index and split over both slices.
+    #[test]
+    fn prefetch_stays_in_bounds() {
+        let name = test_name("prefetch");
+        let options = RingOptions {
+            prefetch: 16,
+            ..RingOptions::default()
+        };
+        let mut producer = RingProducer::create_with(&name, CAPACITY, &options).unwrap();
+        let mut consumer = RingConsumer::open_with(&name, CAPACITY, &options).unwrap();
+        let src = pattern();
+        let mut dst = vec![0u8; src.len()];
+        let (mut written, mut read) = (0, 0);
+        while read < src.len() {
+            written += producer.write(&src[written..(written + 300).min(src.len())]);
+            while let n @ 1.. = consumer.read(&mut dst[read..(read + 100).min(src.len())]) {
+                read += n;
+            }
+        }
+        assert_eq!(dst, src);
+    }
+
+    // Without a hugetlbfs mount or free pages the error has to say so.
+    fn huge_or_skip(result: Result<RingProducer, RingError>) -> Option<RingProducer> {
+        match result {
+            Ok(producer) => Some(producer),
+            Err(err) => {
+                assert!(matches!(err, RingError::InvalidConfig(_)), "{}", err);
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn huge_pages_round_trip() {
+        let name = test_name("huge");
+        let options = RingOptions {
+            pages: Pages::Huge2M,
+            ..RingOptions::default()
+        };
+        let Some(mut producer) = huge_or_skip(RingProducer::create_with(&name, CAPACITY, &options))
+        else {
+            return;
+        };
+        let consumer = spawn_consumer_with(&name, Duration::ZERO, options);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+    }
+
+    #[test]
+    fn mirrored_huge_pages_wrap() {
+        const HUGE: u64 = 2 * 1024 * 1024;
+        let name = test_name("huge-mirrored");
+        let options = RingOptions {
+            pages: Pages::Huge2M,
+            mirrored: true,
+            ..RingOptions::default()
+        };
+        let Some(mut producer) = huge_or_skip(RingProducer::create_with(&name, HUGE, &options))
+        else {
+            return;
+        };
+        let mut consumer = RingConsumer::open_with(&name, HUGE, &options).unwrap();
+        let src: Vec<u8> = (0..HUGE as usize).map(|i| (i % 251) as u8).collect();
+        let mut dst = vec![0u8; HUGE as usize];
+
+        let half = HUGE as usize * 3 / 4;
+        assert_eq!(producer.write(&src[..half]), half);
+        assert_eq!(consumer.read(&mut dst[..half]), half);
+        // Straddles the end of the data region.
+        assert_eq!(producer.write(&src), HUGE as usize);
+        let (first, second) = consumer.peek();
+        assert_eq!(first.len(), HUGE as usize);
+        assert!(second.is_empty());
+        assert_eq!(first, &src[..]);
+    }
+
+    // A producer and a consumer of a new ring, both in this thread.
+    fn pair(tag: &str) -> (RingProducer, RingConsumer) {
+        let name = test_name(tag);
+        let producer = RingProducer::create(&name, CAPACITY).unwrap();
+        let consumer = RingConsumer::open(&name, CAPACITY).unwrap();
+        (producer, consumer)
+    }
+
+    #[test]
+    fn partial_commit_and_release() {
+        let (mut producer, mut consumer) = pair("partial");
+        let src = pattern();
+
+        let (first, second) = producer.reserve(100);
+        assert_eq!((first.len(), second.len()), (100, 0));
+        first.copy_from_slice(&src[..100]);
+        producer.commit(40);
+
+        let (first, second) = consumer.peek();
+        assert_eq!(first, &src[..40]);
+        assert!(second.is_empty());
+        consumer.release(10);
+        let (first, _) = consumer.peek();
+        assert_eq!(first, &src[10..40]);
+
+        // The uncommitted rest of the reservation is handed out again.
+        let (first, _) = producer.reserve(100);
+        first.copy_from_slice(&src[40..140]);
+        producer.commit(100);
+        let (first, _) = consumer.peek();
+        assert_eq!(first, &src[10..140]);
+        consumer.release(130);
+        let (first, second) = consumer.peek();
+        assert!(first.is_empty() && second.is_empty());
+    }
+
+    #[test]
+    fn wrapping_reservation_is_split() {
+        let (mut producer, mut consumer) = pair("wrap");
+        let src = pattern();
+        let lead = CAPACITY as usize - 100;
+        let mut dst = vec![0u8; lead];
+        assert_eq!(producer.write(&src[..lead]), lead);
+        assert_eq!(consumer.read(&mut dst), lead);
+
+        let (first, second) = producer.reserve(300);
+        assert_eq!((first.len(), second.len()), (100, 200));
+        first.copy_from_slice(&src[lead..lead + 100]);
+        second.copy_from_slice(&src[lead + 100..lead + 300]);
+        producer.commit(300);
+
+        let (first, second) = consumer.peek();
+        assert_eq!(first, &src[lead..lead + 100]);
+        assert_eq!(second, &src[lead + 100..lead + 300]);
+        consumer.release(300);
+    }
+
+    #[test]
+    fn reserve_on_full_ring_is_empty() {
+        let (mut producer, mut consumer) = pair("full");
+        let src = vec![7u8; CAPACITY as usize];
+        assert_eq!(producer.write(&src), CAPACITY as usize);
+
+        let (first, second) = producer.reserve(1);
+        assert!(first.is_empty() && second.is_empty());
+        assert_eq!(producer.write(&src[..1]), 0);
+
+        assert_eq!(consumer.read(&mut [0u8; 3]), 3);
+        let (first, second) = producer.reserve(10);
+        assert_eq!(first.len() + second.len(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "commit of 11 bytes exceeds the 10 reserved")]
+    fn commit_beyond_reservation_panics() {
+        let (mut producer, _consumer) = pair("overcommit");
+        producer.reserve(10);
+        producer.commit(11);
+    }
+
+    #[test]
+    #[should_panic(expected = "release of 6 bytes exceeds the 5 peeked")]
+    fn release_beyond_peek_panics() {
+        let (mut producer, mut consumer) = pair("overrelease");
+        assert_eq!(producer.write(&[1, 2, 3, 4, 5]), 5);
+        consumer.peek();
+        consumer.release(6);
+    }
+
+    #[test]
+    fn live_segment_is_not_replaced() {
+        let name = test_name("live");
+        let _producer = RingProducer::create(&name, CAPACITY).unwrap();
+        let err = RingProducer::create(&name, CAPACITY).err().unwrap();
+        assert!(matches!(err, RingError::InUse { .. }), "{}", err);
+    }
+
+    #[test]
+    fn consumer_notices_dead_producer() {
+        let name = test_name("peer-gone");
+        let producer = RingProducer::create(&name, CAPACITY).unwrap();
+        let consumer = RingConsumer::open(&name, CAPACITY).unwrap();
+        assert!(consumer.check_producer().is_ok());
+        let info = producer.map.ptr as *mut SegmentInfo;
+        unsafe { ptr::addr_of_mut!((*info).creator_pid).write(i32::MAX as u32) };
+        let err = consumer.check_producer().unwrap_err();
+        assert!(matches!(err, RingError::PeerGone { .. }), "{}", err);
+    }
+
+    fn backend_specs(tag: &str) -> [String; 5] {
+        let dir = std::env::temp_dir();
+        let key = (tag.len() as u32) << 24 | (std::process::id() & 0xff_ffff);
+        [
+            format!("posix:{}", test_name(tag)),
+            format!("file:{}", dir.join(test_name(tag)).display()),
+            format!("memfd:{}", test_name(tag)),
+            format!("sysv:{}", key),
+            format!("unix:{}", dir.join(test_name(tag)).display()),
+        ]
+    }
+
+    // The consumer starts first and has to find each kind of object.
+    #[test]
+    fn backends_round_trip() {
+        for spec in backend_specs("backends") {
+            let consumer = spawn_consumer(&spec, Duration::ZERO);
+            thread::sleep(DELAY);
+            let mut producer = RingProducer::create(&spec, CAPACITY).unwrap();
+            produce(&mut producer);
+            assert_eq!(consumer.join().unwrap(), pattern(), "{}", spec);
+            drop(producer);
+            let object = SegmentName::new(&spec, &RingOptions::default()).unwrap();
+            assert!(!occupied(&object, page_size()).unwrap(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn mirrored_backends_round_trip() {
+        let options = RingOptions {
+            mirrored: true,
+            ..RingOptions::default()
+        };
+        for spec in backend_specs("mirrored-backends") {
+            if spec.starts_with("sysv:") {
+                let err = RingProducer::create_with(&spec, CAPACITY, &options)
+                    .err()
+                    .unwrap();
+                assert!(matches!(err, RingError::InvalidConfig(_)), "{}", err);
+                continue;
+            }
+            let mut producer = RingProducer::create_with(&spec, CAPACITY, &options).unwrap();
+            let consumer = spawn_consumer_with(&spec, Duration::ZERO, options.clone());
+            produce(&mut producer);
+            assert_eq!(consumer.join().unwrap(), pattern(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn unix_segment_goes_to_one_consumer() {
+        let spec = format!(
+            "unix:{}",
+            std::env::temp_dir().join(test_name("handoff")).display()
+        );
+        let mut producer = RingProducer::create(&spec, CAPACITY).unwrap();
+        let err = RingProducer::create(&spec, CAPACITY).err().unwrap();
+        assert!(matches!(err, RingError::InUse { .. }), "{}", err);
+
+        let consumer = spawn_consumer(&spec, Duration::ZERO);
+        produce(&mut producer);
+        assert_eq!(consumer.join().unwrap(), pattern());
+        let options = RingOptions {
+            open_timeout: Some(DELAY),
+            ..RingOptions::default()
+        };
+        let err = RingConsumer::open_with(&spec, CAPACITY, &options)
+            .err()
+            .unwrap();
+        assert!(matches!(err, RingError::Timeout { .. }), "{}", err);
+    }
+
+    #[test]
+    fn unix_segment_keeps_other_files() {
+        let path = std::env::temp_dir().join(test_name("notes.txt"));
+        std::fs::write(&path, "notes").unwrap();
+        let err = RingProducer::create(&format!("unix:{}", path.display()), CAPACITY)
+            .err()
+            .unwrap();
+        assert!(matches!(err, RingError::InvalidConfig(_)), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "notes");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn producer_unlinks_on_drop() {
+        let name = test_name("unlink");
+        drop(RingProducer::create(&name, CAPACITY).unwrap());
+        let object = SegmentName::new(&name, &RingOptions::default()).unwrap();
+        assert!(!occupied(&object, page_size()).unwrap());
+        let fd = unsafe { libc::shm_open(shm_name(&name).unwrap().as_ptr(), libc::O_RDONLY, 0) };
+        assert!(fd < 0);
+    }
+}
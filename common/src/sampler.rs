@@ -0,0 +1,291 @@
+// sampler.rs
+//
+// Throughput over time: the bytes transferred at the end of every fixed
+// quantum of clock time (`--sample-us`, measured with `--clock`, the
+// calibrated TSC by default), where checkpoints (`checkpoint.rs`) take the
+// time at fixed byte counts. A stall shorter than a checkpoint interval is
+// lost in its average; here it is a run of samples that did not move.
+//
+//   let mut sampler = options.sampler("Reader", clock);
+//   sampler.start();
+//   ... sampler.progress(bytes) after every chunk ...
+//   sampler.finish(bytes);
+//   sampler.report()?;
+//
+// `progress` reads the clock and compares it with the end of the current
+// quantum. Quanta that ended since the last call get the bytes of that
+// call, since nothing was seen to arrive in between; that is also how the
+// quanta of a stall, with no calls at all, are filled in. The last sample
+// is a partial quantum ending at `finish`.
+//
+// Samples are preallocated for `PREALLOCATED` quanta; a longer run grows
+// the vector in the timed part. They are written like checkpoints (text,
+// csv or json, to stderr or `--sample-file`); `plotting/plot_samples.py`
+// plots the CSV.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::checkpoint::Format;
+use crate::cli::Args;
+use crate::clock::{Clock, ClockSource};
+
+/// Samples with room made up front.
+pub const PREALLOCATED: usize = 1 << 17;
+
+/// `--sample-us`, `--sample-format` and `--sample-file`.
+#[derive(Debug, Clone, Default)]
+pub struct SampleOptions {
+    /// No sampling if `None`.
+    pub quantum: Option<Duration>,
+    pub format: Format,
+    /// stderr if `None`.
+    pub file: Option<PathBuf>,
+}
+
+impl SampleOptions {
+    pub fn from_args(cli: &Args) -> Self {
+        SampleOptions {
+            quantum: cli
+                .parsed::<f64>("sample-us")
+                .filter(|&us| us > 0.0)
+                .map(|us| Duration::from_secs_f64(us / 1e6)),
+            format: cli.parsed("sample-format").unwrap_or_default(),
+            file: cli.value("sample-file").map(PathBuf::from),
+        }
+    }
+
+    /// A sampler if `--sample-us` was given.
+    pub fn sampler(&self, role: &'static str, clock: ClockSource) -> Option<Sampler> {
+        self.quantum
+            .map(|quantum| Sampler::new(role, clock, quantum, self))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    /// Clock ticks since `start` at the end of the quantum.
+    pub ticks: u64,
+    /// Bytes transferred by then.
+    pub bytes: u64,
+}
+
+pub struct Sampler {
+    role: &'static str,
+    clock: ClockSource,
+    options: SampleOptions,
+    quantum: u64,
+    start: u64,
+    next: u64,
+    bytes: u64,
+    samples: Vec<Sample>,
+}
+
+impl Sampler {
+    pub fn new(
+        role: &'static str,
+        clock: ClockSource,
+        quantum: Duration,
+        options: &SampleOptions,
+    ) -> Self {
+        let quantum = (quantum.as_secs_f64() * clock.hz()).round().max(1.0) as u64;
+        Sampler {
+            role,
+            clock,
+            options: options.clone(),
+            quantum,
+            start: 0,
+            next: u64::MAX,
+            bytes: 0,
+            samples: Vec::with_capacity(PREALLOCATED),
+        }
+    }
+
+    /// Starts the first quantum.
+    pub fn start(&mut self) {
+        self.start = self.clock.now();
+        self.next = self.start + self.quantum;
+        self.bytes = 0;
+    }
+
+    /// Notes that `bytes` are done now.
+    #[inline]
+    pub fn progress(&mut self, bytes: u64) {
+        let now = self.clock.now();
+        if now >= self.next {
+            self.catch_up(now);
+        }
+        self.bytes = bytes;
+    }
+
+    #[cold]
+    fn catch_up(&mut self, now: u64) {
+        while self.next <= now {
+            self.samples.push(Sample {
+                ticks: self.next - self.start,
+                bytes: self.bytes,
+            });
+            self.next += self.quantum;
+        }
+    }
+
+    /// Ends the last, partial, quantum.
+    pub fn finish(&mut self, bytes: u64) {
+        let now = self.clock.now();
+        self.catch_up(now);
+        self.bytes = bytes;
+        let ticks = now - self.start;
+        if self.samples.last().is_none_or(|s| s.ticks != ticks) {
+            self.samples.push(Sample { ticks, bytes });
+        }
+    }
+
+    pub fn samples(&self) -> &[Sample] {
+        &self.samples
+    }
+
+    /// Length of a quantum in clock ticks.
+    pub fn quantum(&self) -> u64 {
+        self.quantum
+    }
+
+    /// Samples in which no bytes moved.
+    pub fn stalls(&self) -> usize {
+        self.rates().filter(|&(_, _, rate)| rate == 0.0).count()
+    }
+
+    /// Each sample with its end in ns and the GB/s over its quantum.
+    fn rates(&self) -> impl Iterator<Item = (&Sample, u64, f64)> {
+        let mut prev = Sample { ticks: 0, bytes: 0 };
+        self.samples.iter().map(move |s| {
+            let ns = self.clock.to_ns(s.ticks);
+            let span = ns - self.clock.to_ns(prev.ticks);
+            let rate = (s.bytes - prev.bytes) as f64
+                / (1024.0 * 1024.0 * 1024.0)
+                / (span.max(1) as f64 / 1e9);
+            prev = *s;
+            (s, ns, rate)
+        })
+    }
+
+    /// Writes the samples where the options say.
+    pub fn report(&self) -> io::Result<()> {
+        match &self.options.file {
+            Some(path) => {
+                let mut out = BufWriter::new(File::create(path)?);
+                self.write(self.options.format, &mut out)?;
+                out.flush()
+            }
+            None => self.write(self.options.format, &mut io::stderr().lock()),
+        }
+    }
+
+    pub fn write(&self, format: Format, out: &mut dyn Write) -> io::Result<()> {
+        match format {
+            Format::Text => {
+                for (i, (s, ns, rate)) in self.rates().enumerate() {
+                    writeln!(
+                        out,
+                        "--- {} sample {} ns: {} bytes: {} {:.4} GB / s",
+                        self.role, i, ns, s.bytes, rate
+                    )?;
+                }
+            }
+            Format::Csv => {
+                writeln!(out, "role,index,clock,ticks,ns,bytes,gb_per_s")?;
+                for (i, (s, ns, rate)) in self.rates().enumerate() {
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{},{:.6}",
+                        self.role, i, self.clock, s.ticks, ns, s.bytes, rate
+                    )?;
+                }
+            }
+            Format::Json => {
+                write!(
+                    out,
+                    "{{\"role\": \"{}\", \"clock\": \"{}\", \"quantum_ns\": {}, \"samples\": [",
+                    self.role,
+                    self.clock,
+                    self.clock.to_ns(self.quantum)
+                )?;
+                for (i, (s, ns, rate)) in self.rates().enumerate() {
+                    write!(
+                        out,
+                        "{}\n  {{\"index\": {}, \"ticks\": {}, \"ns\": {}, \"bytes\": {}, \"gb_per_s\": {:.6}}}",
+                        if i == 0 { "" } else { "," },
+                        i,
+                        s.ticks,
+                        ns,
+                        s.bytes,
+                        rate
+                    )?;
+                }
+                writeln!(out, "\n]}}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn sampler(quantum: Duration) -> Sampler {
+        Sampler::new(
+            "Reader",
+            ClockSource::Monotonic,
+            quantum,
+            &SampleOptions::default(),
+        )
+    }
+
+    #[test]
+    fn fills_in_quanta_without_progress() {
+        let mut s = sampler(Duration::from_millis(1));
+        s.start();
+        s.progress(100);
+        thread::sleep(Duration::from_millis(5));
+        s.progress(200);
+        s.finish(200);
+
+        let samples = s.samples();
+        assert!(samples.len() >= 5, "{:?}", samples);
+        // The quanta of the sleep saw only the bytes from before it.
+        assert!(samples[..5].iter().all(|x| x.bytes <= 100), "{:?}", samples);
+        assert!(samples.iter().filter(|x| x.bytes == 100).count() >= 4);
+        assert_eq!(samples.last().unwrap().bytes, 200);
+        assert!(samples.windows(2).all(|w| w[0].ticks < w[1].ticks));
+        assert_eq!(samples[0].ticks, s.quantum());
+        assert!(s.stalls() >= 3);
+    }
+
+    #[test]
+    fn short_run_is_one_partial_sample() {
+        let mut s = sampler(Duration::from_secs(60));
+        s.start();
+        s.progress(10);
+        s.finish(20);
+        assert_eq!(s.samples().len(), 1);
+        assert_eq!(s.samples()[0].bytes, 20);
+        assert!(s.samples()[0].ticks < s.quantum());
+    }
+
+    #[test]
+    fn writes_csv() {
+        let mut s = sampler(Duration::from_secs(60));
+        s.start();
+        s.finish(1 << 30);
+        let mut csv = Vec::new();
+        s.write(Format::Csv, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "role,index,clock,ticks,ns,bytes,gb_per_s");
+        assert!(lines[1].starts_with("Reader,0,monotonic,"), "{}", lines[1]);
+        assert!(lines[1].contains(",1073741824,"), "{}", lines[1]);
+    }
+}
@@ -0,0 +1,429 @@
+// shm.rs
+//
+// A named POSIX shm object mapped read/write, for the benches that share a
+// fixed struct (`PingPong` and friends) rather than a ring:
+//
+//   creator                               opener
+//   shm_open(O_CREAT | O_EXCL)            shm_open(O_RDWR) until it exists
+//   flock(LOCK_EX), ftruncate(len), mmap  fstat: wait for size >= size_of::<T>
+//   ... shm_unlink, unmap, close on drop  mmap the whole object
+//                                         ... unmap, close on drop
+//
+// The creator always starts from a fresh, zero-filled object, never from a
+// previous run's state. It holds the lock until its fd is closed, so when
+// the name already exists `create` can tell a crashed run's leftover (sized,
+// nobody holds the lock) from the object of a creator still running, and
+// fails with `InUse` instead of unlinking the latter. Cleanup is in `Drop`,
+// so it also happens when a bench panics; `std::process::exit` (e.g. a
+// forked child leaving) skips it, which is what a child sharing its parent's
+// segment wants.
+//
+// The header `T` lives at offset 0. `header()` hands out `&T`, which is only
+// sound for `Plain` types: every bit pattern (the zeroes of a new object,
+// whatever the peer wrote) is a valid value, and the fields are atomics or
+// are only touched through raw pointers.
+
+use std::ffi::{CStr, CString};
+use std::io;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ptr::{self, NonNull};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::RingError;
+use crate::numa;
+use crate::ring::shm_name;
+
+/// How often `open_or_wait` retries while the creator has not created (or
+/// sized) the object yet.
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Types that may sit in shared memory and be read as `&T`: valid for every
+/// bit pattern, including all zeroes.
+///
+/// # Safety
+/// The implementor guarantees the above; e.g. `#[repr(C)]` structs of atomics,
+/// integers and byte arrays.
+pub unsafe trait Plain {}
+
+unsafe impl Plain for crate::pingpong::PingPong {}
+
+/// A mapping of the shm object `name`, holding a `T` at offset 0.
+pub struct ShmSegment<T> {
+    name: CString,
+    fd: libc::c_int,
+    ptr: NonNull<libc::c_void>,
+    len: usize,
+    unlink_on_drop: bool,
+    _header: PhantomData<T>,
+}
+
+// Access goes through atomics or raw pointers, as with any shared memory.
+unsafe impl<T: Send> Send for ShmSegment<T> {}
+
+impl<T> ShmSegment<T> {
+    /// Creates `name` with room for a `T`, replacing a leftover object.
+    /// `InUse` if another creator's segment still holds the name.
+    pub fn create(name: &str) -> Result<Self, RingError> {
+        Self::create_len(name, size_of::<T>())
+    }
+
+    /// Creates `name` with `len` bytes, at least a `T`, replacing a leftover
+    /// object as `create` does. The contents are zero; the name is unlinked
+    /// on drop.
+    pub fn create_len(name: &str, len: usize) -> Result<Self, RingError> {
+        if len < size_of::<T>() {
+            return Err(RingError::InvalidConfig(format!(
+                "{} bytes cannot hold a {} byte header",
+                len,
+                size_of::<T>()
+            )));
+        }
+        let name = shm_name(name)?;
+        let fd = loop {
+            let fd = unsafe {
+                libc::shm_open(
+                    name.as_ptr(),
+                    libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                    0o666,
+                )
+            };
+            if fd >= 0 {
+                break fd;
+            }
+            let source = io::Error::last_os_error();
+            if source.kind() != io::ErrorKind::AlreadyExists {
+                return Err(RingError::Open {
+                    name: name.to_string_lossy().into_owned(),
+                    source,
+                });
+            }
+            remove_leftover(&name)?;
+        };
+        // Unlinks and closes if anything below fails.
+        let mut segment = ShmSegment {
+            name,
+            fd,
+            ptr: NonNull::dangling(),
+            len: 0,
+            unlink_on_drop: true,
+            _header: PhantomData,
+        };
+        // Blocks only while a second creator looks at the new object.
+        if unsafe { libc::flock(fd, libc::LOCK_EX) } != 0 {
+            return Err(RingError::Open {
+                name: segment.name(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        if unsafe { libc::ftruncate(fd, len as libc::off_t) } != 0 {
+            return Err(RingError::Truncate {
+                name: segment.name(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        segment.map(len)?;
+        Ok(segment)
+    }
+
+    /// Opens `name`, which must exist and be large enough for a `T`.
+    pub fn open(name: &str) -> Result<Self, RingError> {
+        let name = shm_name(name)?;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0o666) };
+        if fd < 0 {
+            return Err(RingError::Open {
+                name: name.to_string_lossy().into_owned(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        let mut segment = ShmSegment {
+            name,
+            fd,
+            ptr: NonNull::dangling(),
+            len: 0,
+            unlink_on_drop: false,
+            _header: PhantomData,
+        };
+        // Mapping past the end of a smaller (foreign or half-created) object
+        // would SIGBUS on first touch.
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut st) } != 0 {
+            return Err(RingError::Open {
+                name: segment.name(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        let len = st.st_size as usize;
+        if len < size_of::<T>() {
+            return Err(RingError::LayoutMismatch {
+                name: segment.name(),
+                reason: format!("{} bytes, expected at least {}", len, size_of::<T>()),
+            });
+        }
+        segment.map(len)?;
+        Ok(segment)
+    }
+
+    /// Like `open`, but waits up to `timeout` for the creator to create and
+    /// size the object. `Timeout` if it never does.
+    pub fn open_or_wait(name: &str, timeout: Duration) -> Result<Self, RingError> {
+        let start = Instant::now();
+        loop {
+            match Self::open(name) {
+                Err(RingError::Open { ref source, .. })
+                    if source.kind() == io::ErrorKind::NotFound => {}
+                Err(RingError::LayoutMismatch { .. }) => {}
+                result => return result,
+            }
+            let waited = start.elapsed();
+            if waited >= timeout {
+                return Err(RingError::Timeout {
+                    name: shm_name(name)?.to_string_lossy().into_owned(),
+                    waited,
+                });
+            }
+            thread::sleep(OPEN_RETRY_INTERVAL);
+        }
+    }
+
+    fn name(&self) -> String {
+        self.name.to_string_lossy().into_owned()
+    }
+
+    fn map(&mut self, len: usize) -> Result<(), RingError> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.fd,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(RingError::Map {
+                name: self.name(),
+                source: io::Error::last_os_error(),
+            });
+        }
+        self.ptr = NonNull::new(ptr).unwrap();
+        self.len = len;
+        Ok(())
+    }
+
+    /// The header, as a raw pointer for in-place initialisation.
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr.as_ptr() as *mut T
+    }
+
+    /// Start of the whole mapping.
+    pub fn addr(&self) -> *mut libc::c_void {
+        self.ptr.as_ptr()
+    }
+
+    /// Bytes mapped, the size of the object.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether dropping the segment removes the name; `create` sets it.
+    pub fn set_unlink_on_drop(&mut self, unlink: bool) {
+        self.unlink_on_drop = unlink;
+    }
+
+    /// Binds the whole mapping to NUMA `node` (see `numa::bind`); call it
+    /// before the pages are first touched.
+    pub fn bind(&self, node: usize) -> Result<(), RingError> {
+        unsafe { numa::bind(self.addr(), self.len, node) }.map_err(|source| RingError::Map {
+            name: self.name(),
+            source,
+        })
+    }
+
+    /// The node holding the header's page.
+    pub fn node(&self) -> io::Result<usize> {
+        unsafe { numa::node_of_addr(self.addr()) }
+    }
+}
+
+// Unlinks `name` if it is a crashed creator's object: nobody holds its lock
+// and it was sized (a creator between `shm_open` and `flock` has not sized
+// it yet). `InUse` if a creator may still be running.
+fn remove_leftover(name: &CStr) -> Result<(), RingError> {
+    let open_error = |source| RingError::Open {
+        name: name.to_string_lossy().into_owned(),
+        source,
+    };
+    let in_use = || RingError::InUse {
+        name: name.to_string_lossy().into_owned(),
+    };
+    let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        // Gone since the create failed; try again.
+        return if err.kind() == io::ErrorKind::NotFound {
+            Ok(())
+        } else {
+            Err(open_error(err))
+        };
+    }
+    let result = unsafe {
+        if libc::flock(fd, libc::LOCK_EX | libc::LOCK_NB) != 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                Err(in_use())
+            } else {
+                Err(open_error(err))
+            }
+        } else {
+            match (file_id(fd), file_id_of(name)) {
+                (Ok((_, 0)), _) => Err(in_use()),
+                // Holding the lock keeps any other creator from replacing
+                // the name between this check and the unlink.
+                (Ok((id, _)), Ok(Some(now))) if id == now => {
+                    libc::shm_unlink(name.as_ptr());
+                    Ok(())
+                }
+                // Replaced or removed since it was opened; try again.
+                (Ok(_), Ok(_)) => Ok(()),
+                (Err(err), _) | (_, Err(err)) => Err(open_error(err)),
+            }
+        }
+    };
+    unsafe { libc::close(fd) };
+    result
+}
+
+// (dev, inode) and size of the open file `fd`.
+unsafe fn file_id(fd: libc::c_int) -> io::Result<((u64, u64), i64)> {
+    let mut st: libc::stat = std::mem::zeroed();
+    if libc::fstat(fd, &mut st) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(((st.st_dev, st.st_ino), st.st_size))
+}
+
+// (dev, inode) of the object `name` refers to now, `None` if it is gone.
+unsafe fn file_id_of(name: &CStr) -> io::Result<Option<(u64, u64)>> {
+    let fd = libc::shm_open(name.as_ptr(), libc::O_RDONLY, 0);
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        return if err.kind() == io::ErrorKind::NotFound {
+            Ok(None)
+        } else {
+            Err(err)
+        };
+    }
+    let id = file_id(fd).map(|(id, _)| Some(id));
+    libc::close(fd);
+    id
+}
+
+impl<T: Plain> ShmSegment<T> {
+    pub fn header(&self) -> &T {
+        unsafe { &*self.as_ptr() }
+    }
+}
+
+impl<T> Drop for ShmSegment<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // Before closing, which drops the lock: once it is gone another
+            // creator may replace the name.
+            if self.unlink_on_drop {
+                libc::shm_unlink(self.name.as_ptr());
+            }
+            if self.len > 0 {
+                libc::munmap(self.addr(), self.len);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pingpong::PingPong;
+    use std::sync::atomic::Ordering;
+
+    fn test_name(tag: &str) -> String {
+        format!("shm-test-{}-{}", std::process::id(), tag)
+    }
+
+    #[test]
+    fn creator_and_opener_share_the_header() {
+        let name = test_name("share");
+        let created = ShmSegment::<PingPong>::create(&name).unwrap();
+        assert_eq!(created.header().done.load(Ordering::Relaxed), 0);
+        let opened = ShmSegment::<PingPong>::open(&name).unwrap();
+        created.header().done.store(7, Ordering::Relaxed);
+        assert_eq!(opened.header().done.load(Ordering::Relaxed), 7);
+
+        drop(created);
+        match ShmSegment::<PingPong>::open(&name).err().unwrap() {
+            RingError::Open { source, .. } => assert_eq!(source.kind(), io::ErrorKind::NotFound),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn create_replaces_leftover() {
+        let name = test_name("leftover");
+        let mut old = ShmSegment::<PingPong>::create(&name).unwrap();
+        old.header().done.store(1, Ordering::Relaxed);
+        old.set_unlink_on_drop(false);
+        drop(old);
+        let new = ShmSegment::<PingPong>::create(&name).unwrap();
+        assert_eq!(new.header().done.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn create_keeps_live_segment() {
+        let name = test_name("live");
+        let live = ShmSegment::<PingPong>::create(&name).unwrap();
+        live.header().done.store(1, Ordering::Relaxed);
+        let err = ShmSegment::<PingPong>::create(&name).err().unwrap();
+        assert!(matches!(err, RingError::InUse { .. }), "{}", err);
+        assert_eq!(err.exit_code(), 3);
+        let opened = ShmSegment::<PingPong>::open(&name).unwrap();
+        assert_eq!(opened.header().done.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn open_rejects_small_object() {
+        let name = test_name("small");
+        let _small = ShmSegment::<u8>::create(&name).unwrap();
+        let err = ShmSegment::<[u64; 1024]>::open(&name).err().unwrap();
+        assert!(matches!(err, RingError::LayoutMismatch { .. }), "{}", err);
+    }
+
+    #[test]
+    fn open_or_wait_waits_for_creator() {
+        let name = test_name("wait");
+        let creator = {
+            let name = name.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                let segment = ShmSegment::<PingPong>::create(&name).unwrap();
+                segment.header().done.store(3, Ordering::Relaxed);
+                thread::sleep(Duration::from_millis(200));
+            })
+        };
+        let opened = ShmSegment::<PingPong>::open_or_wait(&name, Duration::from_secs(5)).unwrap();
+        creator.join().unwrap();
+        // The creator unlinked on drop; the mapping stays valid.
+        let _ = opened.header().done.load(Ordering::Relaxed);
+
+        let err = ShmSegment::<PingPong>::open_or_wait(&name, Duration::from_millis(30))
+            .err()
+            .unwrap();
+        assert!(matches!(err, RingError::Timeout { .. }), "{}", err);
+    }
+}
@@ -0,0 +1,285 @@
+// tsc.rs
+//
+// Whether TSC readings taken on different CPUs can be compared, which the
+// writer's and reader's checkpoints (and `plotting/plot_tsc.py`) assume. Two
+// things have to hold:
+//
+// - the TSC ticks at one rate through frequency changes (`constant_tsc`)
+//   and deep C-states (`nonstop_tsc`), per the flags in /proc/cpuinfo; and
+// - the counters of the CPUs involved agree.
+//
+// The offset of `cpu` against `reference` is measured by two threads pinned
+// to them, bouncing a cache line:
+//
+//   reference                          cpu
+//   t0 = tsc, request = i    ------>   spin until request == i
+//                                      reply = tsc, ack = i
+//   spin until ack == i      <------
+//   t1 = tsc
+//
+// `reply` was read between t0 and t1, so with agreeing counters it would be
+// their midpoint give or take half the round trip: offset = reply - (t0 +
+// t1) / 2, uncertain by (t1 - t0) / 2. The round with the shortest round
+// trip wins. The skew of a set of CPUs is the spread of their offsets
+// against the first one, uncertainties included, so it is an upper bound.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use crate::affinity::{pin_current, CpuList};
+use crate::clock::{Clock, Tsc};
+use crate::{read_tsc, CachePadded};
+
+/// Round trips per measured CPU.
+const ROUNDS: u64 = 2000;
+
+// Spins before a waiting side yields, in case both ended up on one CPU.
+const SPINS: u32 = 1 << 12;
+
+/// The TSC flags of /proc/cpuinfo.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Features {
+    pub constant: bool,
+    pub nonstop: bool,
+}
+
+impl Features {
+    /// Same rate on every CPU, all the time.
+    pub fn invariant(&self) -> bool {
+        self.constant && self.nonstop
+    }
+}
+
+/// Reads the flags of the first CPU in /proc/cpuinfo.
+pub fn features() -> io::Result<Features> {
+    parse_features(&fs::read_to_string("/proc/cpuinfo")?)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no flags line in /proc/cpuinfo"))
+}
+
+fn parse_features(cpuinfo: &str) -> Option<Features> {
+    let flags = cpuinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("flags")?.trim_start().strip_prefix(':'))?;
+    let has = |flag| flags.split_whitespace().any(|f| f == flag);
+    Some(Features {
+        constant: has("constant_tsc"),
+        nonstop: has("nonstop_tsc"),
+    })
+}
+
+/// How far `cpu`'s TSC is ahead of the reference CPU's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Offset {
+    pub cpu: usize,
+    pub cycles: i64,
+    /// Half the best round trip; the true offset is within this of `cycles`.
+    pub uncertainty: u64,
+}
+
+struct Exchange {
+    request: CachePadded<AtomicU64>,
+    ack: CachePadded<AtomicU64>,
+    reply: CachePadded<AtomicU64>,
+}
+
+fn wait_for(word: &AtomicU64, value: u64) {
+    let mut spins = 0;
+    while word.load(Ordering::Acquire) != value {
+        spins += 1;
+        if spins == SPINS {
+            spins = 0;
+            thread::yield_now();
+        } else {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Measures `cpu` against `reference` with two threads pinned to them.
+pub fn measure_offset(reference: usize, cpu: usize) -> io::Result<Offset> {
+    let exchange = Exchange {
+        request: CachePadded(AtomicU64::new(0)),
+        ack: CachePadded(AtomicU64::new(0)),
+        reply: CachePadded(AtomicU64::new(0)),
+    };
+    let pin = |cpu| pin_current(&CpuList::one(cpu));
+    thread::scope(|s| {
+        let responder = s.spawn(|| {
+            // A failed pin still has to answer, or the reference never
+            // finishes; the error is reported once both are done.
+            let pinned = pin(cpu);
+            for i in 1..=ROUNDS {
+                wait_for(&exchange.request, i);
+                exchange.reply.store(read_tsc(), Ordering::Relaxed);
+                exchange.ack.store(i, Ordering::Release);
+            }
+            pinned
+        });
+        let measured = s.spawn(|| {
+            let pinned = pin(reference);
+            let mut best = Offset {
+                cpu,
+                cycles: 0,
+                uncertainty: u64::MAX,
+            };
+            for i in 1..=ROUNDS {
+                let t0 = read_tsc();
+                exchange.request.store(i, Ordering::Release);
+                wait_for(&exchange.ack, i);
+                let t1 = read_tsc();
+                let reply = exchange.reply.load(Ordering::Relaxed);
+                let half = t1.wrapping_sub(t0) / 2;
+                if half < best.uncertainty {
+                    best.uncertainty = half;
+                    best.cycles = reply.wrapping_sub(t0.wrapping_add(half)) as i64;
+                }
+            }
+            pinned.map(|()| best)
+        });
+        let measured = measured.join().unwrap();
+        responder.join().unwrap()?;
+        measured
+    })
+}
+
+/// The TSC flags and the offsets of a set of CPUs against the first one.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub features: Features,
+    pub reference: usize,
+    pub offsets: Vec<Offset>,
+}
+
+impl Report {
+    /// Largest difference two of the CPUs' counters can have, in cycles.
+    pub fn skew(&self) -> u64 {
+        let reference = Offset {
+            cpu: self.reference,
+            cycles: 0,
+            uncertainty: 0,
+        };
+        let all = || self.offsets.iter().chain([&reference]);
+        let low = all()
+            .map(|o| o.cycles as i128 - o.uncertainty as i128)
+            .min();
+        let high = all()
+            .map(|o| o.cycles as i128 + o.uncertainty as i128)
+            .max();
+        (high.unwrap() - low.unwrap()) as u64
+    }
+
+    /// `skew` in nanoseconds.
+    pub fn skew_ns(&self) -> u64 {
+        Tsc.to_ns(self.skew())
+    }
+
+    /// Whether readings from these CPUs can be compared to within
+    /// `max_skew_ns`.
+    pub fn comparable(&self, max_skew_ns: u64) -> bool {
+        self.features.invariant() && self.skew_ns() <= max_skew_ns
+    }
+}
+
+/// Checks the flags and measures every CPU of `cpus` against the first.
+pub fn check(cpus: &[usize]) -> io::Result<Report> {
+    let features = features()?;
+    let Some(&reference) = cpus.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no CPUs to check",
+        ));
+    };
+    let mut offsets = Vec::new();
+    for &cpu in cpus {
+        if cpu != reference && offsets.iter().all(|o: &Offset| o.cpu != cpu) {
+            offsets.push(measure_offset(reference, cpu)?);
+        }
+    }
+    Ok(Report {
+        features,
+        reference,
+        offsets,
+    })
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let yes = |b| if b { "yes" } else { "no" };
+        write!(
+            f,
+            "constant_tsc {}, nonstop_tsc {}, skew <= {} cycles ({} ns) against cpu {}",
+            yes(self.features.constant),
+            yes(self.features.nonstop),
+            self.skew(),
+            self.skew_ns(),
+            self.reference
+        )?;
+        for o in &self.offsets {
+            write!(f, ", cpu {} {:+}±{}", o.cpu, o.cycles, o.uncertainty)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_flags() {
+        let cpuinfo = "processor\t: 0\nflags\t\t: fpu tsc constant_tsc rep_good nonstop_tsc\n";
+        let features = parse_features(cpuinfo).unwrap();
+        assert!(features.invariant());
+        let features = parse_features("flags\t: fpu tsc constant_tsc\n").unwrap();
+        assert_eq!(
+            features,
+            Features {
+                constant: true,
+                nonstop: false
+            }
+        );
+        assert!(parse_features("processor\t: 0\n").is_none());
+    }
+
+    #[test]
+    fn offset_against_itself_is_zero() {
+        // Both threads on one CPU: the only CPU this is sure to have.
+        let offset = measure_offset(0, 0).unwrap();
+        assert!(
+            offset.cycles.unsigned_abs() <= offset.uncertainty,
+            "{:?}",
+            offset
+        );
+    }
+
+    #[test]
+    fn skew_spans_all_offsets() {
+        let report = Report {
+            features: Features {
+                constant: true,
+                nonstop: true,
+            },
+            reference: 0,
+            offsets: vec![
+                Offset {
+                    cpu: 1,
+                    cycles: 30,
+                    uncertainty: 10,
+                },
+                Offset {
+                    cpu: 2,
+                    cycles: -20,
+                    uncertainty: 5,
+                },
+            ],
+        };
+        // From cpu 2's -25 to cpu 1's +40.
+        assert_eq!(report.skew(), 65);
+        assert!(report.comparable(u64::MAX));
+        let single = check(&[0]).unwrap();
+        assert_eq!(single.skew(), 0);
+    }
+}
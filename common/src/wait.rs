@@ -0,0 +1,276 @@
+// wait.rs
+//
+// Ways for one side to wait for the other to change a shared 32-bit word.
+// Every strategy implements `Waiter`, so a benchmark can be written once and
+// run with each of them:
+//
+//   spin        reload in a tight loop
+//   pause       reload with a PAUSE (`spin_loop`) between loads
+//   yield       reload with a `sched_yield` between loads
+//   futex       sleep in FUTEX_WAIT straight away
+//   spin-futex  PAUSE-spin for a budget of loads, then sleep like `futex`
+//
+// The sleeping strategies count themselves in `WaitWord::sleepers` before
+// FUTEX_WAIT, and `wake` only makes the FUTEX_WAKE syscall when that count
+// is non-zero. The store of the new value and the load of `sleepers` are both
+// SeqCst, as are the increment and the kernel's re-check of the value, so
+// either the waker sees the sleeper or the sleeper sees the new value.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::futex::{futex_wait, futex_wake};
+
+/// A word one side waits on and the other changes.
+#[repr(C)]
+#[derive(Default)]
+pub struct WaitWord {
+    pub value: AtomicU32,
+    /// Threads currently in (or entering) FUTEX_WAIT on `value`.
+    pub sleepers: AtomicU32,
+}
+
+impl WaitWord {
+    /// Publishes `value` and wakes a sleeper if there is one.
+    pub fn store_and_wake(&self, value: u32) {
+        self.value.store(value, Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) != 0 {
+            unsafe { futex_wake(&self.value, 1) };
+        }
+    }
+
+    fn sleep(&self, current: u32) {
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        unsafe { futex_wait(&self.value, current) };
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub trait Waiter {
+    /// Returns once `word` no longer holds `current`, with the value it holds
+    /// now.
+    fn wait_change(&mut self, word: &WaitWord, current: u32) -> u32;
+}
+
+/// Reloads in a tight loop with no hint to the CPU.
+pub struct Spin;
+
+impl Waiter for Spin {
+    #[inline]
+    fn wait_change(&mut self, word: &WaitWord, current: u32) -> u32 {
+        loop {
+            let value = word.value.load(Ordering::Acquire);
+            if value != current {
+                return value;
+            }
+        }
+    }
+}
+
+/// Reloads with a PAUSE in between, which saves power, gives a hyperthread
+/// sibling the core and avoids the memory-order mis-speculation flush when
+/// the word finally changes.
+pub struct Pause;
+
+impl Waiter for Pause {
+    #[inline]
+    fn wait_change(&mut self, word: &WaitWord, current: u32) -> u32 {
+        loop {
+            let value = word.value.load(Ordering::Acquire);
+            if value != current {
+                return value;
+            }
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Gives the CPU back to the scheduler between loads.
+pub struct Yield;
+
+impl Waiter for Yield {
+    #[inline]
+    fn wait_change(&mut self, word: &WaitWord, current: u32) -> u32 {
+        loop {
+            let value = word.value.load(Ordering::Acquire);
+            if value != current {
+                return value;
+            }
+            unsafe { libc::sched_yield() };
+        }
+    }
+}
+
+/// Sleeps in the kernel until woken.
+pub struct Futex;
+
+impl Waiter for Futex {
+    #[inline]
+    fn wait_change(&mut self, word: &WaitWord, current: u32) -> u32 {
+        loop {
+            let value = word.value.load(Ordering::Acquire);
+            if value != current {
+                return value;
+            }
+            word.sleep(current);
+        }
+    }
+}
+
+/// PAUSE-spins for up to `budget` loads, then sleeps like `Futex`.
+pub struct SpinThenFutex {
+    pub budget: u32,
+}
+
+impl Waiter for SpinThenFutex {
+    #[inline]
+    fn wait_change(&mut self, word: &WaitWord, current: u32) -> u32 {
+        for _ in 0..self.budget {
+            let value = word.value.load(Ordering::Acquire);
+            if value != current {
+                return value;
+            }
+            std::hint::spin_loop();
+        }
+        Futex.wait_change(word, current)
+    }
+}
+
+/// Default number of loads `spin-futex` spends spinning before it sleeps.
+pub const DEFAULT_SPIN_BUDGET: u32 = 1000;
+
+/// Command line name of a `Waiter`, for `--wait=...`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStrategy {
+    Spin,
+    Pause,
+    Yield,
+    Futex,
+    SpinThenFutex,
+}
+
+impl WaitStrategy {
+    pub const ALL: [WaitStrategy; 5] = [
+        WaitStrategy::Spin,
+        WaitStrategy::Pause,
+        WaitStrategy::Yield,
+        WaitStrategy::Futex,
+        WaitStrategy::SpinThenFutex,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WaitStrategy::Spin => "spin",
+            WaitStrategy::Pause => "pause",
+            WaitStrategy::Yield => "yield",
+            WaitStrategy::Futex => "futex",
+            WaitStrategy::SpinThenFutex => "spin-futex",
+        }
+    }
+}
+
+impl fmt::Display for WaitStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for WaitStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        WaitStrategy::ALL
+            .into_iter()
+            .find(|w| w.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = WaitStrategy::ALL.iter().map(|w| w.name()).collect();
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Enough hand-overs for a lost wakeup to show up as a hang.
+    const ITERATIONS: u32 = 20_000;
+    const HANG: Duration = Duration::from_secs(20);
+
+    #[test]
+    fn satisfied_waiter_does_not_sleep() {
+        let word = WaitWord::default();
+        word.value.store(5, Ordering::Relaxed);
+        // Nobody wakes the word, so a sleeper would never return.
+        assert_eq!(Spin.wait_change(&word, 4), 5);
+        assert_eq!(Pause.wait_change(&word, 4), 5);
+        assert_eq!(Yield.wait_change(&word, 4), 5);
+        assert_eq!(Futex.wait_change(&word, 4), 5);
+        assert_eq!(SpinThenFutex { budget: 0 }.wait_change(&word, 4), 5);
+        assert_eq!(word.sleepers.load(Ordering::Relaxed), 0);
+    }
+
+    // Two threads take turns bumping `ping` and `pong`, each sleeping in
+    // the kernel for the other.
+    fn hand_over<W: Waiter + 'static>(new: fn() -> W) {
+        let (ping, pong) = (Arc::new(WaitWord::default()), Arc::new(WaitWord::default()));
+        let (done, finished) = mpsc::channel();
+        let other = {
+            let (ping, pong) = (ping.clone(), pong.clone());
+            thread::spawn(move || {
+                let mut waiter = new();
+                for i in 1..=ITERATIONS {
+                    assert_eq!(waiter.wait_change(&ping, i - 1), i);
+                    pong.store_and_wake(i);
+                }
+            })
+        };
+        thread::spawn(move || {
+            let mut waiter = new();
+            for i in 1..=ITERATIONS {
+                ping.store_and_wake(i);
+                assert_eq!(waiter.wait_change(&pong, i - 1), i);
+            }
+            done.send(()).unwrap();
+        });
+        finished
+            .recv_timeout(HANG)
+            .expect("a wakeup was lost, or a side failed");
+        other.join().unwrap();
+    }
+
+    #[test]
+    fn futex_loses_no_wakeup() {
+        hand_over(|| Futex);
+    }
+
+    #[test]
+    fn spin_then_futex_loses_no_wakeup() {
+        hand_over(|| SpinThenFutex { budget: 10 });
+    }
+
+    #[test]
+    fn spin_then_futex_sleeps_after_budget() {
+        let word = Arc::new(WaitWord::default());
+        let (woken, value) = mpsc::channel();
+        {
+            let word = word.clone();
+            thread::spawn(move || {
+                let _ = woken.send(SpinThenFutex { budget: 1000 }.wait_change(&word, 0));
+            });
+        }
+        let start = Instant::now();
+        while word.sleepers.load(Ordering::SeqCst) == 0 {
+            assert!(start.elapsed() < HANG, "never went to sleep");
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(value.try_recv().is_err());
+        word.store_and_wake(1);
+        assert_eq!(value.recv_timeout(HANG), Ok(1), "the sleeper was not woken");
+    }
+}
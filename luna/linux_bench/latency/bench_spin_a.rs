@@ -1,72 +1,53 @@
 // Process A: Creates shared memory, initializes to 0, increments when odd
 
-use std::env;
-use std::ffi::CString;
-use std::sync::atomic::{AtomicU32, Ordering};
+use common::affinity::Placement;
+use common::cli::{Args, USAGE};
+use common::error::OrExit;
+use common::numa;
+use common::pingpong::{self, PingPong};
+use common::shm::ShmSegment;
+use common::wait::WaitStrategy;
 
 fn main() {
     // Get shared memory name from command line
-    // env::args() gives us the command line arguments
-    // .nth(1) gets the second argument (first is program name)
-    // .expect() crashes with a message if argument is missing
-    let shm_name = env::args().nth(1)
-        .expect("Usage: process_a <shared_memory_name>");
-    
-    // Add '/' prefix if not present (required for POSIX shared memory)
-    let shm_name = if shm_name.starts_with('/') {
-        shm_name
-    } else {
-        format!("/{}", shm_name)
-    };
-    
-    // Convert Rust string to C string (null-terminated)
-    let c_name = CString::new(shm_name.as_bytes()).unwrap();
-    
-    // Open/create shared memory
-    let fd = unsafe {
-        libc::shm_open(
-            c_name.as_ptr(),           // pointer to name string
-            libc::O_CREAT | libc::O_RDWR,  // create if needed, read/write
-            0o666                       // permissions: read/write for all
-        )
-    };
-    
-    if fd < 0 {
-        panic!("Failed to create shared memory");
-    }
-    
-    // Set size to 4 bytes (size of u32)
-    unsafe {
-        libc::ftruncate(fd, 4);
-    }
-    
-    // Map shared memory into our address space
-    let ptr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),      // let OS choose address
-            4,                          // 4 bytes
-            libc::PROT_READ | libc::PROT_WRITE,  // read and write access
-            libc::MAP_SHARED,           // share with other processes
-            fd,                         // our file descriptor
-            0                           // offset 0
-        )
+    // cli.positional() holds the arguments that are not --options
+    // [1] is the first one after the program name
+    // without it, print the usage and exit with the usage status
+    let cli = Args::from_env();
+    let Some(shm_name) = cli.positional().get(1).cloned() else {
+        eprintln!("Usage: process_a <shared_memory_name> [--cpu=LIST] [--numa=NODE]");
+        std::process::exit(USAGE);
     };
-    
-    if ptr == libc::MAP_FAILED {
-        panic!("Failed to map shared memory");
+
+    // Pin to --cpu and take memory from --numa before touching anything
+    let placement = Placement::from_args(&cli, "cpu");
+    placement
+        .apply()
+        .or_exit("Failed to apply placement");
+    
+    // Create a fresh, zero-filled segment sized for the shared counter, so
+    // the counter starts at 0; it is removed again when `segment` goes out
+    // of scope, even on a panic
+    let segment = ShmSegment::<PingPong>::create(&shm_name)
+        .or_exit("Failed to create shared memory");
+
+    // Place the counter's page on the --numa node for both processes
+    if let Some(node) = placement.node {
+        segment
+            .bind(node)
+            .or_exit("Failed to bind shared memory");
     }
+
+    let shared = segment.header();
     
-    // Cast raw pointer to AtomicU32 (atomic 32-bit unsigned integer)
-    // &* converts pointer to reference
-    let shared = unsafe { &*(ptr as *const AtomicU32) };
-    
-    shared.store(0, Ordering::SeqCst);
     println!("Process A ready. Waiting for odd numbers...");
     
-    loop {
-        let val = shared.load(Ordering::SeqCst);  
-        if val % 2 == 1 {                          
-            shared.store(val + 1, Ordering::SeqCst);  
-        }
-    }
+    // Answer every odd number until process B is done
+    let answered = pingpong::pong(shared, WaitStrategy::Spin, 0);
+    println!("Process A done after {} handoffs", answered);
+    println!(
+        "Process A topology: {}, counter on node {}",
+        placement,
+        numa::show(segment.node())
+    );
 }
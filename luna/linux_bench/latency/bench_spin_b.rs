@@ -1,83 +1,51 @@
 // Process B: Opens existing shared memory, increments when even, times the benchmark
 
-use std::env;
-use std::ffi::CString;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::time::Instant;
+use std::time::Duration;
+
+use common::affinity::Placement;
+use common::cli::{Args, USAGE};
+use common::error::OrExit;
+use common::numa;
+use common::pingpong::{self, PingPong};
+use common::shm::ShmSegment;
+use common::wait::WaitStrategy;
+
+// How long to wait for process A to create the segment
+const OPEN_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
     
     if args.len() < 3 {
-        eprintln!("Usage: {} <shared_memory_name> <target_number>", args[0]);
-        std::process::exit(1);
+        eprintln!("Usage: {} <shared_memory_name> <target_number> [--cpu=LIST] [--numa=NODE]", args[0]);
+        std::process::exit(USAGE);
     }
     
     let shm_name = &args[1];
-    let target: u32 = args[2].parse()
-        .expect("Target must be a valid number");
-    
-    // Add '/' prefix if needed
-    let shm_name = if shm_name.starts_with('/') {
-        shm_name.to_string()
-    } else {
-        format!("/{}", shm_name)
-    };
-    
-    // Convert to C string
-    let c_name = CString::new(shm_name.as_bytes()).unwrap();
-    
-    // Open existing shared memory (no O_CREAT flag this time)
-    let fd = unsafe {
-        libc::shm_open(
-            c_name.as_ptr(),
-            libc::O_RDWR,     // just read/write, don't create
-            0o666
-        )
-    };
-    if fd < 0 {
-        panic!("Failed to open shared memory. Is process A running?");
-    }
+    let target: u32 = cli.arg(2, "target_number");
+    let placement = Placement::from_args(&cli, "cpu");
+    placement
+        .apply()
+        .or_exit("Failed to apply placement");
     
-    // Map it into our address space
-    let ptr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),
-            4,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED,
-            fd,
-            0
-        )
-    };
-    
-    if ptr == libc::MAP_FAILED {
-        panic!("Failed to map shared memory");
-    }
-    
-    // Cast to AtomicU32
-    let shared = unsafe { &*(ptr as *const AtomicU32) };
+    // Open the segment process A creates, giving it a moment to appear
+    let segment = ShmSegment::<PingPong>::open_or_wait(shm_name, OPEN_TIMEOUT)
+        .or_exit("Failed to open shared memory (is process A running?)");
+    let shared = segment.header();
     
     println!("Process B ready. Target: {}", target);
     
-    let start = Instant::now();
-    
-    loop {
-        let val = shared.load(Ordering::SeqCst);
-        
-        if val % 2 == 0 {       
-            if val >= target {
-                let elapsed = start.elapsed();
-                
-                println!("\nReached target: {}", val);
-                println!("Total time: {:.3} ms", elapsed.as_secs_f64() * 1000.0);
-                println!("Per handoff: {:.3} ns", elapsed.as_nanos() as f64 / target as f64);
-                
-                std::process::exit(0);
-            }
-            shared.store(val + 1, Ordering::SeqCst);
-        }
-    }
+    // Each round trip is two handoffs: B (even -> odd), then A (odd -> even)
+    let stats = pingpong::ping(shared, WaitStrategy::Spin, 0, target.div_ceil(2), false);
+    pingpong::stop(shared);
+    
+    println!("\nReached target: {}", stats.round_trips * 2);
+    println!("Total time: {:.3} ms", stats.total_ns as f64 / 1e6);
+    println!("Per handoff: {:.3} ns", stats.total_ns as f64 / target as f64);
+    println!(
+        "Topology: {}, counter on node {}",
+        placement,
+        numa::show(segment.node())
+    );
 }
-
-// 4KB, 2MB, 1GB
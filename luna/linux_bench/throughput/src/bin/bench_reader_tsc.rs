@@ -1,79 +1,49 @@
-use std::env;
-use std::ffi::CString;
-use std::sync::atomic::{Ordering, fence};
-use std::ptr;
-use std::mem::size_of;
-use throughput::{ShmHeader, read_tsc};
+use throughput::affinity::Placement;
+use throughput::cli::{Args, USAGE};
+use throughput::checkpoint::{CheckpointOptions, Recorder};
+use throughput::clock::{overhead, Clock, ClockSource};
+use throughput::error::{OrExit, RingError};
+use throughput::{numa, RingConsumer, RingOptions};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
     
     if args.len() < 5 {
-        eprintln!("Usage: {} <shared_mem_name> <share_mem_size> <transfer_size> <read_chunk_size>", args[0]);
-        std::process::exit(1);
+        eprintln!("Usage: {} <shared_mem_name> <share_mem_size> <transfer_size> <read_chunk_size> [--layout=legacy|padded] [--cpu=LIST] [--numa=NODE] [--copy=std|movsb|avx2|avx512|nt] [--clock=tsc|tsc-lfence|tsc-raw|rdtscp|monotonic] [--prefetch=LINES] [--checkpoints=N] [--checkpoint-format=text|csv|json] [--checkpoint-file=PATH]", args[0]);
+        std::process::exit(USAGE);
     }
     
     let shm_name = &args[1];
-    let shm_size: u64 = args[2].parse()
-        .expect("share_mem_size must be a valid number");
-    let transfer_size: u64 = args[3].parse()
-        .expect("transfer_size must be a valid number");
-    let chunk_size: u32 = args[4].parse()
-        .expect("chunk_size must be a valid number");
-    
-    // Add '/' prefix if needed
-    let shm_name = if shm_name.starts_with('/') {
-        shm_name.to_string()
-    } else {
-        format!("/{}", shm_name)
-    };
-    
-    // Convert to C string
-    let c_name = CString::new(shm_name.as_bytes()).unwrap();
+    let shm_size: u64 = cli.arg(2, "share_mem_size");
+    let transfer_size: u64 = cli.arg(3, "transfer_size");
+    let chunk_size: u32 = cli.arg(4, "read_chunk_size");
+    let placement = Placement::from_args(&cli, "cpu");
+    let clock: ClockSource = cli.parsed("clock").unwrap_or_default();
+    placement
+        .apply()
+        .or_exit("Failed to apply placement");
     
     println!("Reader: Waiting for writer to create shared memory...");
     
-    // Open existing shared memory (no O_CREAT flag)
-    let fd = loop {
-        let fd = unsafe {
-            libc::shm_open(
-                c_name.as_ptr(),
-                libc::O_RDWR,
-                0o666
-            )
-        };
-        
-        if fd >= 0 {
-            break fd;
-        }
-        
-        // Wait a bit and retry
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    let options = RingOptions {
+        layout: cli.parsed("layout").unwrap_or_default(),
+        copy: cli.parsed("copy").unwrap_or_default(),
+        prefetch: cli.parsed("prefetch").unwrap_or_default(),
+        ..RingOptions::default()
     };
+    let mut consumer = RingConsumer::open_with(shm_name, shm_size, &options)
+        .or_exit("Failed to map shared memory");
     
     println!("Reader: Shared memory found!");
-    
-    let total_size = size_of::<ShmHeader>() as u64 + shm_size;
-    println!("Writer: ShmHeader size: {}", size_of::<ShmHeader>());
-    
-    let ptr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),
-            total_size as usize,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED,
-            fd,
-            0
-        )
-    };
-    
-    if ptr == libc::MAP_FAILED {
-        panic!("Failed to map shared memory");
-    }
-    
-    // Get pointers to shared variables
-    let header = unsafe { &*(ptr as *mut ShmHeader) };
-    let data_start = unsafe { (ptr as *mut u8).add(size_of::<ShmHeader>()) };
+    // Calibrates the TSC now rather than at the first checkpoint.
+    println!(
+        "Reader: Clock: {} at {:.3} GHz, {}",
+        clock,
+        clock.hz() / 1e9,
+        overhead(&clock)
+    );
+    println!("Writer: ShmHeader size: {}", options.layout.header_size());
     
     // Prepare buffer for reading
     let mut dst = vec![0u8; transfer_size as usize];
@@ -89,88 +59,48 @@ fn main() {
     }
     let mut total_read = 0u64;
 
-    #[cfg(debug_assertions)]
-    let mut xor_checksum: u8 = 0;
-
-    // tsc
-    let ckpt_total_interval = 10;
-    let ckpt_interval_sz = (transfer_size + ckpt_total_interval - 1) / ckpt_total_interval;
-    let mut ckpt_next = ckpt_interval_sz;
+    let mut checkpoints = Recorder::new("Reader", clock, transfer_size, &CheckpointOptions::from_args(&cli));
 
     // Change transfer_started to 1 (signal writer to start)
-    header.transfer_started.store(1, Ordering::Release);
+    consumer.signal_start();
     println!("Reader: Signaled writer to start, waiting for data...");
 
-    eprintln!("--- Reader checkpoint 0/{} tsc: {}", ckpt_total_interval, read_tsc());
+    checkpoints.start();
     
     while total_read < transfer_size {
-        let end_idx = header.end_index.load(Ordering::Acquire);
-        let start_idx = header.start_index.load(Ordering::Acquire);
-        
-        let avail_len = end_idx - start_idx;
-        
-        if avail_len > 0 {        
-            let len = (chunk_size as u64).min(transfer_size - total_read).min(avail_len);
+        let len = (chunk_size as u64).min(transfer_size - total_read) as usize;
+        let read = consumer.read(&mut dst[total_read as usize..][..len]);
 
-            // Calculate read position with wrap-around
-            let read_start = (start_idx % shm_size) as usize;
-            let l = std::cmp::min(len, shm_size - read_start as u64) as usize;
-            
-            unsafe {
-                // First part (until wrap or end of chunk)
-                ptr::copy_nonoverlapping(
-                    data_start.add(read_start),
-                    dst.as_mut_ptr().add(total_read as usize),
-                    l
-                );
-                
-                // Second part (wrapped around to beginning)
-                if l < len as usize {
-                    ptr::copy_nonoverlapping(
-                        data_start,
-                        dst.as_mut_ptr().add(total_read as usize + l),
-                        len as usize - l
-                    );
-                }
-            }
-            
-            // Barrier: smp_wmb() - ensure data reads complete before index update
-            // On x86, this is just a compiler barrier since Store→Store is guaranteed
-            fence(Ordering::Release);
-            
-            header.start_index.store(start_idx + len, Ordering::Relaxed);
-            total_read += len;
+        if read > 0 {
+            total_read += read as u64;
 
-            if total_read > ckpt_next {
-                eprintln!("--- Reader checkpoint {}/{} tsc: {}", ckpt_next / ckpt_interval_sz, 
-                    ckpt_total_interval, read_tsc());
-                ckpt_next += ckpt_interval_sz;
-            }
+            checkpoints.progress(total_read);
         } else {
             std::hint::spin_loop();
         }
     }
 
-    eprintln!("--- Reader checkpoint {}/{} tsc: {}", ckpt_next / ckpt_interval_sz, ckpt_total_interval, read_tsc());
+    checkpoints.finish(total_read);
     println!("Reader: Finished reading {} bytes", total_read);
 
-    header.transfer_started.store(0, Ordering::Relaxed);
+    consumer.signal_done();
+    checkpoints
+        .report()
+        .map_err(|e| RingError::InvalidConfig(e.to_string()))
+        .or_exit("Failed to write checkpoints");
+    println!(
+        "Reader: Topology: {}, ring data on node {}",
+        placement,
+        numa::show(consumer.data_node())
+    );
+    println!("Reader: Copy kernel: {}", consumer.copy_kernel());
+    println!("Reader: Migrations: {} between checkpoints", checkpoints.migrations());
+    println!("Reader: Prefetch: {} cache lines ahead", options.prefetch);
 
     #[cfg(debug_assertions)]
     {
         // println!("{:?}", &dst[0..total_read as usize]);
-        for i in 0..total_read as usize {
-            xor_checksum ^= dst[i];
-        }
+        let xor_checksum = dst[..total_read as usize].iter().fold(0u8, |acc, &b| acc ^ b);
         println!("Reader XOR checksum: 0x{:02X}", xor_checksum);
-    }   
-    
-    // Cleanup
-    unsafe {
-        libc::munmap(ptr, total_size as usize);
-        libc::close(fd);
-        
-        // Remove shared memory
-        libc::shm_unlink(c_name.as_ptr());
     }
 }
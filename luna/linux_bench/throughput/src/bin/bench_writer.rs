@@ -1,84 +1,39 @@
-use std::env;
-use std::ffi::CString;
-use std::sync::atomic::{Ordering, fence};
 use std::time::Instant;
-use std::ptr;
-use std::mem::size_of;
-use throughput::{ShmHeader};
+use throughput::affinity::Placement;
+use throughput::cli::{Args, USAGE};
+use throughput::error::OrExit;
+use throughput::{numa, RingOptions, RingProducer};
 // use rand::RngCore;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
     
     if args.len() < 5 {
-        eprintln!("Usage: {} <shared_mem_name> <share_mem_size> <transfer_size> <write_chunk_size>", args[0]);
-        std::process::exit(1);
+        eprintln!("Usage: {} <shared_mem_name> <share_mem_size> <transfer_size> <write_chunk_size> [--layout=legacy|padded] [--cpu=LIST] [--numa=NODE] [--copy=std|movsb|avx2|avx512|nt]", args[0]);
+        std::process::exit(USAGE);
     }
     
     let shm_name = &args[1];
-    let shm_size: u64 = args[2].parse()
-        .expect("share_mem_size must be a valid number");
-    let transfer_size: u64 = args[3].parse()
-        .expect("transfer_size must be a valid number");
-    let chunk_size: u32 = args[4].parse()
-        .expect("chunk_size must be a valid number");
-    
-    // Add '/' prefix if needed
-    let shm_name = if shm_name.starts_with('/') {
-        shm_name.to_string()
-    } else {
-        format!("/{}", shm_name)
-    };
-    
-    // Convert to C string
-    let c_name = CString::new(shm_name.as_bytes()).unwrap();
-    
-    // Open/create shared memory
-    let fd = unsafe {
-        libc::shm_open(
-            c_name.as_ptr(),
-            libc::O_CREAT | libc::O_RDWR,
-            0o666
-        )
-    };
-    
-    if fd < 0 {
-        panic!("Failed to create shared memory");
-    }
-    
-    let total_size = size_of::<ShmHeader>() as u64 + shm_size;
-    
-    unsafe {
-        libc::ftruncate(fd, total_size as i64);
-    }
-    
-    let ptr = unsafe {
-        libc::mmap(
-            std::ptr::null_mut(),
-            total_size as usize,
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED,
-            fd,
-            0
-        )
+    let shm_size: u64 = cli.arg(2, "share_mem_size");
+    let transfer_size: u64 = cli.arg(3, "transfer_size");
+    let chunk_size: u32 = cli.arg(4, "write_chunk_size");
+    let placement = Placement::from_args(&cli, "cpu");
+    placement
+        .apply()
+        .or_exit("Failed to apply placement");
+    
+    let options = RingOptions {
+        layout: cli.parsed("layout").unwrap_or_default(),
+        numa_node: placement.node,
+        copy: cli.parsed("copy").unwrap_or_default(),
+        ..RingOptions::default()
     };
+    let mut producer = RingProducer::create_with(shm_name, shm_size, &options)
+        .or_exit("Failed to create shared memory");
     
-    if ptr == libc::MAP_FAILED {
-        panic!("Failed to map shared memory");
-    }
-    
-    // Get pointers to shared variables
-    let header = unsafe { &*(ptr as *mut ShmHeader) };
-    let data_start = unsafe { (ptr as *mut u8).add(size_of::<ShmHeader>()) };
-    
-    // Prepare data chunk (all zeros)
-    // let src = vec![0u8; chunk_size as usize];
-
     // Fill with pattern: 1, 2, 3, ..., 255, 1, 2, 3, ...
-    let mut src = vec![0u8; chunk_size as usize];
-    for i in 0..chunk_size as usize {
-        src[i] = ((i % 255) + 1) as u8;
-    }
+    let src: Vec<u8> = (0..chunk_size as usize).map(|i| ((i % 255) + 1) as u8).collect();
     // rand::thread_rng().fill_bytes(&mut src);
 
     let mut total_written = 0u64;
@@ -89,56 +44,22 @@ fn main() {
     println!("Writer: Waiting for reader to start (transfer_started=1)...");
     
     // Wait till reader changes transfer_started to 1
-    while header.transfer_started.load(Ordering::Acquire) == 0 {
-        std::hint::spin_loop();
-    }
+    producer.wait_for_consumer();
     
     println!("Writer: Reader ready, starting write...");
     let start_time = Instant::now();
     
     while total_written < transfer_size {
-        let end_idx = header.end_index.load(Ordering::Acquire);
-        let start_idx = header.start_index.load(Ordering::Acquire);
-        
-        let unused_len = shm_size - (end_idx - start_idx);
+        let len = (chunk_size as u64).min(transfer_size - total_written) as usize;
+        let written = producer.write(&src[..len]);
 
-        if unused_len > 0 {            
-            let len = (chunk_size as u64).min(transfer_size - total_written).min(unused_len);
-            
-            // Calculate write position with wrap-around
-            let write_start = (end_idx % shm_size) as usize;
-            let l = std::cmp::min(len, shm_size - write_start as u64) as usize;
-            
-            unsafe {
-                // First part (until wrap or end of chunk)
-                ptr::copy_nonoverlapping(
-                    src.as_ptr(),
-                    data_start.add(write_start),
-                    l
-                );
-                
-                // Second part (wrapped around to beginning)
-                if l < len as usize {
-                    ptr::copy_nonoverlapping(
-                        src.as_ptr().add(l),
-                        data_start,
-                        len as usize - l
-                    );
-                }
-            }
-            
-            // Barrier: smp_wmb() - ensure data writes complete before index update
-            // On x86, this is just a compiler barrier since Store→Store is guaranteed
-            fence(Ordering::Release);
-            
-            header.end_index.store(end_idx + len, Ordering::Release);
-            total_written += len;
+        if written > 0 {
+            total_written += written as u64;
 
             #[cfg(debug_assertions)]
             {
-                // println!("{:?}", &src[0..len as usize]);
-                for i in 0..len as usize {
-                    xor_checksum ^= src[i];
+                for &b in &src[..written] {
+                    xor_checksum ^= b;
                 }
             }
         } else {
@@ -154,9 +75,7 @@ fn main() {
     println!("Writer: Waiting for reader to finish ...");
     
     // Wait till reader changes transfer_started to 0
-    while header.transfer_started.load(Ordering::Relaxed) != 0 {
-        std::hint::spin_loop();
-    }
+    producer.wait_for_consumer_done();
     
     let elapsed = start_time.elapsed();
     
@@ -167,11 +86,11 @@ fn main() {
     println!("Total time: {} µs, {} s", elapsed.as_micros(), elapsed.as_secs_f64());
     println!("Data written: {} bytes", total_written );
     println!("Throughput: {:.4} GB / s", total_written as f64 / (1024.0 * 1024.0 * 1024.0 * elapsed.as_secs_f64()));
+    println!(
+        "Topology: {}, ring data on node {}",
+        placement,
+        numa::show(producer.data_node())
+    );
+    println!("Copy kernel: {}", producer.copy_kernel());
     println!("========================================");
-    
-    // Cleanup
-    unsafe {
-        libc::munmap(ptr, total_size as usize);
-        libc::close(fd);
-    }
 }
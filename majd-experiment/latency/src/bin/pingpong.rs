@@ -0,0 +1,57 @@
+// Ping-pong with the waiting strategy picked on the command line, so every
+// strategy runs through the same harness (fork, shared counter, timing) as
+// busy / futex / futex_active.
+//
+//   pingpong [--wait=spin|pause|yield|futex|spin-futex] [--all]
+//            [--spin-budget=N] [--iters=N] [--active]
+//            [--ping-cpu=LIST] [--pong-cpu=LIST] [--numa=NODE]
+//
+// --all runs every strategy in turn; --active also reports the time spent
+// in increment + wake only.
+use common::cli::Args;
+use common::error::OrExit;
+use common::wait::{WaitStrategy, DEFAULT_SPIN_BUDGET};
+use latency::{run_forked, Pinning};
+
+const SHM_NAME: &str = "/pp_shm_pingpong";
+const ITERS: u32 = 100_000;
+
+fn main() {
+    let cli = Args::from_env();
+    let iters = cli.parsed("iters").unwrap_or(ITERS);
+    let spin_budget = cli.parsed("spin-budget").unwrap_or(DEFAULT_SPIN_BUDGET);
+    let time_active = cli.flag("active");
+    let pinning = Pinning::from_args(&cli);
+    let strategies = if cli.flag("all") {
+        WaitStrategy::ALL.to_vec()
+    } else {
+        vec![cli.parsed("wait").unwrap_or(WaitStrategy::Futex)]
+    };
+
+    for strategy in strategies {
+        let (stats, topology) =
+            run_forked(SHM_NAME, strategy, spin_budget, iters, time_active, &pinning)
+                .or_exit("shared counter");
+        let label = match strategy {
+            WaitStrategy::SpinThenFutex => format!("{}({})", strategy, spin_budget),
+            _ => strategy.to_string(),
+        };
+        print!("{:<16} avg latency {} ns ({} round-trips)", label, stats.avg_ns(), iters);
+        match (stats.avg_active_ns(), stats.avg_active_net_ns()) {
+            (Some(ns), Some(net)) => println!(
+                ", active {} ns ({} ns without the {:.1} ns clock reading)",
+                ns,
+                net,
+                stats.clock_overhead_ns.unwrap()
+            ),
+            _ => println!(),
+        }
+        println!("{:<16} {}", "", topology);
+        if stats.migrations > 0 {
+            println!(
+                "{:<16} ping side changed CPU {} times, {} active samples dropped",
+                "", stats.migrations, stats.discarded
+            );
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// Fork-based harness for the ping-pong latency experiments. Maps a page of
+// shared memory, forks, and runs the two sides of `common::pingpong` with
+// the chosen waiting strategy:
+//   parent: ping (even -> odd), timed, then stop
+//   child:  pong (odd -> even) until stopped
+//
+// Each side pins itself after the fork (`--ping-cpu`, `--pong-cpu`), so the
+// child never inherits the parent's CPUs, and `--numa` binds the shared page
+// and both sides' memory to one node. The parent only starts pinging once
+// the child reported its placement in the shared page, and fails instead if
+// the child could not place itself.
+use common::affinity::{CpuList, Placement};
+use common::cli::Args;
+use common::numa;
+use common::pingpong::{self, PingPong, Stats};
+use common::error::{OrExit, RingError};
+use common::shm::{Plain, ShmSegment};
+use common::wait::WaitStrategy;
+use libc::*;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+const PAGE: usize = 4096;
+
+#[repr(C)]
+struct Shared {
+    pingpong: PingPong,
+    // Where the child was running when it was stopped.
+    pong_cpu: AtomicU32,
+    // `PONG_PLACED` or `PONG_FAILED` once the child applied its placement.
+    pong_setup: AtomicU32,
+}
+
+const PONG_PLACED: u32 = 1;
+const PONG_FAILED: u32 = 2;
+
+unsafe impl Plain for Shared {}
+
+/// Where each side is asked to run, from `--ping-cpu=LIST`,
+/// `--pong-cpu=LIST` and `--numa=NODE`.
+#[derive(Debug, Clone, Default)]
+pub struct Pinning {
+    pub ping: Placement,
+    pub pong: Placement,
+}
+
+impl Pinning {
+    pub fn from_args(cli: &Args) -> Self {
+        Pinning {
+            ping: Placement::from_args(cli, "ping-cpu"),
+            pong: Placement::from_args(cli, "pong-cpu"),
+        }
+    }
+}
+
+/// Where a run actually happened, for the results line.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    pub ping_cpus: Option<CpuList>,
+    pub pong_cpus: Option<CpuList>,
+    pub ping_cpu: usize,
+    pub pong_cpu: usize,
+    pub counter_node: Option<usize>,
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let side = |f: &mut fmt::Formatter<'_>, name, cpus: &Option<CpuList>, cpu| {
+            match cpus {
+                Some(cpus) => write!(f, "{} cpus {} ran on {}", name, cpus, cpu)?,
+                None => write!(f, "{} ran on {}", name, cpu)?,
+            }
+            match numa::node_of_cpu(cpu) {
+                Some(node) => write!(f, " (node {})", node),
+                None => Ok(()),
+            }
+        };
+        side(f, "ping", &self.ping_cpus, self.ping_cpu)?;
+        f.write_str(", ")?;
+        side(f, "pong", &self.pong_cpus, self.pong_cpu)?;
+        match self.counter_node {
+            Some(node) => write!(f, ", counter on node {}", node),
+            None => Ok(()),
+        }
+    }
+}
+
+// Waits until the child `pid` has placed itself; reaps it if it failed.
+unsafe fn wait_for_pong(shared: &Shared, pid: pid_t) -> Result<(), RingError> {
+    loop {
+        match shared.pong_setup.load(Ordering::Acquire) {
+            PONG_PLACED => return Ok(()),
+            // The child said why on stderr.
+            PONG_FAILED => {
+                let _ = waitpid(pid, ptr::null_mut(), 0);
+                return Err(RingError::InvalidConfig(
+                    "pong could not apply its placement".to_string(),
+                ))
+            }
+            _ => {}
+        }
+        if waitpid(pid, ptr::null_mut(), WNOHANG) == pid {
+            return Err(RingError::InvalidConfig(
+                "pong exited before it started".to_string(),
+            ));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
+
+/// Runs `iters` round trips between a parent and a forked child that both
+/// wait with `strategy`, and returns the parent's timings and where both
+/// sides ran, or why the shared counter could not be set up.
+pub fn run_forked(
+    shm_name: &str,
+    strategy: WaitStrategy,
+    spin_budget: u32,
+    iters: u32,
+    time_active: bool,
+    pinning: &Pinning,
+) -> Result<(Stats, Topology), RingError> {
+    // Unlinked when dropped; the child leaves with `exit`, which skips it.
+    let segment = ShmSegment::<Shared>::create_len(shm_name, PAGE)?;
+    if let Some(node) = pinning.ping.node {
+        segment.bind(node)?;
+    }
+    unsafe {
+        ptr::write(
+            segment.as_ptr(),
+            Shared {
+                pingpong: PingPong::default(),
+                pong_cpu: AtomicU32::new(0),
+                pong_setup: AtomicU32::new(0),
+            },
+        );
+    }
+    let shared = segment.header();
+
+    unsafe {
+        let pid = fork();
+        if pid < 0 {
+            return Err(RingError::InvalidConfig(format!(
+                "fork: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+
+        if pid == 0 {
+            if let Err(e) = pinning.pong.apply() {
+                shared.pong_setup.store(PONG_FAILED, Ordering::Release);
+                Err::<(), _>(e).or_exit("pong: placement");
+            }
+            shared.pong_setup.store(PONG_PLACED, Ordering::Release);
+            pingpong::pong(&shared.pingpong, strategy, spin_budget);
+            shared
+                .pong_cpu
+                .store(numa::current_cpu() as u32, Ordering::Relaxed);
+            std::process::exit(0);
+        }
+
+        if let Err(e) = pinning.ping.apply() {
+            kill(pid, SIGKILL);
+            let _ = waitpid(pid, ptr::null_mut(), 0);
+            return Err(e);
+        }
+        // Pinging a child that never got going would wait forever.
+        wait_for_pong(shared, pid)?;
+        let stats = pingpong::ping(&shared.pingpong, strategy, spin_budget, iters, time_active);
+        let ping_cpu = numa::current_cpu();
+        pingpong::stop(&shared.pingpong);
+
+        let _ = waitpid(pid, ptr::null_mut(), 0);
+        let topology = Topology {
+            ping_cpus: pinning.ping.cpus.clone(),
+            pong_cpus: pinning.pong.cpus.clone(),
+            ping_cpu,
+            pong_cpu: shared.pong_cpu.load(Ordering::Relaxed) as usize,
+            counter_node: segment.node().ok(),
+        };
+        Ok((stats, topology))
+    }
+}
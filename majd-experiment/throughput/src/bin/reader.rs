@@ -1,14 +1,28 @@
-use libc::*;
-use std::ptr;
-use std::sync::atomic::{fence, Ordering};
+use common::affinity::Placement;
+use common::cli::{Args, USAGE};
+use common::checkpoint::{CheckpointOptions, Recorder};
+use common::clock::{Clock, ClockSource};
+use common::copy::CopyKernel;
+use common::error::{OrExit, RingError};
+use common::numa;
+use common::prefault::{Faults, Prefault};
+use common::sampler::SampleOptions;
+use common::shm::ShmSegment;
+use libc::c_void;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::Ordering;
 use std::time::Instant;
-use throughput::{Shared, BUF_SIZE};
+use throughput::{check_shared, run_reader_loop_with_progress, wait_for_total_bytes, xor, Shared};
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
     if args.len() < 2 {
-        eprintln!("usage: {} <shm_name> [size_mb]", args[0]);
-        std::process::exit(2);
+        eprintln!(
+            "usage: {} <shm_name> [size_mb] [--cpu=LIST] [--numa=NODE] [--populate] [--mlock] [--warm] [--copy=std|movsb|avx2|avx512|nt] [--clock=tsc|tsc-lfence|tsc-raw|rdtscp|monotonic] [--checkpoints=N] [--checkpoint-format=text|csv|json] [--checkpoint-file=PATH] [--sample-us=N] [--sample-format=text|csv|json] [--sample-file=PATH]",
+            args[0]
+        );
+        std::process::exit(USAGE);
     }
 
     let shm_name = &args[1];
@@ -16,105 +30,151 @@ fn main() {
         .get(2)
         .and_then(|s| s.parse::<u64>().ok())
         .map(|mb| mb * 1024 * 1024);
-    let interval: u64 = 10_000_000; // Record every 10 million bytes
-    let mut next_milestone = interval;
-    let mut records = Vec::new();
-
-    unsafe {
-        let name = std::ffi::CString::new(shm_name.as_str()).unwrap();
-        let shm_size = std::mem::size_of::<Shared>();
-        let fd = shm_open(name.as_ptr(), O_RDWR, 0o666);
-        if fd < 0 { panic!("SHM failed. Run writer first."); }
+    let placement = Placement::from_args(&cli, "cpu");
+    let prefault = Prefault::from_args(&cli);
+    let copy: CopyKernel = cli.parsed("copy").unwrap_or_default();
+    copy.check().or_exit("reader");
+    let clock: ClockSource = cli.parsed("clock").unwrap_or_default();
+    let checkpoint_options = CheckpointOptions::from_args(&cli);
+    // The table below is always text; a format only applies to the file.
+    if checkpoint_options.file.is_none() && cli.value("checkpoint-format").is_some() {
+        Err::<(), _>(RingError::InvalidConfig(
+            "--checkpoint-format needs --checkpoint-file".to_string(),
+        ))
+        .or_exit("reader");
+    }
+    placement.apply().or_exit("reader: placement");
 
-        let map = mmap(ptr::null_mut(), shm_size, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
-        let shm = map as *mut Shared;
+    let segment = ShmSegment::<Shared>::open(shm_name).or_exit("reader (run the writer first)");
+    unsafe { prefault.mapping(segment.addr(), segment.len(), false) }
+        .map_err(|source| RingError::Map {
+            name: shm_name.to_string(),
+            source,
+        })
+        .or_exit("reader: prefault");
+    let shm = segment.as_ptr();
 
-        while (*shm).total_bytes.load(Ordering::Relaxed) == 0 { std::hint::spin_loop(); }
-        let total_bytes = (*shm).total_bytes.load(Ordering::Relaxed);
+    unsafe {
+        let total_bytes = wait_for_total_bytes(shm);
+        let mismatch = |reason| RingError::LayoutMismatch {
+            name: shm_name.to_string(),
+            reason,
+        };
+        check_shared(shm).map_err(mismatch).or_exit("reader");
 
         if let Some(expected_bytes) = cli_total_bytes {
             if expected_bytes != total_bytes {
-                eprintln!(
-                    "reader: size mismatch (CLI: {} MiB, shared memory: {} MiB)",
+                Err::<(), _>(mismatch(format!(
+                    "size mismatch (CLI: {} MiB, shared memory: {} MiB)",
                     expected_bytes / (1024 * 1024),
                     total_bytes / (1024 * 1024),
-                );
-                std::process::exit(1);
+                )))
+                .or_exit("reader");
             }
         }
         let check_mode = (*shm).check_mode.load(Ordering::Relaxed) == 1;
 
         // PRE-ZERO the full sink to ensure no lazy allocation jitter
         let mut sink = vec![0u8; total_bytes as usize];
-        sink.fill(0); 
-        let sink_ptr = sink.as_mut_ptr();
+        (Prefault { warm: true, ..prefault })
+            .buffer(&mut sink)
+            .map_err(|source| RingError::Map {
+                name: "the sink".to_string(),
+                source,
+            })
+            .or_exit("reader: prefault");
+
+        let mut checkpoints = Recorder::new("Reader", clock, total_bytes, &checkpoint_options);
+        // Calibrates the TSC now, outside the timed part.
+        let mut sampler = SampleOptions::from_args(&cli).sampler("Reader", clock);
 
-        let mut running_xor: u8 = 0;
-        
         // Timer starts right before signaling the writer
+        let faults = Faults::now();
+        if let Some(sampler) = &mut sampler {
+            sampler.start();
+        }
+        checkpoints.start();
         let start = Instant::now();
         (*shm).start_signal.store(1, Ordering::Release);
 
-        let mut consumed: u64 = 0;
-
-        while consumed < total_bytes {
-            let w = (*shm).write_pos.load(Ordering::Acquire);
-            let r = (*shm).read_pos.load(Ordering::Relaxed);
-            let avail = w.wrapping_sub(r);
-
-            if avail == 0 {
-                if (*shm).done.load(Ordering::Relaxed) != 0 { break; }
-                std::hint::spin_loop();
-                continue;
-            }
-
-            let n = avail.min(total_bytes - consumed) as usize;
-            let off = (r as usize) & (BUF_SIZE - 1);
-            let first = n.min(BUF_SIZE - off);
-
-            ptr::copy_nonoverlapping((*shm).buffer.as_ptr().add(off), sink_ptr.add(consumed as usize), first);
-            if first < n {
-                ptr::copy_nonoverlapping((*shm).buffer.as_ptr(), sink_ptr.add(consumed as usize + first), n - first);
-            }
-
-            if check_mode {
-                for i in 0..n {
-                    running_xor ^= *sink_ptr.add(consumed as usize + i);
-                }
-            }
-
-            fence(Ordering::Release);
-            (*shm).read_pos.store(r + n as u64, Ordering::Relaxed);
-            consumed += n as u64;
-
-            // Log milestones every 10 million bytes
-            while consumed >= next_milestone && next_milestone <= total_bytes {
-                records.push((next_milestone, start.elapsed()));
-                next_milestone += interval;
+        let result = run_reader_loop_with_progress(shm, &mut sink, total_bytes, copy, |consumed| {
+            checkpoints.progress(consumed);
+            if let Some(sampler) = &mut sampler {
+                sampler.progress(consumed);
             }
+        });
+        let consumed = result.bytes_read;
+        checkpoints.finish(consumed);
+        if let Some(sampler) = &mut sampler {
+            sampler.finish(consumed);
         }
 
         let total_time = start.elapsed().as_secs_f64();
+        let faults = Faults::since(faults);
 
         // --- Final Report ---
         println!("\n{:<15} {:<15} {:<15}", "Bytes", "Time (s)", "Gb/s");
-        for (b, t) in &records {
-            let s = t.as_secs_f64();
-            println!("{:<15} {:<15.6} {:<15.2}", b, s, (*b as f64 * 8.0) / (s * 1e9));
+        if let Some((first, rest)) = checkpoints.checkpoints().split_first() {
+            for p in rest {
+                let s = clock.to_ns(p.stamp.ticks - first.stamp.ticks) as f64 / 1e9;
+                println!("{:<15} {:<15.6} {:<15.2}", p.bytes, s, (p.bytes as f64 * 8.0) / (s * 1e9));
+            }
         }
         
         println!("{:-<45}", "");
         println!("{:<15} {:<15.6} {:<15.2} (TOTAL)", consumed, total_time, (consumed as f64 * 8.0) / (total_time * 1e9));
+        println!(
+            "MiB/s: {:.2}",
+            consumed as f64 / (1024.0 * 1024.0) / total_time
+        );
+
+        println!("Page faults: {} (prefault: {})", faults, prefault);
+        println!("Copy kernel: {}", copy);
+        println!("Migrations: {} between checkpoints", checkpoints.migrations());
+        // The table above is the text form; the file gets what was asked for.
+        if checkpoint_options.file.is_some() {
+            if let Err(e) = checkpoints.report() {
+                eprintln!("reader: failed to write checkpoints: {}", e);
+            }
+        }
+        if let Some(sampler) = &sampler {
+            println!(
+                "Samples: {} of {} ns, {} without progress",
+                sampler.samples().len(),
+                clock.to_ns(sampler.quantum()),
+                sampler.stalls()
+            );
+            if let Err(e) = sampler.report() {
+                eprintln!("reader: failed to write samples: {}", e);
+            }
+        }
+
+        if result.aborted {
+            println!("❌ Writer aborted after {} of {} bytes", consumed, total_bytes);
+        }
 
-        if check_mode {
-            let expected = (*shm).expected_xor.load(Ordering::Relaxed);
+        // Hash outside the timed section.
+        let digest = Sha256::digest(&sink[..consumed as usize]);
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("SHA256: {}", hex);
+        if check_mode && !result.aborted {
+            // The last bytes can arrive before the writer stored their XOR.
+            while (*shm).done.load(Ordering::Acquire) == 0 {
+                std::hint::spin_loop();
+            }
+            let expected = (*shm).expected_xor.load(Ordering::Acquire);
+            let running_xor = xor(&sink[..consumed as usize]);
             if running_xor == expected {
                 println!("✅ Verification Success (XOR {:#04x})", running_xor);
             } else {
                 println!("❌ Verification Failed! Expected {:#04x}, got {:#04x}", expected, running_xor);
             }
         }
-        
-        munmap(map, shm_size);
+        println!(
+            "Topology: {}, ring on node {}, sink on node {}",
+            placement,
+            numa::show(segment.node()),
+            numa::show(numa::node_of_addr(sink.as_ptr() as *const c_void))
+        );
     }
-}
\ No newline at end of file
+}
@@ -1,30 +1,49 @@
-use libc::*;
-use std::ptr;
-use std::sync::atomic::{fence, Ordering};
-use throughput::{init_shared, Shared, BUF_SIZE};
+use common::affinity::Placement;
+use common::cli::{Args, USAGE};
+use common::error::{OrExit, RingError};
+use common::numa;
+use common::prefault::{Faults, Prefault};
+use common::shm::ShmSegment;
+use std::sync::atomic::Ordering;
+use throughput::{init_shared, run_writer_loop, Shared};
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let cli = Args::from_env();
+    let args = cli.positional();
     if args.len() < 2 {
-        eprintln!("usage: {} <shm_name> [size_mb] [--check]", args[0]);
-        std::process::exit(2);
+        eprintln!(
+            "usage: {} <shm_name> [size_mb] [--cpu=LIST] [--numa=NODE] [--populate] [--mlock] [--warm] [--check]",
+            args[0]
+        );
+        std::process::exit(USAGE);
     }
 
     let shm_name = &args[1];
     let total_bytes: u64 = args.get(2)
         .and_then(|s| s.parse::<u64>().ok())
         .unwrap_or(100) * 1024 * 1024;
-    let check_mode = args.contains(&"--check".to_string());
+    let check_mode = cli.flag("check");
+    let placement = Placement::from_args(&cli, "cpu");
+    let prefault = Prefault::from_args(&cli);
+    placement.apply().or_exit("writer: placement");
 
-    unsafe {
-        let name = std::ffi::CString::new(shm_name.as_str()).unwrap();
-        let shm_size = std::mem::size_of::<Shared>();
-
-        let fd = shm_open(name.as_ptr(), O_CREAT | O_RDWR, 0o666);
-        ftruncate(fd, shm_size as i64);
-        let map = mmap(ptr::null_mut(), shm_size, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
-        let shm = map as *mut Shared;
+    // Unmapped and unlinked when dropped, also on a panic.
+    let segment = ShmSegment::<Shared>::create(shm_name).or_exit("writer");
+    // Before init_shared touches the pages, so they land on the node.
+    if let Some(node) = placement.node {
+        segment.bind(node).or_exit("writer: mbind");
+    }
+    // After the bind for the same reason; MADV_POPULATE_WRITE rather than
+    // MAP_POPULATE.
+    unsafe { prefault.mapping(segment.addr(), segment.len(), false) }
+        .map_err(|source| RingError::Map {
+            name: shm_name.to_string(),
+            source,
+        })
+        .or_exit("writer: prefault");
+    let shm = segment.as_ptr();
 
+    unsafe {
         init_shared(shm, total_bytes, check_mode);
 
         println!("Writer ready. Waiting for Reader signal...");
@@ -32,42 +51,15 @@ fn main() {
             std::hint::spin_loop();
         }
 
-        let mut produced: u64 = 0;
-        let mut running_xor: u8 = 0;
-        let local_data = vec![0xABu8; 1024 * 1024]; 
-
-        while produced < total_bytes {
-            let r = (*shm).read_pos.load(Ordering::Acquire);
-            let w = (*shm).write_pos.load(Ordering::Relaxed);
-            let used = w.wrapping_sub(r);
-
-            if used as usize >= BUF_SIZE {
-                std::hint::spin_loop();
-                continue;
-            }
-
-            let n = ((BUF_SIZE as u64 - used).min(total_bytes - produced).min(local_data.len() as u64)) as usize;
-            
-            if check_mode {
-                for i in 0..n {
-                    running_xor ^= ((produced + i as u64) % 256) as u8;
-                }
-            }
-
-            let off = (w as usize) & (BUF_SIZE - 1);
-            let first = n.min(BUF_SIZE - off);
-            ptr::copy_nonoverlapping(local_data.as_ptr(), (*shm).buffer.as_mut_ptr().add(off), first);
-            if first < n {
-                ptr::copy_nonoverlapping(local_data.as_ptr(), (*shm).buffer.as_mut_ptr(), n - first);
-            }
-
-            fence(Ordering::Release);
-            (*shm).write_pos.store(w + n as u64, Ordering::Relaxed);
-            produced += n as u64;
-        }
-
-        if check_mode { (*shm).expected_xor.store(running_xor, Ordering::Relaxed); }
-        (*shm).done.store(1, Ordering::Release);
-        munmap(map, shm_size);
+        // Returns once the reader has consumed everything.
+        let faults = Faults::now();
+        run_writer_loop(shm, total_bytes);
+        let faults = Faults::since(faults);
+        println!(
+            "Writer topology: {}, ring on node {}",
+            placement,
+            numa::show(segment.node())
+        );
+        println!("Writer page faults: {} (prefault: {})", faults, prefault);
     }
-}
\ No newline at end of file
+}
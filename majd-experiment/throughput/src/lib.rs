@@ -1,27 +1,394 @@
+use common::copy::CopyKernel;
+use std::ptr;
+use std::slice;
 use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, Ordering, fence};
 
 pub const BUF_SIZE: usize = 4 * 1024 * 1024;
 
+/// "ARCATPUT" in little-endian ASCII; tells this layout apart from other
+/// segments (e.g. the `common` ring, which starts with "ARCARING").
+pub const SHARED_MAGIC: u64 = u64::from_le_bytes(*b"ARCATPUT");
+
+/// Bumped whenever the layout of `Shared` changes.
+pub const SHARED_VERSION: u32 = 2;
+
+/// Largest chunk the writer publishes with a single `write_pos` update.
+const WRITE_CHUNK: usize = 1024 * 1024;
+
 #[repr(C)]
 pub struct Shared {
+    pub magic: u64,
+    pub version: u32,
+    /// `size_of::<Shared>()` as seen by the writer.
+    pub size: u32,
     pub total_bytes: AtomicU64,
     pub read_pos: AtomicU64,
     pub write_pos: AtomicU64,
     pub done: AtomicI32,
+    pub abort: AtomicI32,
     pub start_signal: AtomicI32,
+    /// 1 if the writer runs with `--check`: it sends `check_byte`s instead
+    /// of zeros and leaves their XOR in `expected_xor` before `done`.
     pub check_mode: AtomicI32,
     pub expected_xor: AtomicU8,
     pub buffer: [u8; BUF_SIZE],
 }
 
+/// What the reader loop ended with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadResult {
+    /// Bytes copied into the sink (equals the final `read_pos`).
+    pub bytes_read: u64,
+    /// The writer set `abort` before `total_bytes` were received.
+    pub aborted: bool,
+}
+
+/// Resets every control field, then publishes `total_bytes` last so a reader
+/// that sees it non-zero also sees the rest initialised.
+///
+/// # Safety
+/// `shm` must point to a mapped, writable `Shared`.
 pub unsafe fn init_shared(shm: *mut Shared, total_bytes: u64, check_mode: bool) {
+    (*shm).magic = SHARED_MAGIC;
+    (*shm).version = SHARED_VERSION;
+    (*shm).size = std::mem::size_of::<Shared>() as u32;
     (*shm).read_pos.store(0, Ordering::Relaxed);
     (*shm).write_pos.store(0, Ordering::Relaxed);
     (*shm).done.store(0, Ordering::Relaxed);
+    (*shm).abort.store(0, Ordering::Relaxed);
     (*shm).start_signal.store(0, Ordering::Relaxed);
     (*shm).expected_xor.store(0, Ordering::Relaxed);
-    (*shm).check_mode.store(if check_mode { 1 } else { 0 }, Ordering::Relaxed);
-    
+    (*shm).check_mode.store(check_mode as i32, Ordering::Relaxed);
+
     fence(Ordering::Release);
-    (*shm).total_bytes.store(total_bytes, Ordering::Relaxed);
-}
\ No newline at end of file
+    (*shm).total_bytes.store(total_bytes, Ordering::Release);
+}
+
+/// Spins until the writer has published a non-zero `total_bytes`.
+///
+/// # Safety
+/// `shm` must point to a mapped `Shared`.
+pub unsafe fn wait_for_total_bytes(shm: *const Shared) -> u64 {
+    loop {
+        let total_bytes = (*shm).total_bytes.load(Ordering::Acquire);
+        if total_bytes != 0 {
+            return total_bytes;
+        }
+        std::hint::spin_loop();
+    }
+}
+
+/// Checks that the region was initialised by a writer using this exact
+/// layout. Call after `wait_for_total_bytes`.
+///
+/// # Safety
+/// `shm` must point to a mapped `Shared`.
+pub unsafe fn check_shared(shm: *const Shared) -> Result<(), String> {
+    let magic = ptr::addr_of!((*shm).magic).read();
+    if magic != SHARED_MAGIC {
+        return Err(format!(
+            "not a throughput segment: magic {:#018x}, expected {:#018x}",
+            magic, SHARED_MAGIC
+        ));
+    }
+    let version = ptr::addr_of!((*shm).version).read();
+    if version != SHARED_VERSION {
+        return Err(format!("layout version {}, expected {}", version, SHARED_VERSION));
+    }
+    let size = ptr::addr_of!((*shm).size).read() as usize;
+    if size != std::mem::size_of::<Shared>() {
+        return Err(format!(
+            "writer's Shared is {} bytes, ours is {}",
+            size,
+            std::mem::size_of::<Shared>()
+        ));
+    }
+    Ok(())
+}
+
+/// The byte at position `pos` of a `--check` transfer.
+pub fn check_byte(pos: u64) -> u8 {
+    (pos % 256) as u8
+}
+
+/// XOR of all of `bytes`, as the reader checks `expected_xor`.
+pub fn xor(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |acc, &b| acc ^ b)
+}
+
+/// Writes `total_bytes` zero bytes (`check_byte`s in check mode) through the
+/// ring, sets `done`, then waits until the reader has consumed everything
+/// (or someone set `abort`).
+///
+/// # Safety
+/// `shm` must point to a mapped `Shared` initialised with `init_shared`, and
+/// this must be the only writer.
+pub unsafe fn run_writer_loop(shm: *mut Shared, total_bytes: u64) {
+    let buffer = ptr::addr_of_mut!((*shm).buffer) as *mut u8;
+    let check_mode = (*shm).check_mode.load(Ordering::Relaxed) != 0;
+    let mut running_xor: u8 = 0;
+    let mut written: u64 = 0;
+
+    while written < total_bytes {
+        if (*shm).abort.load(Ordering::Acquire) != 0 {
+            return;
+        }
+
+        let r = (*shm).read_pos.load(Ordering::Acquire);
+        let w = (*shm).write_pos.load(Ordering::Relaxed);
+        let used = w.wrapping_sub(r) as usize;
+
+        if used >= BUF_SIZE {
+            std::hint::spin_loop();
+            continue;
+        }
+
+        let to_write = (BUF_SIZE - used)
+            .min((total_bytes - written) as usize)
+            .min(WRITE_CHUNK);
+
+        let base = (w as usize) & (BUF_SIZE - 1);
+        let first = to_write.min(BUF_SIZE - base);
+        if check_mode {
+            for i in 0..to_write {
+                let byte = check_byte(w + i as u64);
+                *buffer.add((base + i) & (BUF_SIZE - 1)) = byte;
+                running_xor ^= byte;
+            }
+        } else {
+            ptr::write_bytes(buffer.add(base), 0, first);
+            if first < to_write {
+                ptr::write_bytes(buffer, 0, to_write - first);
+            }
+        }
+
+        (*shm).write_pos.store(w + to_write as u64, Ordering::Release);
+        written += to_write as u64;
+    }
+
+    if check_mode {
+        (*shm).expected_xor.store(running_xor, Ordering::Relaxed);
+    }
+    (*shm).done.store(1, Ordering::Release);
+
+    while (*shm).read_pos.load(Ordering::Acquire) < total_bytes {
+        if (*shm).abort.load(Ordering::Acquire) != 0 {
+            return;
+        }
+        std::hint::spin_loop();
+    }
+}
+
+/// Reads the published `total_bytes` into `sink`.
+///
+/// # Safety
+/// `shm` must point to a mapped `Shared` whose `total_bytes` is published and
+/// at most `sink.len()`, and this must be the only reader.
+pub unsafe fn run_reader_loop(shm: *mut Shared, sink: &mut [u8]) -> ReadResult {
+    let total_bytes = (*shm).total_bytes.load(Ordering::Acquire);
+    run_reader_loop_given_total(shm, sink, total_bytes)
+}
+
+/// Like `run_reader_loop`, but with `total_bytes` supplied by the caller, so
+/// a zero-byte transfer does not have to wait for a non-zero publish.
+///
+/// # Safety
+/// Same as `run_reader_loop`; `total_bytes` must be at most `sink.len()`.
+pub unsafe fn run_reader_loop_given_total(
+    shm: *mut Shared,
+    sink: &mut [u8],
+    total_bytes: u64,
+) -> ReadResult {
+    run_reader_loop_with_progress(shm, sink, total_bytes, CopyKernel::Std, |_| {})
+}
+
+/// The reader loop itself, copying out of the ring with `copy`.
+/// `on_progress` is called with the new `read_pos` after every chunk, e.g.
+/// to record milestones.
+///
+/// # Safety
+/// Same as `run_reader_loop_given_total`.
+pub unsafe fn run_reader_loop_with_progress(
+    shm: *mut Shared,
+    sink: &mut [u8],
+    total_bytes: u64,
+    copy: CopyKernel,
+    mut on_progress: impl FnMut(u64),
+) -> ReadResult {
+    assert!(total_bytes <= sink.len() as u64, "sink smaller than total_bytes");
+
+    let buffer = ptr::addr_of!((*shm).buffer) as *const u8;
+    let mut read_pos = (*shm).read_pos.load(Ordering::Relaxed);
+
+    while read_pos < total_bytes {
+        let w = (*shm).write_pos.load(Ordering::Acquire);
+        let available = w.wrapping_sub(read_pos);
+
+        if available == 0 {
+            if (*shm).abort.load(Ordering::Acquire) != 0 {
+                return ReadResult { bytes_read: read_pos, aborted: true };
+            }
+            if (*shm).done.load(Ordering::Acquire) != 0 {
+                // Re-check: the last chunk may have landed after our load.
+                if (*shm).write_pos.load(Ordering::Acquire) == read_pos {
+                    break;
+                }
+                continue;
+            }
+            std::hint::spin_loop();
+            continue;
+        }
+
+        // Never trust write_pos beyond total_bytes: the sink ends there.
+        let to_read = available.min(total_bytes - read_pos) as usize;
+        let base = (read_pos as usize) & (BUF_SIZE - 1);
+        let first = to_read.min(BUF_SIZE - base);
+        let dst = &mut sink[read_pos as usize..][..to_read];
+
+        copy.copy(&mut dst[..first], slice::from_raw_parts(buffer.add(base), first));
+        if first < to_read {
+            copy.copy(&mut dst[first..], slice::from_raw_parts(buffer, to_read - first));
+        }
+
+        read_pos += to_read as u64;
+        (*shm).read_pos.store(read_pos, Ordering::Release);
+        on_progress(read_pos);
+    }
+
+    ReadResult { bytes_read: read_pos, aborted: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Raw pointer wrapper so the writer thread can share the region.
+    #[derive(Clone, Copy)]
+    struct ShmPtr(*mut Shared);
+    unsafe impl Send for ShmPtr {}
+
+    fn new_shared() -> Box<Shared> {
+        // All-zero is a valid `Shared`: atomics at 0 and a zeroed buffer.
+        unsafe { Box::<Shared>::new_zeroed().assume_init() }
+    }
+
+    // Runs the writer in a thread and the reader here, like the two binaries.
+    fn transfer(total_bytes: u64) -> (ReadResult, Vec<u8>) {
+        transfer_checked(total_bytes, false).0
+    }
+
+    // Also hands back `expected_xor` once the writer is done.
+    fn transfer_checked(total_bytes: u64, check_mode: bool) -> ((ReadResult, Vec<u8>), u8) {
+        let mut shm = new_shared();
+        let p = ShmPtr(&mut *shm);
+        unsafe { init_shared(p.0, total_bytes, check_mode) };
+
+        let writer = thread::spawn(move || {
+            let p = p;
+            unsafe { run_writer_loop(p.0, total_bytes) }
+        });
+
+        let mut sink = vec![0xFFu8; total_bytes as usize];
+        let result = unsafe { run_reader_loop_given_total(p.0, &mut sink, total_bytes) };
+        writer.join().unwrap();
+        let expected_xor = shm.expected_xor.load(Ordering::Acquire);
+        ((result, sink), expected_xor)
+    }
+
+    fn assert_complete(total_bytes: u64) {
+        let (result, sink) = transfer(total_bytes);
+        assert_eq!(result, ReadResult { bytes_read: total_bytes, aborted: false });
+        assert!(sink.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn total_bytes_zero() {
+        let mut shm = new_shared();
+        unsafe { init_shared(&mut *shm, 0, false) };
+        let result = unsafe { run_reader_loop_given_total(&mut *shm, &mut [], 0) };
+        assert_eq!(result, ReadResult { bytes_read: 0, aborted: false });
+    }
+
+    #[test]
+    fn one_byte() {
+        assert_complete(1);
+    }
+
+    #[test]
+    fn three_bytes() {
+        assert_complete(3);
+    }
+
+    #[test]
+    fn full_transfer() {
+        assert_complete(10_000);
+    }
+
+    #[test]
+    fn exactly_one_buffer() {
+        assert_complete(BUF_SIZE as u64);
+    }
+
+    #[test]
+    fn larger_than_one_buffer() {
+        assert_complete(3 * BUF_SIZE as u64 + 12_345);
+    }
+
+    #[test]
+    fn buffer_boundary_wrap() {
+        assert_complete(BUF_SIZE as u64 + 1);
+    }
+
+    #[test]
+    fn check_mode_sends_pattern_and_its_xor() {
+        let total_bytes = BUF_SIZE as u64 + 1001;
+        let ((result, sink), expected_xor) = transfer_checked(total_bytes, true);
+        assert_eq!(result, ReadResult { bytes_read: total_bytes, aborted: false });
+        assert!(sink.iter().enumerate().all(|(i, &b)| b == check_byte(i as u64)));
+        assert_eq!(xor(&sink), expected_xor);
+        // 1001 bytes past whole 256-byte periods, which XOR to 0.
+        assert_eq!(expected_xor, xor(&sink[..1001]));
+        assert_ne!(expected_xor, 0);
+    }
+
+    #[test]
+    fn abort_immediate() {
+        let mut shm = new_shared();
+        let mut sink = vec![0u8; 100];
+        let result = unsafe {
+            init_shared(&mut *shm, 100, false);
+            shm.abort.store(1, Ordering::Release);
+            run_reader_loop(&mut *shm, &mut sink)
+        };
+        assert_eq!(result, ReadResult { bytes_read: 0, aborted: true });
+    }
+
+    #[test]
+    fn done_with_partial_data() {
+        let mut shm = new_shared();
+        let mut sink = vec![0u8; 100];
+        let result = unsafe {
+            init_shared(&mut *shm, 100, false);
+            shm.write_pos.store(50, Ordering::Release);
+            shm.done.store(1, Ordering::Release);
+            run_reader_loop(&mut *shm, &mut sink)
+        };
+        assert_eq!(result, ReadResult { bytes_read: 50, aborted: false });
+        assert_eq!(shm.read_pos.load(Ordering::Relaxed), 50);
+    }
+
+    #[test]
+    fn write_pos_exceeds_total_bytes_no_overflow() {
+        let mut shm = new_shared();
+        let mut sink = vec![0u8; 100];
+        let result = unsafe {
+            init_shared(&mut *shm, 100, false);
+            shm.write_pos.store(200, Ordering::Release);
+            run_reader_loop(&mut *shm, &mut sink)
+        };
+        assert_eq!(result.bytes_read, 100);
+        assert_eq!(shm.read_pos.load(Ordering::Relaxed), 100);
+        assert_eq!(sink.len(), 100);
+    }
+}